@@ -1,12 +1,11 @@
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
 use std::time;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
 use crate::{
-	protocols::uds::UdsInterface,
+	protocols::uds::{UdsInterface, UdsKeepAlive, keepalive},
 	definition::{self, Pid},
 	numvariant::NumVariant,
 	error::Result,
@@ -82,7 +81,7 @@ pub struct LoggerEntry {
 }
 
 pub struct UdsLogger {
-	interface: Rc<UdsInterface>,
+	interface: UdsKeepAlive,
 	running: AtomicBool,
 	entries: Vec<LoggerEntry>
 }
@@ -90,11 +89,17 @@ pub struct UdsLogger {
 impl UdsLogger {
 	pub fn new(interface: Rc<UdsInterface>) -> UdsLogger {
 		UdsLogger {
-			interface,
+			interface: UdsKeepAlive::new(interface, keepalive::DEFAULT_S3),
 			running: AtomicBool::new(false),
 			entries: Vec::new(),
 		}
 	}
+
+	/// Sets the idle time after which TesterPresent is sent to keep the session active
+	pub fn with_s3(mut self, interval: time::Duration) -> UdsLogger {
+		self.interface.set_interval(interval);
+		self
+	}
 }
 
 impl Logger for UdsLogger {
@@ -152,7 +157,7 @@ impl Logger for UdsLogger {
 				// Add the data to the log
 				log.add_data(entry.pid_id, num);
 			}
-			thread::sleep(time::Duration::from_millis(1000));
+			self.interface.sleep(time::Duration::from_millis(1000))?;
 		}
 
 		*self.running.get_mut() = false;
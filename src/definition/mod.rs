@@ -28,6 +28,8 @@ impl default::Default for DownloadMode {
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub enum FlashMode {
 	Mazda1,
+	// Standard ISO 14229 programming sequence configured by `Transfer::uds`
+	Uds,
 	None,
 }
 
@@ -44,6 +46,33 @@ pub enum LogMode {
 	None,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityMode {
+	// Mazda's seed/key algorithm with `Auth::key`
+	Mazda,
+	// No security access
+	None,
+}
+
+/// Parameters of the check programming dependencies routine
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckParameters {
+	// No routineControlOptionRecord
+	None,
+	// The addressAndLengthFormatIdentifier followed by the address and size of each programmed segment
+	Segments,
+	// A fixed routineControlOptionRecord
+	Data(Vec<u8>),
+}
+
+impl default::Default for CheckParameters {
+	fn default() -> CheckParameters {
+		CheckParameters::None
+	}
+}
+
 impl default::Default for LogMode {
 	fn default() -> LogMode {
 		LogMode::None
@@ -169,14 +198,105 @@ pub struct Transfer {
 	// Server ID for ISO-TP requests
 	#[serde(rename = "serverid")]
 	pub server_id: u16,
+	// Maximum data length of ReadMemoryByAddress and TransferData blocks. ECUs
+	// supporting ISO-TP messages over 4095 bytes can use larger blocks.
+	#[serde(default)]
+	#[serde(rename = "blocksize")]
+	pub block_size: Option<usize>,
+	// Parameters for FlashMode::Uds. Required to flash with it.
+	#[serde(default)]
+	pub uds: Option<UdsFlash>,
+	// Verification of flashed data
+	#[serde(default)]
+	pub verify: VerifyMode,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+	// Read the flashed region back with ReadMemoryByAddress
+	ReadMemory,
+	// Compare CRC-32 checksums computed by a RoutineControl routine
+	Checksum {
+		routine: u16,
+	},
+	None,
 }
 
+impl default::Default for VerifyMode {
+	fn default() -> VerifyMode {
+		VerifyMode::None
+	}
+}
+
+/// Parameters of the standard ISO 14229 flash sequence. The programming
+/// session is `Auth::flash_sessionid`.
 #[derive(Debug, Deserialize, Serialize)]
+pub struct UdsFlash {
+	// Seed/key algorithm. It is vendor specific, so it must always be given.
+	pub security: SecurityMode,
+	// SecurityAccess requestSeed level. Must be odd; the key is sent with level + 1.
+	#[serde(default = "default_security_level")]
+	#[serde(rename = "securitylevel")]
+	pub security_level: u8,
+	// RoutineControl identifier of the erase routine
+	#[serde(default = "default_erase_routine")]
+	#[serde(rename = "eraseroutine")]
+	pub erase_routine: Option<u16>,
+	// RoutineControl identifier of the check programming dependencies routine
+	#[serde(default = "default_check_routine")]
+	#[serde(rename = "checkroutine")]
+	pub check_routine: Option<u16>,
+	// Parameters of the check programming dependencies routine
+	#[serde(default)]
+	#[serde(rename = "checkparameters")]
+	pub check_parameters: CheckParameters,
+	// RequestDownload dataFormatIdentifier
+	#[serde(default)]
+	#[serde(rename = "dataformat")]
+	pub data_format: u8,
+	// addressAndLengthFormatIdentifier used by RequestDownload and the erase routine
+	#[serde(default = "default_address_format")]
+	#[serde(rename = "addressformat")]
+	pub address_format: u8,
+	// ECUReset type sent after programming. No reset is sent if it is missing.
+	#[serde(default)]
+	#[serde(rename = "resettype")]
+	pub reset_type: Option<u8>,
+}
+
+fn default_security_level() -> u8 {
+	0x01
+}
+
+fn default_erase_routine() -> Option<u16> {
+	Some(0xFF00)
+}
+
+fn default_check_routine() -> Option<u16> {
+	Some(0xFF01)
+}
+
+fn default_address_format() -> u8 {
+	0x44
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
 pub struct FlashRegion {
 	pub offset: usize,
 	pub size: usize,
 }
 
+impl FlashRegion {
+	/// Returns true if `size` bytes at `offset` lie within the region
+	pub fn contains(&self, offset: usize, size: usize) -> bool {
+		match (offset.checked_add(size), self.offset.checked_add(self.size)) {
+			(Some(end), Some(region_end)) => offset >= self.offset && end <= region_end,
+			_ => false,
+		}
+	}
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Auth {
 	// Security key
@@ -202,6 +322,9 @@ pub struct Main {
 	// Flash region
 	#[serde(rename = "flashregion")]
 	pub flash_region: FlashRegion,
+	// Erase blocks of the flash region. Used to only flash changed sectors.
+	#[serde(default)]
+	pub sectors: Vec<FlashRegion>,
 
 	pub auth: Auth,
 
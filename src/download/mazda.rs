@@ -1,19 +1,28 @@
-use super::{Downloader, DownloadResponse, DownloadCallback};
+use super::{Downloader, DownloadResponse, DownloadCallback, CancelHandle, Options, PartialHeader, DEFAULT_RETRIES, DEFAULT_BACKOFF};
 
 use crate::{
-	protocols::uds::UdsInterface,
+	protocols::uds::{self, UdsInterface, DEFAULT_BLOCK_SIZE},
 	authenticator::MazdaAuthenticator,
 	error::{Error, Result},
 };
 
 use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-
+use std::thread;
+use std::time;
 
 pub struct Mazda1Downloader {
 	interface: Rc<UdsInterface>,
 	key: String,
 	download_size: usize,
+	block_size: usize,
+	retries: u32,
+	backoff: time::Duration,
+	partial_path: Option<(PathBuf, String)>,
+	cancel: Option<CancelHandle>,
 }
 
 impl Mazda1Downloader {
@@ -21,30 +30,154 @@ impl Mazda1Downloader {
 		Mazda1Downloader {
 			interface,
 			key: key.to_string(),
-			download_size
+			download_size,
+			block_size: DEFAULT_BLOCK_SIZE,
+			retries: DEFAULT_RETRIES,
+			backoff: DEFAULT_BACKOFF,
+			partial_path: None,
+			cancel: None,
+		}
+	}
+
+	/// Sets the maximum length of ReadMemoryByAddress requests. Lengths are limited to 0xFFFF.
+	pub fn with_block_size(mut self, block_size: usize) -> Mazda1Downloader {
+		self.block_size = block_size.clamp(1, 0xFFFF);
+		self
+	}
+
+	/// Sets the number of retries of a timed out block and the delay before the first retry
+	pub fn with_retries(mut self, retries: u32, backoff: time::Duration) -> Mazda1Downloader {
+		self.retries = retries;
+		self.backoff = backoff;
+		self
+	}
+
+	/// Persists downloaded data to `path` so a failed download resumes from the
+	/// last completed block. Data is only resumed from a file written for the same
+	/// platform, ROM size and VIN. The file is removed once the download completes.
+	pub fn with_partial_path(mut self, path: PathBuf, platform_id: &str) -> Mazda1Downloader {
+		self.partial_path = Some((path, platform_id.to_string()));
+		self
+	}
+
+	/// Sets the handle used to cancel the download
+	pub fn with_cancel(mut self, cancel: CancelHandle) -> Mazda1Downloader {
+		self.cancel = Some(cancel);
+		self
+	}
+
+	/// Applies the download options. `platform_id` identifies partial download files.
+	pub fn with_options(mut self, options: Options, platform_id: &str) -> Mazda1Downloader {
+		self = self.with_retries(options.retries, options.backoff);
+		if let Some(path) = options.partial_path {
+			self = self.with_partial_path(path, platform_id);
+		}
+		if let Some(cancel) = options.cancel {
+			self = self.with_cancel(cancel);
+		}
+		self
+	}
+}
+
+impl Mazda1Downloader {
+	fn check_cancelled(&self) -> Result<()> {
+		match self.cancel {
+			Some(ref cancel) => cancel.check(),
+			None => Ok(()),
+		}
+	}
+
+	/// Reads the VIN identifying the ECU. ECUs that reject the request have an empty VIN.
+	fn read_vin(&self) -> Result<Vec<u8>> {
+		match self.interface.read_data_by_identifier(uds::UDS_DID_VIN) {
+			Err(Error::NegativeResponse(..)) => Ok(Vec::new()),
+			result => result,
+		}
+	}
+
+	/// Opens the partial download file and reads the previously downloaded data
+	fn open_partial(&self, path: &Path, header: &PartialHeader) -> Result<(File, Vec<u8>)> {
+		let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+		let mut contents = Vec::new();
+		file.read_to_end(&mut contents)?;
+
+		let mut data = Vec::with_capacity(self.download_size);
+		match PartialHeader::decode(&contents) {
+			Some((ref existing, start)) if existing == header && contents.len() - start <= self.download_size => {
+				data.extend_from_slice(&contents[start..]);
+			},
+			_ => {
+				// The file does not belong to this download
+				file.set_len(0)?;
+				file.seek(SeekFrom::Start(0))?;
+				file.write_all(&header.encode()?)?;
+			},
+		}
+		Ok((file, data))
+	}
+
+	/// Reads a block, retrying with exponential backoff on timeouts. The late
+	/// response to a timed out request is discarded before retrying.
+	fn read_block(&self, offset: u32, length: u16) -> Result<Vec<u8>> {
+		let mut backoff = self.backoff;
+		let mut attempt = 0;
+		loop {
+			match self.interface.request_read_memory_address(offset, length) {
+				Err(Error::Timeout) if attempt < self.retries => {
+					thread::sleep(backoff);
+					self.check_cancelled()?;
+					self.interface.discard_responses()?;
+					backoff *= 2;
+					attempt += 1;
+				},
+				result => return result,
+			}
 		}
 	}
 }
 
 impl Downloader for Mazda1Downloader {
 	fn download(&self, callback: &DownloadCallback) -> Result<DownloadResponse> {
+		self.check_cancelled()?;
+
 		let auth = MazdaAuthenticator{};
 		auth.authenticate(&self.key, &*self.interface, 0x87)?;
 
+		// Resume after previously downloaded data
+		let (mut partial, mut data) = match self.partial_path {
+			Some((ref path, ref platform_id)) => {
+				let header = PartialHeader {
+					platform_id: platform_id.clone(),
+					rom_size: self.download_size,
+					vin: self.read_vin()?,
+				};
+				let (file, data) = self.open_partial(path, &header)?;
+				(Some(file), data)
+			},
+			None => (None, Vec::with_capacity(self.download_size)),
+		};
+
 		// Start downloading through ReadMemoryByAddress
-		let mut data = Vec::with_capacity(self.download_size);
-		let mut offset = 0 as u32;
-		let mut remaining = self.download_size as u32;
+		let mut offset = data.len() as u32;
+		let mut remaining = (self.download_size - data.len()) as u32;
 
 		while remaining > 0 {
-			let section = self.interface.request_read_memory_address(offset, cmp::min(remaining, 0xFFE) as u16)?;
+			self.check_cancelled()?;
+
+			let section = self.read_block(offset, cmp::min(remaining, self.block_size as u32) as u16)?;
 
 			if section.is_empty() {
 				return Err(Error::EmptyPacket);
 			}
+			if section.len() as u32 > remaining {
+				return Err(Error::TooMuchData);
+			}
 
 			// Add response to buffer
 			data.extend_from_slice(&section);
+			if let Some(ref mut file) = partial {
+				file.write_all(&section)?;
+			}
 			offset += section.len() as u32;
 			remaining -= section.len() as u32;
 
@@ -55,6 +188,252 @@ impl Downloader for Mazda1Downloader {
 			}
 		}
 
+		// The download is complete, so nothing is left to resume
+		if let Some((ref path, _)) = self.partial_path {
+			drop(partial);
+			fs::remove_file(path)?;
+		}
+
 		Ok(DownloadResponse {data})
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		protocols::can::VirtualCanBus,
+		simulator::{testing, mazda::Mazda1Ecu},
+	};
+	use std::cell::{Cell, RefCell};
+	use std::process;
+
+	const KEY: &str = "MazdA";
+	const SIZE: usize = 0x2000;
+	const BLOCK: usize = 0x400;
+
+	fn rom() -> Vec<u8> {
+		(0..SIZE).map(|i| (i * 7) as u8).collect()
+	}
+
+	/// A partial download file in the temporary directory, removed when created and dropped
+	struct PartialFile(PathBuf);
+
+	impl PartialFile {
+		fn new(name: &str) -> PartialFile {
+			let path = std::env::temp_dir().join(format!("tuneutils-{}-{}.partial", name, process::id()));
+			let _ = fs::remove_file(&path);
+			PartialFile(path)
+		}
+
+		fn path(&self) -> &Path {
+			&self.0
+		}
+	}
+
+	impl Drop for PartialFile {
+		fn drop(&mut self) {
+			let _ = fs::remove_file(&self.0);
+		}
+	}
+
+	/// Fails the first `failures` ReadMemoryByAddress requests with a timeout and
+	/// cancels `cancel` on each failure. If `late` is set, the failed requests are
+	/// still sent and the server's responses arrive after the timeout.
+	struct Flaky {
+		interface: Rc<UdsInterface>,
+		failures: Cell<u32>,
+		reads: Cell<u32>,
+		cancel: Option<CancelHandle>,
+		late: bool,
+	}
+
+	impl Flaky {
+		fn new(interface: &Rc<UdsInterface>, failures: u32) -> Flaky {
+			Flaky {
+				interface: interface.clone(),
+				failures: Cell::new(failures),
+				reads: Cell::new(0),
+				cancel: None,
+				late: false,
+			}
+		}
+	}
+
+	impl UdsInterface for Flaky {
+		fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>> {
+			if request_sid == uds::UDS_REQ_READMEM {
+				self.reads.set(self.reads.get() + 1);
+				if self.failures.get() > 0 {
+					self.failures.set(self.failures.get() - 1);
+					if let Some(ref cancel) = self.cancel {
+						cancel.cancel();
+					}
+					if self.late {
+						self.interface.send_request(request_sid, data)?;
+					}
+					return Err(Error::Timeout);
+				}
+			}
+			self.interface.request(request_sid, data)
+		}
+
+		fn send_request(&self, request_sid: u8, data: &[u8]) -> Result<()> {
+			self.interface.send_request(request_sid, data)
+		}
+
+		fn discard_responses(&self) -> Result<()> {
+			self.interface.discard_responses()
+		}
+	}
+
+	/// Downloads until the first block is complete, then cancels
+	fn download_first_block(interface: Rc<UdsInterface>, path: &Path, platform_id: &str) {
+		let cancel = CancelHandle::new();
+		let downloader = Mazda1Downloader::new(interface, KEY, SIZE)
+			.with_block_size(BLOCK)
+			.with_partial_path(path.to_path_buf(), platform_id)
+			.with_cancel(cancel.clone());
+		match downloader.download(&DownloadCallback::with(move |_| cancel.cancel())) {
+			Err(Error::Cancelled) => (),
+			other => panic!("unexpected result {:?}", other.map(|response| response.data.len())),
+		}
+	}
+
+	/// Downloads with the partial file `path` and returns the data and the first progress
+	fn download(interface: Rc<UdsInterface>, path: &Path, platform_id: &str) -> (Vec<u8>, f32) {
+		let progress = Rc::new(RefCell::new(Vec::new()));
+		let recorded = progress.clone();
+		let downloader = Mazda1Downloader::new(interface, KEY, SIZE)
+			.with_block_size(BLOCK)
+			.with_partial_path(path.to_path_buf(), platform_id);
+		let response = downloader.download(&DownloadCallback::with(move |p| recorded.borrow_mut().push(p))).unwrap();
+		let first = progress.borrow()[0];
+		(response.data, first)
+	}
+
+	#[test]
+	fn cancelled_downloads_resume_from_the_partial_file() {
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new(KEY, rom(), 0x1000, 0x1000).unwrap().with_pid(uds::UDS_DID_VIN, b"VIN1"));
+		let tester = testing::tester(bus.open());
+		let partial = PartialFile::new("resume");
+		let path = partial.path();
+
+		download_first_block(tester.clone(), path, "test");
+		let contents = fs::read(path).unwrap();
+		let (header, start) = PartialHeader::decode(&contents).unwrap();
+		assert_eq!(header, PartialHeader { platform_id: "test".to_string(), rom_size: SIZE, vin: b"VIN1".to_vec() });
+		assert_eq!(&contents[start..], &rom()[..BLOCK]);
+
+		// The first block is not downloaded again
+		let (data, first) = download(tester.clone(), path, "test");
+		assert_eq!(data, rom());
+		assert_eq!(first, 2.0 * BLOCK as f32 / SIZE as f32);
+		assert!(!path.exists());
+		server.stop();
+	}
+
+	#[test]
+	fn partial_files_of_other_downloads_are_discarded() {
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new(KEY, rom(), 0x1000, 0x1000).unwrap().with_pid(uds::UDS_DID_VIN, b"VIN1"));
+		let tester = testing::tester(bus.open());
+		let partial = PartialFile::new("discard");
+		let path = partial.path();
+
+		// Another platform
+		download_first_block(tester.clone(), path, "other");
+		let (data, first) = download(tester.clone(), path, "test");
+		assert_eq!(data, rom());
+		assert_eq!(first, BLOCK as f32 / SIZE as f32);
+
+		// A file without a header
+		fs::write(path, &rom()[..BLOCK]).unwrap();
+		let (data, first) = download(tester.clone(), path, "test");
+		assert_eq!(data, rom());
+		assert_eq!(first, BLOCK as f32 / SIZE as f32);
+
+		// Another ECU
+		download_first_block(tester.clone(), path, "test");
+		server.stop();
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new(KEY, vec![0xA5; SIZE], 0x1000, 0x1000).unwrap().with_pid(uds::UDS_DID_VIN, b"VIN2"));
+		let (data, first) = download(testing::tester(bus.open()), path, "test");
+		assert_eq!(data, vec![0xA5; SIZE]);
+		assert_eq!(first, BLOCK as f32 / SIZE as f32);
+		server.stop();
+	}
+
+	#[test]
+	fn timed_out_blocks_are_retried() {
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new(KEY, rom(), 0x1000, 0x1000).unwrap());
+		let tester = testing::tester(bus.open());
+
+		let flaky = Rc::new(Flaky::new(&tester, 2));
+		let downloader = Mazda1Downloader::new(flaky.clone(), KEY, SIZE)
+			.with_block_size(BLOCK)
+			.with_retries(2, time::Duration::from_millis(1));
+		assert_eq!(downloader.download(&DownloadCallback::null()).unwrap().data, rom());
+		assert_eq!(flaky.reads.get(), (SIZE / BLOCK) as u32 + 2);
+
+		let flaky = Rc::new(Flaky::new(&tester, 3));
+		let downloader = Mazda1Downloader::new(flaky.clone(), KEY, SIZE)
+			.with_block_size(BLOCK)
+			.with_retries(2, time::Duration::from_millis(1));
+		match downloader.download(&DownloadCallback::null()) {
+			Err(Error::Timeout) => (),
+			other => panic!("unexpected result {:?}", other.map(|response| response.data.len())),
+		}
+		assert_eq!(flaky.reads.get(), 3);
+		server.stop();
+	}
+
+	#[test]
+	fn late_responses_are_not_taken_for_retries() {
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new(KEY, rom(), 0x1000, 0x1000).unwrap());
+		let tester = testing::tester(bus.open());
+
+		// The responses to the timed out requests arrive during the backoff. Blocks
+		// of 4 bytes are answered in single frames that wait on the bus.
+		let flaky = Rc::new(Flaky { late: true, ..Flaky::new(&tester, 2) });
+		let downloader = Mazda1Downloader::new(flaky.clone(), KEY, 0x40)
+			.with_block_size(4)
+			.with_retries(2, time::Duration::from_millis(50));
+		assert_eq!(downloader.download(&DownloadCallback::null()).unwrap().data, &rom()[..0x40]);
+		assert_eq!(flaky.reads.get(), 0x40 / 4 + 2);
+		server.stop();
+	}
+
+	#[test]
+	fn cancelling_stops_retries() {
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new(KEY, rom(), 0x1000, 0x1000).unwrap());
+		let tester = testing::tester(bus.open());
+
+		let cancel = CancelHandle::new();
+		cancel.cancel();
+		let flaky = Rc::new(Flaky::new(&tester, 0));
+		let downloader = Mazda1Downloader::new(flaky.clone(), KEY, SIZE).with_cancel(cancel.clone());
+		match downloader.download(&DownloadCallback::null()) {
+			Err(Error::Cancelled) => (),
+			other => panic!("unexpected result {:?}", other.map(|response| response.data.len())),
+		}
+		assert_eq!(flaky.reads.get(), 0);
+
+		// Cancelled while waiting to retry
+		let cancel = CancelHandle::new();
+		let flaky = Rc::new(Flaky { cancel: Some(cancel.clone()), ..Flaky::new(&tester, 1) });
+		let downloader = Mazda1Downloader::new(flaky.clone(), KEY, SIZE)
+			.with_retries(3, time::Duration::from_millis(1))
+			.with_cancel(cancel);
+		match downloader.download(&DownloadCallback::null()) {
+			Err(Error::Cancelled) => (),
+			other => panic!("unexpected result {:?}", other.map(|response| response.data.len())),
+		}
+		assert_eq!(flaky.reads.get(), 1);
+		server.stop();
+	}
+}
@@ -3,7 +3,16 @@
 pub mod mazda;
 
 use std::cell::RefCell;
-use crate::error::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time;
+use crate::error::{Error, Result};
+
+/// Number of times a block is retried after a timeout
+pub const DEFAULT_RETRIES: u32 = 3;
+/// Delay before the first retry. The delay doubles after each retry.
+pub const DEFAULT_BACKOFF: time::Duration = time::Duration::from_millis(100);
 
 pub struct DownloadCallback {
 	pub callback: Option<Box<RefCell<FnMut(f32)>>>,
@@ -23,6 +32,97 @@ impl DownloadCallback {
 	}
 }
 
+/// Cancels a download from another thread. The download stops before the next block.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+	cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+	pub fn new() -> CancelHandle {
+		CancelHandle::default()
+	}
+
+	/// Requests cancellation
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::Relaxed);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::Relaxed)
+	}
+
+	/// Returns `Error::Cancelled` if cancellation was requested
+	pub fn check(&self) -> Result<()> {
+		if self.is_cancelled() {
+			return Err(Error::Cancelled);
+		}
+		Ok(())
+	}
+}
+
+/// Options of a download
+#[derive(Debug, Clone)]
+pub struct Options {
+	// Persists downloaded data so an interrupted download resumes
+	pub partial_path: Option<PathBuf>,
+	// Cancels the download
+	pub cancel: Option<CancelHandle>,
+	// Number of retries of a timed out block
+	pub retries: u32,
+	// Delay before the first retry
+	pub backoff: time::Duration,
+}
+
+impl Default for Options {
+	fn default() -> Options {
+		Options {
+			partial_path: None,
+			cancel: None,
+			retries: DEFAULT_RETRIES,
+			backoff: DEFAULT_BACKOFF,
+		}
+	}
+}
+
+const PARTIAL_MAGIC: &[u8; 4] = b"TUDL";
+
+/// Identifies the download a partial download file belongs to. Data is only
+/// resumed if the header matches.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PartialHeader {
+	pub platform_id: String,
+	pub rom_size: usize,
+	// VIN read from the ECU, empty if the ECU does not report one
+	pub vin: Vec<u8>,
+}
+
+impl PartialHeader {
+	/// Encodes the header written at the start of a partial download file
+	pub fn encode(&self) -> Result<Vec<u8>> {
+		let header = serde_yaml::to_vec(self)?;
+		let mut encoded = PARTIAL_MAGIC.to_vec();
+		encoded.extend_from_slice(&(header.len() as u32).to_be_bytes());
+		encoded.extend_from_slice(&header);
+		Ok(encoded)
+	}
+
+	/// Decodes the header at the start of a partial download file. Returns the
+	/// header and its encoded length, or `None` if `data` does not start with a header.
+	pub fn decode(data: &[u8]) -> Option<(PartialHeader, usize)> {
+		if data.len() < 8 || &data[..4] != PARTIAL_MAGIC {
+			return None;
+		}
+		let length = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+		let end = length.checked_add(8)?;
+		if end > data.len() {
+			return None;
+		}
+		let header = serde_yaml::from_slice(&data[8..end]).ok()?;
+		Some((header, end))
+	}
+}
+
 pub struct DownloadResponse {
     pub data: Vec<u8>,
 }
@@ -2,6 +2,8 @@ use std::result;
 use std::io;
 use std::fmt;
 
+use crate::protocols::uds::NegativeResponseCode;
+
 
 #[derive(Debug)]
 pub enum Error {
@@ -11,13 +13,28 @@ pub enum Error {
     InvalidResponse,
     Timeout,
     TooMuchData,
+    /// The CAN identifier does not fit in the frame format
+    InvalidId,
+    /// The operation is not supported by the interface
+    Unsupported,
     IncompleteWrite,
     Read,
     // ISO-TP Frame
     InvalidFrame,
+    /// The receiver reported a buffer overflow in a flow control frame
+    Overflow,
+    /// A consecutive frame was received with an unexpected sequence number
+    InvalidSequence,
+    /// The receiver sent more wait flow control frames than allowed
+    WaitLimit,
+    /// The adapter reported that it could not send the frame
+    SendFailed,
 
-    NegativeResponse(u8),
+    /// A UDS negative response to the request SID
+    NegativeResponse(u8, NegativeResponseCode),
     InvalidPacket,
+    /// The SecurityAccess requestSeed level is even or 0xFF
+    InvalidSecurityLevel(u8),
 
     Yaml(serde_yaml::Error),
     InvalidPlatformId,
@@ -29,6 +46,16 @@ pub enum Error {
 
     /// Received an empty packet
     EmptyPacket,
+    /// Flashed data did not match at the offset
+    VerifyFailed(usize),
+    /// Flash data lies outside of the platform's flash region
+    OutsideFlashRegion,
+    /// Flash data size does not match the platform's flash region
+    FlashSizeMismatch,
+    /// The platform's flash sectors overlap
+    InvalidSectorMap,
+    /// The operation was cancelled through a `CancelHandle`
+    Cancelled,
     
     #[cfg(feature = "j2534")]
     J2534(j2534::Error),
@@ -63,11 +90,18 @@ impl fmt::Display for Error {
             Error::InvalidResponse => write!(f, "Invalid response"),
             Error::Timeout => write!(f, "Timed out"),
             Error::TooMuchData => write!(f, "Too much data"),
+            Error::InvalidId => write!(f, "Invalid CAN identifier"),
+            Error::Unsupported => write!(f, "Operation not supported by the interface"),
             Error::IncompleteWrite => write!(f, "Failed to finish writing data"),
             Error::Read => write!(f, "Read failed"),
             Error::InvalidFrame => write!(f, "Invalid frame"),
-            Error::NegativeResponse(code) => write!(f, "Negative ISO-TP response received ({})", code),
+            Error::Overflow => write!(f, "ISO-TP receiver buffer overflow"),
+            Error::InvalidSequence => write!(f, "Invalid ISO-TP sequence number"),
+            Error::WaitLimit => write!(f, "Exceeded the maximum number of ISO-TP wait frames"),
+            Error::SendFailed => write!(f, "The adapter failed to send the frame"),
+            Error::NegativeResponse(sid, code) => write!(f, "Negative response to service 0x{:02X}: {}", sid, code),
             Error::InvalidPacket => write!(f, "Invalid packet received"),
+            Error::InvalidSecurityLevel(level) => write!(f, "Invalid security access level 0x{:02X}", level),
             Error::Yaml(ref err) => write!(f, "Yaml error: {}", err),
             Error::InvalidPlatformId => write!(f, "Invalid platform id"),
             Error::InvalidModelId => write!(f, "Invalid model id"),
@@ -76,6 +110,11 @@ impl fmt::Display for Error {
             Error::InvalidTableId => write!(f, "Invalid table id"),
             Error::NoTableOffset => write!(f, "No table offset"),
             Error::EmptyPacket => write!(f, "Received an empty packet"),
+            Error::VerifyFailed(offset) => write!(f, "Flash verification failed at offset 0x{:X}", offset),
+            Error::OutsideFlashRegion => write!(f, "Flash data is outside of the flash region"),
+            Error::FlashSizeMismatch => write!(f, "Flash data size does not match the flash region"),
+            Error::InvalidSectorMap => write!(f, "Invalid flash sector map"),
+            Error::Cancelled => write!(f, "Cancelled"),
             #[cfg(feature = "j2534")]
             Error::J2534(ref err) => write!(f, "J2534 error: {}", err),
             _ => write!(f, "unimplemented: {:?}", *self),
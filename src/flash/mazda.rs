@@ -1,29 +1,56 @@
-use super::{Flasher, FlashData};
+use super::{Flasher, FlashData, Verifier};
 
 use crate::{
-	protocols::uds::{self, UdsInterface},
+	protocols::uds::{self, UdsInterface, UdsKeepAlive, keepalive, DEFAULT_BLOCK_SIZE},
 	authenticator::MazdaAuthenticator,
-	error::Result,
+	definition::FlashRegion,
+	error::{Error, Result},
 };
 
 use byteorder::{BigEndian, WriteBytesExt};
 
 use std::cmp;
 use std::rc::Rc;
-
+use std::time;
 
 pub struct Mazda1Flasher {
-	interface: Rc<UdsInterface>,
+	interface: UdsKeepAlive,
 	key: String,
+	region: FlashRegion,
+	block_size: usize,
+	verifier: Option<Box<Verifier>>,
 }
 
 impl Mazda1Flasher {
-	pub fn new(interface: Rc<UdsInterface>, key: &str) -> Mazda1Flasher {
+	/// Creates a flasher for the flash region `region`. The whole region is erased
+	/// before programming, so flash data must cover it entirely.
+	pub fn new(interface: Rc<UdsInterface>, key: &str, region: FlashRegion) -> Mazda1Flasher {
 		Mazda1Flasher {
-			interface,
+			interface: UdsKeepAlive::new(interface, keepalive::DEFAULT_S3),
 			key: key.to_string(),
+			region,
+			block_size: DEFAULT_BLOCK_SIZE,
+			verifier: None,
 		}
 	}
+
+	/// Verifies the flashed data after programming
+	pub fn with_verifier(mut self, verifier: Box<Verifier>) -> Mazda1Flasher {
+		self.verifier = Some(verifier);
+		self
+	}
+
+	/// Sets the idle time after which TesterPresent is sent to keep the session active
+	pub fn with_s3(mut self, interval: time::Duration) -> Mazda1Flasher {
+		self.interface.set_interval(interval);
+		self
+	}
+
+	/// Sets the maximum data length of TransferData requests
+	pub fn with_block_size(mut self, block_size: usize) -> Mazda1Flasher {
+		self.block_size = cmp::max(block_size, 1);
+		self
+	}
 }
 
 impl Mazda1Flasher {
@@ -37,9 +64,15 @@ impl Mazda1Flasher {
 
 impl Flasher for Mazda1Flasher {
 	fn flash(&self, data: &FlashData) -> Result<()> {
+		data.check_region(&self.region)?;
+		// Erasing clears the entire region
+		if data.offset != self.region.offset || data.data.len() != self.region.size {
+			return Err(Error::FlashSizeMismatch);
+		}
+
 		// Authenticate
 		let auth = MazdaAuthenticator{};
-		auth.authenticate(&self.key, &*self.interface, 0x85)?;
+		auth.authenticate(&self.key, &self.interface, 0x85)?;
 
 		// Erase
 		self.erase()?;
@@ -58,8 +91,8 @@ impl Flasher for Mazda1Flasher {
 		let mut buffer = data.data;
 		let mut sent = 0;
 		while buffer.len() != 0 {
-			let to_send = cmp::min(buffer.len(), 0xFFE);
-			self.interface.request(uds::UDS_REQ_TRANSFERDATA, &buffer[0..=to_send])?;
+			let to_send = cmp::min(buffer.len(), self.block_size);
+			self.interface.request(uds::UDS_REQ_TRANSFERDATA, &buffer[..to_send])?;
 			sent += to_send;
 			buffer = &data.data[sent..];
 
@@ -70,6 +103,10 @@ impl Flasher for Mazda1Flasher {
 			}
 		}
 
+		if let Some(ref verifier) = self.verifier {
+			verifier.verify(data.offset, data.data)?;
+		}
+
 		Ok(())
 	}
 }
\ No newline at end of file
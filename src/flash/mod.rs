@@ -1,10 +1,19 @@
 // Utilities for flashing devices
 
 pub mod mazda;
+pub mod uds;
+pub mod verify;
+pub mod plan;
+
+pub use self::verify::{Verifier, ReadMemoryVerifier, ChecksumVerifier};
+pub use self::plan::FlashPlan;
 
 use std::cell::RefCell;
 
-use crate::error::Result;
+use crate::{
+	definition::FlashRegion,
+	error::{Error, Result},
+};
 
 pub struct FlashData<'a> {
 	pub offset: usize,
@@ -22,6 +31,26 @@ impl<'a> FlashData<'a> {
 		}
 	}
 
+	/// Creates flash data for `region` from the full ROM image `rom`
+	pub fn from_rom(rom: &'a [u8], region: &FlashRegion) -> Result<FlashData<'a>> {
+		let end = region.offset.checked_add(region.size).ok_or(Error::FlashSizeMismatch)?;
+		if region.size == 0 || end > rom.len() {
+			return Err(Error::FlashSizeMismatch);
+		}
+		Ok(FlashData::new(region.offset, &rom[region.offset..end]))
+	}
+
+	/// Checks that the data is not empty and lies within `region`
+	pub fn check_region(&self, region: &FlashRegion) -> Result<()> {
+		if self.data.is_empty() {
+			return Err(Error::FlashSizeMismatch);
+		}
+		if !region.contains(self.offset, self.data.len()) {
+			return Err(Error::OutsideFlashRegion);
+		}
+		Ok(())
+	}
+
 	pub fn with_callback<CB: 'static + FnMut(f32)>(mut self, cb: CB) -> Self {
 		self.callback = Some(Box::new(RefCell::new(cb)));
 		self
@@ -30,4 +59,41 @@ impl<'a> FlashData<'a> {
 
 pub trait Flasher {
     fn flash(&self, data: &FlashData) -> Result<()>;
-}
\ No newline at end of file
+
+    /// Returns true if the flasher only erases the segments it programs, so
+    /// unchanged sectors can be skipped
+    fn supports_region_erase(&self) -> bool {
+        false
+    }
+
+    /// Flashes multiple segments. By default, each segment is flashed separately.
+    fn flash_segments(&self, segments: &[FlashData]) -> Result<()> {
+        for data in segments {
+            self.flash(data)?;
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn regions_near_the_address_limit_do_not_overflow() {
+		let region = FlashRegion { offset: usize::max_value() - 0x10, size: 0x100 };
+		assert!(!region.contains(usize::max_value() - 0x08, 0x04));
+		assert!(!region.contains(0, 0x10));
+		match FlashData::new(usize::max_value() - 0x08, &[0; 4]).check_region(&region) {
+			Err(Error::OutsideFlashRegion) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+
+		let rom = [0u8; 0x100];
+		match FlashData::from_rom(&rom, &region) {
+			Err(Error::FlashSizeMismatch) => (),
+			other => panic!("unexpected result {:?}", other.map(|data| data.offset)),
+		}
+		let region = FlashRegion { offset: 0x80, size: 0x80 };
+		assert_eq!(FlashData::from_rom(&rom, &region).unwrap().data.len(), 0x80);
+	}
+}
@@ -0,0 +1,320 @@
+use super::{Flasher, FlashData};
+
+use crate::{
+	definition::{self, FlashRegion},
+	error::{Error, Result},
+};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The segments of the flash region that must be erased and programmed to write an image
+#[derive(Debug)]
+pub struct FlashPlan {
+	pub segments: Vec<FlashRegion>,
+}
+
+impl FlashPlan {
+	/// Plans flashing `image` over `current`, the known ECU contents. Only changed
+	/// sectors are planned if the flasher supports region erase.
+	pub fn new(platform: &definition::Main, flasher: &Flasher, current: &[u8], image: &[u8]) -> Result<FlashPlan> {
+		if flasher.supports_region_erase() {
+			FlashPlan::incremental(platform, current, image)
+		} else {
+			FlashPlan::full(platform, current, image)
+		}
+	}
+
+	/// Plans programming of the whole flash region if `image` differs from `current`
+	pub fn full(platform: &definition::Main, current: &[u8], image: &[u8]) -> Result<FlashPlan> {
+		let region = platform.flash_region;
+		check_sizes(&region, current, image)?;
+
+		let mut segments = Vec::new();
+		if changed(&region, current, image) {
+			segments.push(region);
+		}
+		Ok(FlashPlan {
+			segments,
+		})
+	}
+
+	/// Plans programming of the whole flash region, which covers every sector. Used
+	/// when the ECU's contents are unknown.
+	pub fn whole(platform: &definition::Main, image: &[u8]) -> Result<FlashPlan> {
+		let region = platform.flash_region;
+		check_sizes(&region, image, image)?;
+		Ok(FlashPlan {
+			segments: vec![region],
+		})
+	}
+
+	/// Plans programming of only the sectors in which `image` differs from `current`.
+	/// Adjacent changed sectors are merged into one segment. The whole region is planned
+	/// if the platform has no sector map or data outside of the mapped sectors changed.
+	pub fn incremental(platform: &definition::Main, current: &[u8], image: &[u8]) -> Result<FlashPlan> {
+		if platform.sectors.is_empty() {
+			return FlashPlan::full(platform, current, image);
+		}
+		let region = platform.flash_region;
+		check_sizes(&region, current, image)?;
+
+		let mut sectors = platform.sectors.clone();
+		sectors.sort_by_key(|sector| sector.offset);
+
+		let mut segments: Vec<FlashRegion> = Vec::new();
+		let mut end = region.offset;
+		for sector in &sectors {
+			if sector.size == 0 || !region.contains(sector.offset, sector.size) {
+				return Err(Error::OutsideFlashRegion);
+			}
+			if sector.offset < end {
+				return Err(Error::InvalidSectorMap);
+			}
+			// Unmapped data between sectors cannot be erased separately
+			let gap = FlashRegion { offset: end, size: sector.offset - end };
+			if changed(&gap, current, image) {
+				return FlashPlan::full(platform, current, image);
+			}
+			end = sector.offset + sector.size;
+
+			if !changed(sector, current, image) {
+				continue;
+			}
+			match segments.last_mut() {
+				Some(ref mut last) if last.offset + last.size == sector.offset => last.size += sector.size,
+				_ => segments.push(*sector),
+			}
+		}
+		let tail = FlashRegion { offset: end, size: region.offset + region.size - end };
+		if changed(&tail, current, image) {
+			return FlashPlan::full(platform, current, image);
+		}
+
+		Ok(FlashPlan {
+			segments,
+		})
+	}
+
+	/// Returns true if nothing needs to be flashed
+	pub fn is_empty(&self) -> bool {
+		self.segments.is_empty()
+	}
+
+	/// Returns the number of bytes that will be programmed
+	pub fn size(&self) -> usize {
+		self.segments.iter().map(|segment| segment.size).sum()
+	}
+
+	/// Returns the flash data of each segment from the full image `image`
+	pub fn data<'a>(&self, image: &'a [u8]) -> Vec<FlashData<'a>> {
+		self.segments.iter().map(|segment| {
+			FlashData::new(segment.offset, &image[segment.offset..segment.offset + segment.size])
+		}).collect()
+	}
+
+	/// Programs the planned segments of `image` with `flasher`. `callback` is called
+	/// with the progress over all segments.
+	pub fn flash<CB: 'static + FnMut(f32)>(&self, flasher: &Flasher, image: &[u8], callback: CB) -> Result<()> {
+		if self.is_empty() {
+			return Ok(());
+		}
+		if self.segments.iter().any(|segment| segment.offset + segment.size > image.len()) {
+			return Err(Error::FlashSizeMismatch);
+		}
+
+		let total = self.size() as f32;
+		let callback = Rc::new(RefCell::new(callback));
+		let mut done = 0;
+		let segments: Vec<FlashData> = self.data(image).into_iter().map(|data| {
+			let start = done as f32;
+			let size = data.data.len() as f32;
+			done += data.data.len();
+			let callback = callback.clone();
+			data.with_callback(move |progress| {
+				let mut closure = callback.borrow_mut();
+				(&mut *closure)((start + progress * size) / total)
+			})
+		}).collect();
+		flasher.flash_segments(&segments)
+	}
+}
+
+fn check_sizes(region: &FlashRegion, current: &[u8], image: &[u8]) -> Result<()> {
+	match region.offset.checked_add(region.size) {
+		Some(end) if current.len() == image.len() && end <= image.len() => Ok(()),
+		_ => Err(Error::FlashSizeMismatch),
+	}
+}
+
+fn changed(region: &FlashRegion, current: &[u8], image: &[u8]) -> bool {
+	let range = region.offset..region.offset + region.size;
+	current[range.clone()] != image[range]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Records flashed segments
+	#[derive(Default)]
+	struct Recorder {
+		region_erase: bool,
+		flashed: RefCell<Vec<(usize, Vec<u8>)>>,
+	}
+
+	impl Flasher for Recorder {
+		fn flash(&self, data: &FlashData) -> Result<()> {
+			self.flashed.borrow_mut().push((data.offset, data.data.to_vec()));
+			if let Some(ref cb) = data.callback {
+				let mut closure = cb.borrow_mut();
+				(&mut *closure)(1.0);
+			}
+			Ok(())
+		}
+
+		fn supports_region_erase(&self) -> bool {
+			self.region_erase
+		}
+	}
+
+	/// A platform with the flash region 0x100..0x500, sectors 0x100..0x200, 0x200..0x300
+	/// and 0x380..0x480, a gap at 0x300..0x380 and a tail at 0x480..0x500
+	fn platform() -> definition::Main {
+		let mut platform: definition::Main = serde_yaml::from_str("
+name: Test
+id: test
+transfer:
+  serverid: 0x7E0
+baudrate: 500000
+endianness: big
+flashregion: {offset: 0x100, size: 0x400}
+auth: {key: MazdA, download_sessionid: 0x85, flash_sessionid: 0x85}
+romsize: 0x800
+tables: {}
+pids: []
+vins: []
+").unwrap();
+		platform.sectors = vec![
+			FlashRegion { offset: 0x380, size: 0x100 },
+			FlashRegion { offset: 0x100, size: 0x100 },
+			FlashRegion { offset: 0x200, size: 0x100 },
+		];
+		platform
+	}
+
+	fn modified(current: &[u8], offsets: &[usize]) -> Vec<u8> {
+		let mut image = current.to_vec();
+		for &offset in offsets {
+			image[offset] = !image[offset];
+		}
+		image
+	}
+
+	fn segments(plan: &FlashPlan) -> Vec<(usize, usize)> {
+		plan.segments.iter().map(|segment| (segment.offset, segment.size)).collect()
+	}
+
+	#[test]
+	fn unknown_contents_plan_the_whole_region() {
+		let platform = platform();
+		let image = vec![0u8; 0x800];
+		let plan = FlashPlan::whole(&platform, &image).unwrap();
+		assert_eq!(segments(&plan), vec![(0x100, 0x400)]);
+		for sector in &platform.sectors {
+			assert!(plan.segments[0].contains(sector.offset, sector.size));
+		}
+		match FlashPlan::whole(&platform, &image[..0x400]) {
+			Err(Error::FlashSizeMismatch) => (),
+			res => panic!("expected FlashSizeMismatch, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn changed_sectors_are_planned_and_adjacent_ones_merged() {
+		let platform = platform();
+		let current = vec![0u8; 0x800];
+		assert!(FlashPlan::incremental(&platform, &current, &current).unwrap().is_empty());
+
+		let plan = FlashPlan::incremental(&platform, &current, &modified(&current, &[0x150, 0x250, 0x400])).unwrap();
+		assert_eq!(segments(&plan), vec![(0x100, 0x200), (0x380, 0x100)]);
+		assert_eq!(plan.size(), 0x300);
+
+		let plan = FlashPlan::incremental(&platform, &current, &modified(&current, &[0x100, 0x47F])).unwrap();
+		assert_eq!(segments(&plan), vec![(0x100, 0x100), (0x380, 0x100)]);
+	}
+
+	#[test]
+	fn changes_outside_of_the_sectors_plan_the_whole_region() {
+		let platform = platform();
+		let current = vec![0u8; 0x800];
+		// Gap between sectors
+		let plan = FlashPlan::incremental(&platform, &current, &modified(&current, &[0x150, 0x300])).unwrap();
+		assert_eq!(segments(&plan), vec![(0x100, 0x400)]);
+		// Tail of the region
+		let plan = FlashPlan::incremental(&platform, &current, &modified(&current, &[0x4FF])).unwrap();
+		assert_eq!(segments(&plan), vec![(0x100, 0x400)]);
+		// Outside of the region nothing is flashed
+		assert!(FlashPlan::incremental(&platform, &current, &modified(&current, &[0x50, 0x600])).unwrap().is_empty());
+	}
+
+	#[test]
+	fn invalid_sector_maps_are_rejected() {
+		let mut platform = platform();
+		let current = vec![0u8; 0x800];
+		platform.sectors.push(FlashRegion { offset: 0x2F0, size: 0x20 });
+		match FlashPlan::incremental(&platform, &current, &current) {
+			Err(Error::InvalidSectorMap) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+
+		let mut platform = self::platform();
+		platform.sectors.push(FlashRegion { offset: 0x480, size: 0x100 });
+		match FlashPlan::incremental(&platform, &current, &current) {
+			Err(Error::OutsideFlashRegion) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+
+		match FlashPlan::incremental(&self::platform(), &current, &current[..0x400]) {
+			Err(Error::FlashSizeMismatch) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+	}
+
+	#[test]
+	fn flashers_without_region_erase_program_the_whole_region() {
+		let platform = platform();
+		let current = vec![0u8; 0x800];
+		let image = modified(&current, &[0x150]);
+
+		let recorder = Recorder::default();
+		let plan = FlashPlan::new(&platform, &recorder, &current, &image).unwrap();
+		assert_eq!(segments(&plan), vec![(0x100, 0x400)]);
+
+		let recorder = Recorder { region_erase: true, ..Default::default() };
+		let plan = FlashPlan::new(&platform, &recorder, &current, &image).unwrap();
+		assert_eq!(segments(&plan), vec![(0x100, 0x100)]);
+	}
+
+	#[test]
+	fn flash_programs_the_planned_segments() {
+		let platform = platform();
+		let current = vec![0u8; 0x800];
+		let image = modified(&current, &[0x150, 0x400]);
+		let recorder = Recorder { region_erase: true, ..Default::default() };
+		let plan = FlashPlan::new(&platform, &recorder, &current, &image).unwrap();
+
+		let progress = Rc::new(RefCell::new(Vec::new()));
+		let recorded = progress.clone();
+		plan.flash(&recorder, &image, move |p| recorded.borrow_mut().push(p)).unwrap();
+		assert_eq!(*recorder.flashed.borrow(), vec![
+			(0x100, image[0x100..0x200].to_vec()),
+			(0x380, image[0x380..0x480].to_vec()),
+		]);
+		assert_eq!(*progress.borrow(), vec![0.5, 1.0]);
+
+		let empty = FlashPlan::new(&platform, &recorder, &image, &image).unwrap();
+		empty.flash(&recorder, &image, |_| ()).unwrap();
+		assert_eq!(recorder.flashed.borrow().len(), 2);
+	}
+}
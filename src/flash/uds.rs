@@ -0,0 +1,411 @@
+use super::{Flasher, FlashData, Verifier};
+
+use crate::{
+	protocols::uds::{self, UdsInterface, UdsKeepAlive, keepalive},
+	authenticator::MazdaAuthenticator,
+	definition::{self, CheckParameters, FlashRegion, SecurityMode},
+	error::{Error, Result},
+};
+
+use std::cmp;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::slice;
+use std::time;
+
+/// Computes the SecurityAccess key for a seed
+pub type KeyFunction = Box<Fn(&[u8]) -> Vec<u8>>;
+
+/// Returns the memoryAddress and memorySize of a segment
+fn memory_range(data: &FlashData) -> Result<(u32, u32)> {
+	let address = u32::try_from(data.offset).map_err(|_| Error::OutsideFlashRegion)?;
+	let size = u32::try_from(data.data.len()).map_err(|_| Error::TooMuchData)?;
+	Ok((address, size))
+}
+
+/// Flashes ECUs using the standard ISO 14229 programming sequence: programming session,
+/// security access, erase routine, RequestDownload, TransferData, RequestTransferExit,
+/// check programming dependencies routine and ECUReset.
+pub struct UdsFlasher {
+	interface: UdsKeepAlive,
+	session: u8,
+	region: FlashRegion,
+	security: Option<(u8, KeyFunction)>,
+	erase_routine: Option<u16>,
+	check_routine: Option<u16>,
+	check_parameters: CheckParameters,
+	data_format: u8,
+	address_format: u8,
+	reset_type: Option<u8>,
+	block_size: Option<usize>,
+	verifier: Option<Box<Verifier>>,
+}
+
+impl UdsFlasher {
+	/// Creates a flasher using the programming session `session` that only writes
+	/// within `region`. Security access, the routines and ECUReset are disabled
+	/// until configured.
+	pub fn new(interface: Rc<UdsInterface>, session: u8, region: FlashRegion) -> UdsFlasher {
+		UdsFlasher {
+			interface: UdsKeepAlive::new(interface, keepalive::DEFAULT_S3),
+			session,
+			region,
+			security: None,
+			erase_routine: None,
+			check_routine: None,
+			check_parameters: CheckParameters::None,
+			data_format: 0x00,
+			address_format: 0x44,
+			reset_type: None,
+			block_size: None,
+			verifier: None,
+		}
+	}
+
+	/// Creates a flasher configured by the platform definition. Returns `None` if the
+	/// platform has no UDS flash parameters.
+	pub fn from_platform(interface: Rc<UdsInterface>, platform: &definition::Main) -> Option<UdsFlasher> {
+		let config = platform.transfer.uds.as_ref()?;
+		let mut flasher = UdsFlasher::new(interface, platform.auth.flash_sessionid as u8, platform.flash_region)
+			.with_erase_routine(config.erase_routine)
+			.with_check_routine(config.check_routine, config.check_parameters.clone())
+			.with_formats(config.data_format, config.address_format)
+			.with_reset(config.reset_type);
+		if let SecurityMode::Mazda = config.security {
+			let key = platform.auth.key.clone();
+			flasher = flasher.with_security(config.security_level, Box::new(move |seed| {
+				MazdaAuthenticator::generate_key(&key, 0xC541A9, seed).to_vec()
+			}));
+		}
+		if let Some(block_size) = platform.transfer.block_size {
+			flasher = flasher.with_block_size(block_size);
+		}
+		Some(flasher)
+	}
+
+	/// Enables security access with the requestSeed level `level`. The level must
+	/// be odd and below 0xFF as the key is sent with `level + 1`.
+	pub fn with_security(mut self, level: u8, key: KeyFunction) -> UdsFlasher {
+		self.security = Some((level, key));
+		self
+	}
+
+	/// Sets the RoutineControl identifier of the erase routine
+	pub fn with_erase_routine(mut self, routine: Option<u16>) -> UdsFlasher {
+		self.erase_routine = routine;
+		self
+	}
+
+	/// Sets the RoutineControl identifier and parameters of the check programming dependencies routine
+	pub fn with_check_routine(mut self, routine: Option<u16>, parameters: CheckParameters) -> UdsFlasher {
+		self.check_routine = routine;
+		self.check_parameters = parameters;
+		self
+	}
+
+	/// Sets the dataFormatIdentifier and addressAndLengthFormatIdentifier
+	pub fn with_formats(mut self, data_format: u8, address_format: u8) -> UdsFlasher {
+		self.data_format = data_format;
+		self.address_format = address_format;
+		self
+	}
+
+	/// Sets the ECUReset type sent after programming
+	pub fn with_reset(mut self, reset_type: Option<u8>) -> UdsFlasher {
+		self.reset_type = reset_type;
+		self
+	}
+
+	/// Limits the data length of TransferData requests below the negotiated maxNumberOfBlockLength
+	pub fn with_block_size(mut self, block_size: usize) -> UdsFlasher {
+		self.block_size = Some(cmp::max(block_size, 1));
+		self
+	}
+
+	/// Verifies the flashed data after programming
+	pub fn with_verifier(mut self, verifier: Box<Verifier>) -> UdsFlasher {
+		self.verifier = Some(verifier);
+		self
+	}
+
+	/// Sets the idle time after which TesterPresent is sent to keep the session active
+	pub fn with_s3(mut self, interval: time::Duration) -> UdsFlasher {
+		self.interface.set_interval(interval);
+		self
+	}
+}
+
+impl UdsFlasher {
+	fn security_access(&self) -> Result<()> {
+		if let Some((level, ref key_function)) = self.security {
+			if level % 2 == 0 || level == 0xFF {
+				return Err(Error::InvalidSecurityLevel(level));
+			}
+			let response = self.interface.request(uds::UDS_REQ_SECURITY, &[level])?;
+			if response.first() != Some(&level) {
+				return Err(Error::InvalidPacket);
+			}
+			let seed = &response[1..];
+			// An all-zero seed means the level is already unlocked
+			if seed.iter().all(|&b| b == 0) {
+				return Ok(());
+			}
+
+			let mut request = vec![level + 1];
+			request.extend_from_slice(&key_function(seed));
+			let response = self.interface.request(uds::UDS_REQ_SECURITY, &request)?;
+			if response.first() != Some(&(level + 1)) {
+				return Err(Error::InvalidPacket);
+			}
+		}
+		Ok(())
+	}
+
+	/// Builds the routineControlOptionRecord of the check programming dependencies routine
+	fn check_options(&self, segments: &[FlashData]) -> Result<Vec<u8>> {
+		match self.check_parameters {
+			CheckParameters::None => Ok(Vec::new()),
+			CheckParameters::Segments => {
+				let mut options = vec![self.address_format];
+				for data in segments {
+					let (address, size) = memory_range(data)?;
+					let memory = uds::encode_memory(self.address_format, address, size)?;
+					options.extend_from_slice(&memory[1..]);
+				}
+				Ok(options)
+			}
+			CheckParameters::Data(ref data) => Ok(data.clone()),
+		}
+	}
+
+	/// Erases and programs one segment
+	fn program(&self, data: &FlashData) -> Result<()> {
+		let (address, size) = memory_range(data)?;
+
+		if let Some(routine) = self.erase_routine {
+			self.interface.routine_control(uds::UDS_ROUTINE_START, routine, &uds::encode_memory(self.address_format, address, size)?)?;
+		}
+
+		// maxNumberOfBlockLength includes the SID and block sequence counter
+		let max_block_length = self.interface.request_download(self.data_format, self.address_format, address, size)?;
+		if max_block_length <= 2 {
+			return Err(Error::InvalidPacket);
+		}
+		let mut block_size = max_block_length - 2;
+		if let Some(limit) = self.block_size {
+			block_size = cmp::min(block_size, limit);
+		}
+
+		let mut sequence: u8 = 1;
+		for (index, block) in data.data.chunks(block_size).enumerate() {
+			self.interface.transfer_data(sequence, block)?;
+			sequence = sequence.wrapping_add(1);
+
+			// Call the update callback
+			if let Some(ref cb) = data.callback {
+				let mut closure = cb.borrow_mut();
+				(&mut *closure)(cmp::min((index + 1) * block_size, data.data.len()) as f32 / data.data.len() as f32);
+			}
+		}
+
+		self.interface.request_transfer_exit(&[])?;
+		Ok(())
+	}
+}
+
+impl Flasher for UdsFlasher {
+	fn flash(&self, data: &FlashData) -> Result<()> {
+		self.flash_segments(slice::from_ref(data))
+	}
+
+	fn supports_region_erase(&self) -> bool {
+		self.erase_routine.is_some()
+	}
+
+	/// Programs all segments in one programming session
+	fn flash_segments(&self, segments: &[FlashData]) -> Result<()> {
+		for data in segments {
+			data.check_region(&self.region)?;
+		}
+
+		self.interface.request_session(self.session)?;
+		self.security_access()?;
+
+		for data in segments {
+			self.program(data)?;
+		}
+
+		if let Some(routine) = self.check_routine {
+			self.interface.routine_control(uds::UDS_ROUTINE_START, routine, &self.check_options(segments)?)?;
+		}
+
+		// Verify before resetting locks the ECU again
+		if let Some(ref verifier) = self.verifier {
+			for data in segments {
+				verifier.verify(data.offset, data.data)?;
+			}
+		}
+
+		if let Some(reset_type) = self.reset_type {
+			self.interface.ecu_reset(reset_type)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::definition::UdsFlash;
+	use std::cell::RefCell;
+
+	/// Records requests and answers each with a positive response echoing the request
+	#[derive(Default)]
+	struct Echo {
+		requests: RefCell<Vec<Vec<u8>>>,
+	}
+
+	impl UdsInterface for Echo {
+		fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>> {
+			let mut request = vec![request_sid];
+			request.extend_from_slice(data);
+			self.requests.borrow_mut().push(request);
+			Ok(data.to_vec())
+		}
+
+		fn send_request(&self, request_sid: u8, data: &[u8]) -> Result<()> {
+			self.request(request_sid, data).map(|_| ())
+		}
+	}
+
+	fn flasher(echo: &Rc<Echo>) -> UdsFlasher {
+		UdsFlasher::new(echo.clone(), 0x85, FlashRegion { offset: 0, size: 0x10000 })
+	}
+
+	#[test]
+	fn security_level_must_be_odd_and_below_0xff() {
+		for &level in &[0x02, 0xFF] {
+			let echo = Rc::new(Echo::default());
+			let flasher = flasher(&echo).with_security(level, Box::new(|seed| seed.to_vec()));
+			match flasher.security_access() {
+				Err(Error::InvalidSecurityLevel(l)) => assert_eq!(l, level),
+				other => panic!("unexpected result {:?}", other),
+			}
+			assert!(echo.requests.borrow().is_empty());
+		}
+	}
+
+	/// Answers the requests of the programming sequence like an ECU and records them
+	#[derive(Default)]
+	struct Programmer {
+		requests: RefCell<Vec<Vec<u8>>>,
+	}
+
+	impl UdsInterface for Programmer {
+		fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>> {
+			let mut request = vec![request_sid];
+			request.extend_from_slice(data);
+			self.requests.borrow_mut().push(request);
+			Ok(match request_sid {
+				// requestSeed
+				uds::UDS_REQ_SECURITY if data[0] % 2 == 1 => vec![data[0], 0x11, 0x22, 0x33],
+				uds::UDS_REQ_SECURITY => {
+					assert_eq!(&data[1..], &[0xEE, 0xDD, 0xCC]);
+					vec![data[0]]
+				},
+				// maxNumberOfBlockLength of 0x0012 in two bytes
+				uds::UDS_REQ_REQUESTDOWNLOAD => vec![0x20, 0x00, 0x12],
+				uds::UDS_REQ_TRANSFERDATA => vec![data[0]],
+				uds::UDS_REQ_TRANSFEREXIT => Vec::new(),
+				_ => data.to_vec(),
+			})
+		}
+	}
+
+	#[test]
+	fn programming_sequence() {
+		let programmer = Rc::new(Programmer::default());
+		let flasher = UdsFlasher::new(programmer.clone(), 0x02, FlashRegion { offset: 0, size: 0x10000 })
+			.with_security(0x01, Box::new(|seed| seed.iter().map(|b| !b).collect()))
+			.with_erase_routine(Some(0xFF00))
+			.with_check_routine(Some(0xFF01), CheckParameters::None)
+			.with_reset(Some(uds::UDS_RESET_HARD));
+		// 257 blocks of 16 bytes wrap the block sequence counter
+		let data: Vec<u8> = (0..257 * 16).map(|i| i as u8).collect();
+		flasher.flash(&FlashData::new(0x2000, &data)).unwrap();
+
+		let requests = programmer.requests.borrow();
+		assert_eq!(&requests[..5], &[
+			vec![uds::UDS_REQ_SESSION, 0x02],
+			vec![uds::UDS_REQ_SECURITY, 0x01],
+			vec![uds::UDS_REQ_SECURITY, 0x02, 0xEE, 0xDD, 0xCC],
+			vec![uds::UDS_REQ_ROUTINECONTROL, 0x01, 0xFF, 0x00, 0x44, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x10, 0x10],
+			vec![uds::UDS_REQ_REQUESTDOWNLOAD, 0x00, 0x44, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x10, 0x10],
+		]);
+
+		let transfers = &requests[5..5 + 257];
+		// Each block holds maxNumberOfBlockLength minus the SID and counter
+		for (index, transfer) in transfers.iter().enumerate() {
+			assert_eq!(transfer[0], uds::UDS_REQ_TRANSFERDATA);
+			assert_eq!(&transfer[2..], &data[index * 16..(index + 1) * 16]);
+		}
+		let counters: Vec<u8> = transfers.iter().map(|transfer| transfer[1]).collect();
+		assert_eq!(&counters[253..], &[0xFE, 0xFF, 0x00, 0x01]);
+
+		assert_eq!(&requests[5 + 257..], &[
+			vec![uds::UDS_REQ_TRANSFEREXIT],
+			vec![uds::UDS_REQ_ROUTINECONTROL, 0x01, 0xFF, 0x01],
+			vec![uds::UDS_REQ_ECURESET, 0x01],
+		]);
+	}
+
+	#[test]
+	fn segments_beyond_32_bits_are_rejected() {
+		if usize::max_value() as u64 <= u32::max_value() as u64 {
+			return;
+		}
+		let data = [0; 4];
+		match memory_range(&FlashData::new(u32::max_value() as usize + 1, &data)) {
+			Err(Error::OutsideFlashRegion) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+		assert_eq!(memory_range(&FlashData::new(u32::max_value() as usize - 3, &data)).unwrap(), (u32::max_value() - 3, 4));
+	}
+
+	#[test]
+	fn region_erase_requires_an_erase_routine() {
+		let echo = Rc::new(Echo::default());
+		assert!(!flasher(&echo).supports_region_erase());
+		assert!(flasher(&echo).with_erase_routine(Some(0xFF00)).supports_region_erase());
+	}
+
+	#[test]
+	fn check_routine_parameters() {
+		let segments = [FlashData::new(0x1000, &[0; 0x20]), FlashData::new(0x8000, &[0; 0x100])];
+
+		let echo = Rc::new(Echo::default());
+		let none = flasher(&echo).with_check_routine(Some(0xFF01), CheckParameters::None);
+		assert_eq!(none.check_options(&segments).unwrap(), Vec::<u8>::new());
+
+		let data = flasher(&echo).with_check_routine(Some(0xFF01), CheckParameters::Data(vec![0xAA, 0x55]));
+		assert_eq!(data.check_options(&segments).unwrap(), vec![0xAA, 0x55]);
+
+		let listed = flasher(&echo).with_formats(0x00, 0x22).with_check_routine(Some(0xFF01), CheckParameters::Segments);
+		assert_eq!(listed.check_options(&segments).unwrap(), vec![0x22, 0x10, 0x00, 0x00, 0x20, 0x80, 0x00, 0x01, 0x00]);
+	}
+
+	#[test]
+	fn security_mode_is_required() {
+		assert!(serde_yaml::from_str::<UdsFlash>("securitylevel: 1").is_err());
+
+		let config: UdsFlash = serde_yaml::from_str("security: none\ncheckparameters: segments").unwrap();
+		assert_eq!(config.security_level, 0x01);
+		assert_eq!(config.erase_routine, Some(0xFF00));
+		assert_eq!(config.address_format, 0x44);
+		assert!(config.reset_type.is_none());
+		match config.check_parameters {
+			CheckParameters::Segments => (),
+			other => panic!("unexpected parameters {:?}", other),
+		}
+	}
+}
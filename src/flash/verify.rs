@@ -0,0 +1,244 @@
+use crate::{
+	protocols::uds::{self, UdsInterface},
+	error::{Error, Result},
+};
+
+use std::cmp;
+use std::rc::Rc;
+
+/// Verifies flashed data after programming
+pub trait Verifier {
+	/// Checks that the ECU holds `data` at `offset`. Returns `Error::VerifyFailed`
+	/// with the first mismatching offset.
+	fn verify(&self, offset: usize, data: &[u8]) -> Result<()>;
+}
+
+/// Returns the offset of the first byte that differs
+fn first_mismatch(offset: usize, expected: &[u8], actual: &[u8]) -> Option<usize> {
+	expected.iter().zip(actual).position(|(a, b)| a != b).map(|i| offset + i)
+}
+
+/// Verifies by reading the flashed region back with ReadMemoryByAddress
+pub struct ReadMemoryVerifier {
+	interface: Rc<UdsInterface>,
+	address_format: Option<u8>,
+	block_size: usize,
+}
+
+impl ReadMemoryVerifier {
+	/// Creates a verifier reading `block_size` bytes per request. If `address_format`
+	/// is `None`, Mazda's request format is used.
+	pub fn new(interface: Rc<UdsInterface>, address_format: Option<u8>, block_size: usize) -> ReadMemoryVerifier {
+		ReadMemoryVerifier {
+			interface,
+			address_format,
+			block_size: cmp::max(block_size, 1),
+		}
+	}
+
+	fn read(&self, address: usize, length: usize) -> Result<Vec<u8>> {
+		match self.address_format {
+			Some(format) => self.interface.read_memory_by_address(format, address as u32, length as u32),
+			None => self.interface.request_read_memory_address(address as u32, cmp::min(length, 0xFFFF) as u16),
+		}
+	}
+}
+
+impl Verifier for ReadMemoryVerifier {
+	fn verify(&self, offset: usize, data: &[u8]) -> Result<()> {
+		let mut position = 0;
+		while position < data.len() {
+			let length = cmp::min(data.len() - position, self.block_size);
+			let section = self.read(offset + position, length)?;
+			if section.is_empty() {
+				return Err(Error::EmptyPacket);
+			}
+			let expected = &data[position..cmp::min(position + section.len(), data.len())];
+			if let Some(mismatch) = first_mismatch(offset + position, expected, &section) {
+				return Err(Error::VerifyFailed(mismatch));
+			}
+			position += expected.len();
+		}
+		Ok(())
+	}
+}
+
+/// Computes the CRC-32 (IEEE 802.3) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+	let mut crc = 0xFFFF_FFFFu32;
+	for &byte in data {
+		crc ^= byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+		}
+	}
+	!crc
+}
+
+/// Verifies with a RoutineControl routine that computes the CRC-32 of a memory
+/// region. The routine is started with the addressAndLengthFormatIdentifier, address
+/// and size and returns the checksum in the last 4 bytes of the routineStatusRecord.
+/// Mismatching regions are bisected to find the first mismatching offset.
+pub struct ChecksumVerifier {
+	interface: Rc<UdsInterface>,
+	routine: u16,
+	address_format: u8,
+}
+
+impl ChecksumVerifier {
+	pub fn new(interface: Rc<UdsInterface>, routine: u16, address_format: u8) -> ChecksumVerifier {
+		ChecksumVerifier {
+			interface,
+			routine,
+			address_format,
+		}
+	}
+
+	/// Returns true if the ECU's checksum of the region matches `data`
+	fn matches(&self, offset: usize, data: &[u8]) -> Result<bool> {
+		let parameters = uds::encode_memory(self.address_format, offset as u32, data.len() as u32)?;
+		let status = self.interface.routine_control(uds::UDS_ROUTINE_START, self.routine, &parameters)?;
+		if status.len() < 4 {
+			return Err(Error::InvalidPacket);
+		}
+		let checksum = status[status.len() - 4..].iter().fold(0, |acc, &b| (acc << 8) | b as u32);
+		Ok(checksum == crc32(data))
+	}
+}
+
+impl Verifier for ChecksumVerifier {
+	fn verify(&self, offset: usize, data: &[u8]) -> Result<()> {
+		if data.is_empty() || self.matches(offset, data)? {
+			return Ok(());
+		}
+		// Narrow down the mismatching region
+		let (mut start, mut end) = (0, data.len());
+		while end - start > 1 {
+			let middle = start + (end - start) / 2;
+			if !self.matches(offset + start, &data[start..middle])? {
+				end = middle;
+			} else {
+				start = middle;
+			}
+		}
+		Err(Error::VerifyFailed(offset + start))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+
+	const BASE: usize = 0x8000;
+
+	/// Answers ReadMemoryByAddress and checksum routine requests from its memory at `BASE`
+	struct Memory {
+		memory: Vec<u8>,
+		requests: Cell<usize>,
+	}
+
+	impl Memory {
+		fn new(memory: Vec<u8>) -> Rc<Memory> {
+			Rc::new(Memory { memory, requests: Cell::new(0) })
+		}
+
+		fn region(&self, address: &[u8], size: &[u8]) -> &[u8] {
+			let address = address.iter().fold(0, |acc, &b| (acc << 8) | b as usize) - BASE;
+			let size = size.iter().fold(0, |acc, &b| (acc << 8) | b as usize);
+			&self.memory[address..address + size]
+		}
+	}
+
+	impl UdsInterface for Memory {
+		fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>> {
+			self.requests.set(self.requests.get() + 1);
+			match request_sid {
+				// Mazda's format without the addressAndLengthFormatIdentifier
+				uds::UDS_REQ_READMEM if data.len() == 6 => Ok(self.region(&data[..4], &data[4..]).to_vec()),
+				uds::UDS_REQ_READMEM => Ok(self.region(&data[1..5], &data[5..]).to_vec()),
+				uds::UDS_REQ_ROUTINECONTROL => {
+					let mut response = data[..3].to_vec();
+					response.extend_from_slice(&crc32(self.region(&data[4..8], &data[8..])).to_be_bytes());
+					Ok(response)
+				},
+				_ => Err(Error::Unsupported),
+			}
+		}
+	}
+
+	fn image() -> Vec<u8> {
+		(0..0x100).map(|i| (i * 3) as u8).collect()
+	}
+
+	/// Returns the ECU's memory with the bytes at `offsets` corrupted
+	fn corrupted(offsets: &[usize]) -> Vec<u8> {
+		let mut memory = image();
+		for &offset in offsets {
+			memory[offset] ^= 0x5A;
+		}
+		memory
+	}
+
+	fn assert_fails_at(verifier: &Verifier, offset: usize) {
+		match verifier.verify(BASE, &image()) {
+			Err(Error::VerifyFailed(mismatch)) => assert_eq!(mismatch, offset),
+			res => panic!("expected VerifyFailed, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn read_memory_verifier_reports_the_first_mismatch() {
+		for &address_format in &[None, Some(0x44)] {
+			let verifier = ReadMemoryVerifier::new(Memory::new(image()), address_format, 0x10);
+			verifier.verify(BASE, &image()).unwrap();
+
+			// On a block boundary
+			let verifier = ReadMemoryVerifier::new(Memory::new(corrupted(&[0x40, 0x90])), address_format, 0x10);
+			assert_fails_at(&verifier, BASE + 0x40);
+
+			// In the middle of a block
+			let verifier = ReadMemoryVerifier::new(Memory::new(corrupted(&[0x47, 0x48])), address_format, 0x10);
+			assert_fails_at(&verifier, BASE + 0x47);
+		}
+	}
+
+	#[test]
+	fn checksum_verifier_reports_the_first_mismatch() {
+		let verifier = ChecksumVerifier::new(Memory::new(image()), 0xFF02, 0x44);
+		verifier.verify(BASE, &image()).unwrap();
+
+		let verifier = ChecksumVerifier::new(Memory::new(corrupted(&[0x40, 0x90])), 0xFF02, 0x44);
+		assert_fails_at(&verifier, BASE + 0x40);
+
+		let verifier = ChecksumVerifier::new(Memory::new(corrupted(&[0x47, 0x48])), 0xFF02, 0x44);
+		assert_fails_at(&verifier, BASE + 0x47);
+
+		let verifier = ChecksumVerifier::new(Memory::new(corrupted(&[0xFF])), 0xFF02, 0x44);
+		assert_fails_at(&verifier, BASE + 0xFF);
+	}
+
+	#[test]
+	fn crc32_check_value() {
+		assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+		assert_eq!(crc32(&[]), 0);
+	}
+
+	#[test]
+	fn bisection_is_logarithmic() {
+		let size = 0x10000;
+		let image: Vec<u8> = (0..size).map(|i| (i * 13 + i / 256) as u8).collect();
+		let mut memory = image.clone();
+		memory[0xBEEF] = !memory[0xBEEF];
+		let memory = Memory::new(memory);
+
+		let verifier = ChecksumVerifier::new(memory.clone(), 0xFF02, 0x44);
+		match verifier.verify(BASE, &image) {
+			Err(Error::VerifyFailed(mismatch)) => assert_eq!(mismatch, BASE + 0xBEEF),
+			res => panic!("expected VerifyFailed, got {:?}", res),
+		}
+		// One checksum of the whole region and one per halving
+		let halvings = (size as u32).trailing_zeros() as usize;
+		assert_eq!(memory.requests.get(), 1 + halvings);
+	}
+}
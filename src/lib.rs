@@ -16,5 +16,6 @@ pub mod definition;
 pub mod rom;
 pub mod datalog;
 pub mod diagnostics;
+pub mod simulator;
 
 pub use self::error::{Error, Result};
\ No newline at end of file
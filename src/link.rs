@@ -1,30 +1,40 @@
 #[cfg(feature = "socketcan")]
-use crate::protocols::can::socketcan::SocketCan;
+use crate::protocols::{
+	can::socketcan::{self, SocketCan},
+	isotp::IsotpSocket,
+};
 #[cfg(feature = "j2534")]
 use crate::protocols::can::j2534can::J2534Can;
+#[cfg(feature = "slcan")]
+use crate::protocols::can::slcan::SlcanCan;
+#[cfg(feature = "elm327")]
+use crate::protocols::isotp::elm327::{Elm327, Elm327Isotp};
 
 use crate::{
 	protocols::{
-		can::CanInterface,
+		can::{CanInterface, TcpCan},
 		isotp::{self, IsotpInterface, IsotpCan},
-		uds::{UdsIsotp, UdsInterface},
+		uds::{self, UdsIsotp, UdsInterface},
 	},
 	download::{self, Downloader},
-	flash::{self, Flasher},
-	definition::{self, DownloadMode, FlashMode, LogMode},
+	flash::{self, Flasher, FlashPlan, Verifier, ReadMemoryVerifier, ChecksumVerifier},
+	definition::{self, DownloadMode, FlashMode, LogMode, VerifyMode},
+	rom::Rom,
 	datalog,
-	error::Result,
+	error::{Error, Result},
 };
 
 use std::rc::Rc;
 use std::time;
+#[cfg(feature = "slcan")]
+use std::cell::RefCell;
 
 pub trait DataLink {
 	/// Returns a CAN interface if supported
 	fn can(&self, baudrate: usize) -> Option<Rc<CanInterface>>;
 
-	/// Returns an ISO-TP interface if supported
-	fn isotp(&self, options: isotp::Options) -> Option<Rc<IsotpInterface>>;
+	/// Returns an ISO-TP interface on a bus with the bitrate `baudrate` if supported
+	fn isotp(&self, baudrate: usize, options: isotp::Options) -> Option<Rc<IsotpInterface>>;
 }
 
 #[cfg(feature = "socketcan")]
@@ -62,16 +72,116 @@ impl DataLink for J2534DataLink {
 		 None
 	}
 
-	fn isotp(&self, options: isotp::Options) -> Option<Rc<IsotpInterface>> {
+	fn isotp(&self, baudrate: usize, options: isotp::Options) -> Option<Rc<IsotpInterface>> {
 		// The PassThru device may have an ISO-TP layer, but we will use CAN for now
-		// TODO: Replace hardcoded baudrate
-		if let Some(interface) = self.can(500000) {
+		if let Some(interface) = self.can(baudrate) {
 			return Some(Rc::new(IsotpCan::new(interface, options)));
 		}
 		None
 	}
 }
 
+#[cfg(feature = "slcan")]
+pub struct SlcanDataLink {
+	path: String,
+	// The open interface and its baudrate
+	interface: RefCell<Option<(usize, Rc<SlcanCan>)>>,
+}
+
+#[cfg(feature = "slcan")]
+impl SlcanDataLink {
+	pub fn new(path: &str) -> SlcanDataLink {
+		SlcanDataLink {
+			path: path.to_string(),
+			interface: RefCell::new(None),
+		}
+	}
+}
+
+#[cfg(feature = "slcan")]
+impl DataLink for SlcanDataLink {
+	fn can(&self, baudrate: usize) -> Option<Rc<CanInterface>> {
+		let mut interface = self.interface.borrow_mut();
+		if let Some((open_baudrate, ref can)) = *interface {
+			if open_baudrate == baudrate {
+				return Some(can.clone());
+			}
+		}
+		// Close the channel before reopening it with the new baudrate
+		*interface = None;
+
+		match SlcanCan::open(&self.path, baudrate as u32) {
+			Ok(can) => {
+				let can = Rc::new(can);
+				*interface = Some((baudrate, can.clone()));
+				Some(can)
+			},
+			// TODO: Process error
+			Err(_) => None,
+		}
+	}
+
+	fn isotp(&self, baudrate: usize, options: isotp::Options) -> Option<Rc<IsotpInterface>> {
+		if let Some(interface) = self.can(baudrate) {
+			return Some(Rc::new(IsotpCan::new(interface, options)));
+		}
+		None
+	}
+}
+
+#[cfg(feature = "elm327")]
+pub struct Elm327DataLink {
+	elm: Rc<Elm327>,
+}
+
+#[cfg(feature = "elm327")]
+impl Elm327DataLink {
+	pub fn new(elm: Rc<Elm327>) -> Elm327DataLink {
+		Elm327DataLink {
+			elm,
+		}
+	}
+}
+
+#[cfg(feature = "elm327")]
+impl DataLink for Elm327DataLink {
+	fn can(&self, _baudrate: usize) -> Option<Rc<CanInterface>> {
+		// The adapter does not give raw access to the bus
+		None
+	}
+
+	fn isotp(&self, _baudrate: usize, options: isotp::Options) -> Option<Rc<IsotpInterface>> {
+		match Elm327Isotp::new(self.elm.clone(), options) {
+			Ok(interface) => Some(Rc::new(interface)),
+			// TODO: Process error
+			Err(_) => None,
+		}
+	}
+}
+
+pub struct TcpCanDataLink {
+	interface: Rc<TcpCan>,
+}
+
+impl TcpCanDataLink {
+	pub fn new(interface: Rc<TcpCan>) -> TcpCanDataLink {
+		TcpCanDataLink {
+			interface,
+		}
+	}
+}
+
+impl DataLink for TcpCanDataLink {
+	fn can(&self, _baudrate: usize) -> Option<Rc<CanInterface>> {
+		// The baudrate is configured on the server
+		Some(self.interface.clone())
+	}
+
+	fn isotp(&self, _baudrate: usize, options: isotp::Options) -> Option<Rc<IsotpInterface>> {
+		Some(Rc::new(IsotpCan::new(self.interface.clone(), options)))
+	}
+}
+
 pub trait DataLinkEntry {
 	/// Creates the datalink
 	fn create(&self) -> Result<Box<DataLink>>;
@@ -86,6 +196,10 @@ pub trait DataLinkEntry {
 #[cfg(feature = "socketcan")]
 pub struct SocketCanDataLinkEntry {
 	pub interface: String,
+	/// Operational state of the interface
+	pub state: String,
+	/// Bitrate of the interface, if known
+	pub bitrate: Option<u32>,
 }
 
 #[cfg(feature = "socketcan")]
@@ -99,7 +213,73 @@ impl DataLinkEntry for SocketCanDataLinkEntry {
 	}
 
 	fn description(&self) -> String {
-		String::from("Interface: ") + &self.interface
+		match self.bitrate {
+			Some(bitrate) => format!("Interface: {} ({}, {} bit/s)", self.interface, self.state, bitrate),
+			None => format!("Interface: {} ({})", self.interface, self.state),
+		}
+	}
+}
+
+#[cfg(feature = "slcan")]
+pub struct SlcanDataLinkEntry {
+	/// Path of the serial device
+	pub path: String,
+}
+
+#[cfg(feature = "slcan")]
+impl DataLinkEntry for SlcanDataLinkEntry {
+	fn create(&self) -> Result<Box<DataLink>> {
+		Ok(Box::new(SlcanDataLink::new(&self.path)))
+	}
+
+	fn typename(&self) -> &'static str {
+		"SLCAN"
+	}
+
+	fn description(&self) -> String {
+		String::from("Device: ") + &self.path
+	}
+}
+
+#[cfg(feature = "elm327")]
+pub struct Elm327DataLinkEntry {
+	/// Path of the serial device
+	pub path: String,
+	/// Speed of the serial device
+	pub baudrate: u32,
+}
+
+#[cfg(feature = "elm327")]
+impl DataLinkEntry for Elm327DataLinkEntry {
+	fn create(&self) -> Result<Box<DataLink>> {
+		Ok(Box::new(Elm327DataLink::new(Rc::new(Elm327::open(&self.path, self.baudrate)?))))
+	}
+
+	fn typename(&self) -> &'static str {
+		"ELM327"
+	}
+
+	fn description(&self) -> String {
+		String::from("Device: ") + &self.path
+	}
+}
+
+pub struct TcpCanDataLinkEntry {
+	/// Address of the server, e.g. "192.168.1.20:20000"
+	pub address: String,
+}
+
+impl DataLinkEntry for TcpCanDataLinkEntry {
+	fn create(&self) -> Result<Box<DataLink>> {
+		Ok(Box::new(TcpCanDataLink::new(Rc::new(TcpCan::connect(&self.address[..])?))))
+	}
+
+	fn typename(&self) -> &'static str {
+		"TCP"
+	}
+
+	fn description(&self) -> String {
+		String::from("Server: ") + &self.address
 	}
 }
 
@@ -144,6 +324,21 @@ pub fn discover_datalinks() -> Vec<Box<DataLinkEntry>> {
 		} // Else, listing failed (TODO: log error?)
 	}
 
+	// Search for SocketCAN interfaces
+	#[cfg(feature = "socketcan")]
+	{
+		if let Ok(list) = socketcan::list() {
+			for info in list {
+				let entry: Box<DataLinkEntry> = Box::new(SocketCanDataLinkEntry {
+					interface: info.name,
+					state: info.state,
+					bitrate: info.bitrate,
+				});
+				links.push(entry);
+			}
+		}
+	}
+
 	links
 }
 
@@ -164,8 +359,12 @@ impl DataLink for SocketCanDataLink {
 		Some(self.interface.clone())
 	}
 
-	/// Returns an ISO-TP interface if supported
-	fn isotp(&self, options: isotp::Options) -> Option<Rc<IsotpInterface>> {
+	/// Returns an ISO-TP interface if supported. Uses the kernel's ISO-TP
+	/// implementation if available.
+	fn isotp(&self, _baudrate: usize, options: isotp::Options) -> Option<Rc<IsotpInterface>> {
+		if let Ok(socket) = IsotpSocket::open_idx(self.interface.if_index(), options.clone()) {
+			return Some(Rc::new(socket));
+		}
 		let iface = IsotpCan::new(self.interface.clone(), options);
 		Some(Rc::new(iface))
 	}
@@ -195,12 +394,13 @@ impl PlatformLink {
 			dest_id: server_id + 0x08,
 			// TODO: Pull this from a config
 			timeout: time::Duration::from_secs(1),
+			..Default::default()
 		}
 	}
 
 	/// Returns the ISO-TP interface for the platform, if supported
 	pub fn isotp(&self) -> Option<Rc<IsotpInterface>> {
-		self.link.isotp(self.isotp_options())
+		self.link.isotp(self.platform.baudrate as usize, self.isotp_options())
 	}
 
 	/// Returns the UDS interface for the platform, if supported
@@ -211,12 +411,35 @@ impl PlatformLink {
 		None
 	}
 
+	/// Returns the UDS interface used for flashing. TesterPresent is sent while
+	/// erase and check routines keep the server busy. Returns `None` if the link
+	/// cannot send RequestDownload and TransferData requests, so flashing never
+	/// starts erasing.
+	fn flash_uds(&self) -> Option<Rc<UdsInterface>> {
+		let isotp_interface = self.isotp()?;
+		if !isotp_interface.supports_multi_frame_send() {
+			return None;
+		}
+		Some(Rc::new(UdsIsotp::new(isotp_interface).with_keep_alive(uds::keepalive::DEFAULT_S3)))
+	}
+
 	/// Returns the downloader for the platform, if supported by the platform AND datalink
 	pub fn downloader(&self) -> Option<Box<Downloader>> {
+		self.downloader_with(download::Options::default())
+	}
+
+	/// Returns the downloader for the platform with the partial download file, cancel
+	/// handle and retries of `options`, if supported by the platform AND datalink
+	pub fn downloader_with(&self, options: download::Options) -> Option<Box<Downloader>> {
 		match self.platform.transfer.download_mode {
 			DownloadMode::Mazda1 => {
 				if let Some(uds_interface) = self.uds() {
-					return Some(Box::new(download::mazda::Mazda1Downloader::new(uds_interface, &self.platform.auth.key, self.platform.rom_size)));
+					let mut downloader = download::mazda::Mazda1Downloader::new(uds_interface, &self.platform.auth.key, self.platform.rom_size)
+						.with_options(options, &self.platform.id);
+					if let Some(block_size) = self.platform.transfer.block_size {
+						downloader = downloader.with_block_size(block_size);
+					}
+					return Some(Box::new(downloader));
 				}
 				None
 			},
@@ -224,12 +447,45 @@ impl PlatformLink {
 		}
 	}
 
+	/// Returns the flash verifier configured by the platform. `address_format` is the
+	/// addressAndLengthFormatIdentifier, or `None` for Mazda's requests.
+	fn verifier(&self, uds_interface: &Rc<UdsInterface>, address_format: Option<u8>) -> Option<Box<Verifier>> {
+		match self.platform.transfer.verify {
+			VerifyMode::ReadMemory => {
+				let block_size = self.platform.transfer.block_size.unwrap_or(uds::DEFAULT_BLOCK_SIZE);
+				Some(Box::new(ReadMemoryVerifier::new(uds_interface.clone(), address_format, block_size)))
+			},
+			VerifyMode::Checksum { routine } => {
+				Some(Box::new(ChecksumVerifier::new(uds_interface.clone(), routine, address_format.unwrap_or(0x44))))
+			},
+			VerifyMode::None => None,
+		}
+	}
+
 	/// Returns the flash interface for the platform, if supported by the platform AND datalink
 	pub fn flasher(&self) -> Option<Box<Flasher>> {
 		match self.platform.transfer.flash_mode {
 			FlashMode::Mazda1 => {
-				if let Some(uds_interface) = self.uds() {
-					return Some(Box::new(flash::mazda::Mazda1Flasher::new(uds_interface, &self.platform.auth.key)));
+				if let Some(uds_interface) = self.flash_uds() {
+					let mut flasher = flash::mazda::Mazda1Flasher::new(uds_interface.clone(), &self.platform.auth.key, self.platform.flash_region);
+					if let Some(block_size) = self.platform.transfer.block_size {
+						flasher = flasher.with_block_size(block_size);
+					}
+					if let Some(verifier) = self.verifier(&uds_interface, None) {
+						flasher = flasher.with_verifier(verifier);
+					}
+					return Some(Box::new(flasher));
+				}
+				None
+			},
+			FlashMode::Uds => {
+				let config = self.platform.transfer.uds.as_ref()?;
+				if let Some(uds_interface) = self.flash_uds() {
+					let mut flasher = flash::uds::UdsFlasher::from_platform(uds_interface.clone(), &self.platform)?;
+					if let Some(verifier) = self.verifier(&uds_interface, Some(config.address_format)) {
+						flasher = flasher.with_verifier(verifier);
+					}
+					return Some(Box::new(flasher));
 				}
 				None
 			},
@@ -237,6 +493,26 @@ impl PlatformLink {
 		}
 	}
 
+	/// Flashes `image`, a modified copy of `rom`. If the flasher supports region erase,
+	/// only the sectors that differ from the image last flashed with `rom` are programmed.
+	/// `image` is recorded as flashed once programming and verification succeed. After a
+	/// failed flash, the whole region is programmed.
+	pub fn flash<CB: 'static + FnMut(f32)>(&self, rom: &Rom, image: &[u8], callback: CB) -> Result<FlashPlan> {
+		let flasher = self.flasher().ok_or(Error::Unsupported)?;
+		let plan = match rom.flashed_data()? {
+			Some(current) => FlashPlan::new(&self.platform, &*flasher, &current, image)?,
+			None => FlashPlan::whole(&self.platform, image)?,
+		};
+		if plan.is_empty() {
+			return Ok(plan);
+		}
+		// Any failure from the first erase on leaves the contents unknown
+		rom.set_flashed_unknown()?;
+		plan.flash(&*flasher, image, callback)?;
+		rom.set_flashed(image)?;
+		Ok(plan)
+	}
+
 	/// Returns the datalogging interface for the platform, if supported by the platform AND datalink
 	pub fn datalogger(&self) -> Option<Box<datalog::Logger>> {
 		match self.platform.log_mode {
@@ -249,4 +525,172 @@ impl PlatformLink {
 			_ => None,
 		}
 	}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		protocols::can::{VirtualCan, VirtualCanBus},
+		simulator::{testing, mazda::Mazda1Ecu},
+		rom::RomManager,
+	};
+	use std::fs;
+	use std::path::Path;
+	use std::process;
+	use std::sync::Arc;
+
+	/// A datalink on a virtual CAN bus
+	struct VirtualDataLink {
+		can: Rc<VirtualCan>,
+		single_frame: bool,
+	}
+
+	/// An ISO-TP interface that can only send single frames like an ELM327
+	struct SingleFrameIsotp(IsotpCan);
+
+	impl IsotpInterface for SingleFrameIsotp {
+		fn recv(&self) -> Result<Vec<u8>> {
+			self.0.recv()
+		}
+
+		fn send(&self, data: &[u8]) -> Result<()> {
+			if data.len() > 7 {
+				return Err(Error::Unsupported);
+			}
+			self.0.send(data)
+		}
+
+		fn supports_multi_frame_send(&self) -> bool {
+			false
+		}
+	}
+
+	impl DataLink for VirtualDataLink {
+		fn can(&self, _baudrate: usize) -> Option<Rc<CanInterface>> {
+			Some(self.can.clone())
+		}
+
+		fn isotp(&self, _baudrate: usize, options: isotp::Options) -> Option<Rc<IsotpInterface>> {
+			let isotp = IsotpCan::new(self.can.clone(), options);
+			if self.single_frame {
+				return Some(Rc::new(SingleFrameIsotp(isotp)));
+			}
+			Some(Rc::new(isotp))
+		}
+	}
+
+	fn virtual_link(bus: &VirtualCanBus, single_frame: bool) -> PlatformLink {
+		let link = VirtualDataLink { can: Rc::new(bus.open()), single_frame };
+		PlatformLink::new(Box::new(link), Rc::new(serde_yaml::from_str(PLATFORM).unwrap()))
+	}
+
+	const PLATFORM: &str = "
+name: Test
+id: test
+transfer:
+  serverid: 0x7E0
+  flash_mode: mazda1
+  blocksize: 0x400
+  verify: readmemory
+baudrate: 500000
+endianness: big
+flashregion: {offset: 0x1000, size: 0x1000}
+auth: {key: MazdA, download_sessionid: 0x85, flash_sessionid: 0x85}
+romsize: 0x2000
+tables: {}
+pids: []
+vins: []
+";
+
+	fn memory() -> Vec<u8> {
+		(0..0x2000).map(|i| (i * 7) as u8).collect()
+	}
+
+	/// Creates a ROM of `memory()` in the directory `base`
+	fn new_rom(base: &Path) -> Rc<Rom> {
+		let _ = fs::remove_dir_all(base);
+		fs::create_dir_all(base).unwrap();
+		let platform: definition::Main = serde_yaml::from_str(PLATFORM).unwrap();
+		let model: definition::Model = serde_yaml::from_str("{id: test, name: Test}").unwrap();
+		let mut roms = RomManager::new(base.to_path_buf());
+		roms.new_rom("Test".to_string(), "test".to_string(), Arc::new(platform), Arc::new(model), memory())
+	}
+
+	/// Returns a copy of `data` with the bytes in `range` inverted
+	fn inverted(data: &[u8], range: std::ops::Range<usize>) -> Vec<u8> {
+		let mut image = data.to_vec();
+		for byte in &mut image[range] {
+			*byte = !*byte;
+		}
+		image
+	}
+
+	/// Flashes `image` to a server that accepts writes to `flash_size` bytes of the region
+	fn flash_to(rom: &Rom, image: &[u8], flash_size: usize) -> Result<FlashPlan> {
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new("MazdA", memory(), 0x1000, flash_size).unwrap());
+		let result = virtual_link(&bus, false).flash(rom, image, |_| ());
+		server.stop();
+		result
+	}
+
+	#[test]
+	fn flash_records_the_image_after_verification() {
+		let base = std::env::temp_dir().join(format!("tuneutils-link-flash-{}", process::id()));
+		let rom = new_rom(&base);
+		let image = inverted(rom.data(), 0x1800..0x1810);
+
+		// The server rejects writes past 0x1800, so the contents are left unknown
+		assert!(flash_to(&rom, &image, 0x800).is_err());
+		assert_eq!(rom.flashed_data().unwrap(), None);
+
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new("MazdA", memory(), 0x1000, 0x1000).unwrap());
+		let link = virtual_link(&bus, false);
+		let plan = link.flash(&rom, &image, |_| ()).unwrap();
+		assert_eq!(plan.size(), 0x1000);
+		assert_eq!(rom.flashed_data().unwrap(), Some(image.clone()));
+		// The image is already flashed
+		assert!(link.flash(&rom, &image, |_| ()).unwrap().is_empty());
+		assert_eq!(server.stop().memory(), &image[..]);
+
+		fs::remove_dir_all(&base).unwrap();
+	}
+
+	#[test]
+	fn failed_flashes_replan_every_sector() {
+		let base = std::env::temp_dir().join(format!("tuneutils-link-failed-flash-{}", process::id()));
+		let rom = new_rom(&base);
+		let first = inverted(rom.data(), 0x1100..0x1110);
+		assert_eq!(flash_to(&rom, &first, 0x1000).unwrap().size(), 0x1000);
+		assert_eq!(rom.flashed_data().unwrap(), Some(first.clone()));
+
+		// Erasing succeeds but programming past 0x1800 fails, leaving the ECU neither
+		// the first nor the second image
+		let second = inverted(&first, 0x1800..0x1810);
+		assert!(flash_to(&rom, &second, 0x800).is_err());
+		assert_eq!(rom.flashed_data().unwrap(), None);
+
+		// The first image is no longer assumed to be flashed
+		let plan = flash_to(&rom, &first, 0x1000).unwrap();
+		let segments: Vec<(usize, usize)> = plan.segments.iter().map(|segment| (segment.offset, segment.size)).collect();
+		assert_eq!(segments, vec![(0x1000, 0x1000)]);
+		assert_eq!(rom.flashed_data().unwrap(), Some(first));
+
+		fs::remove_dir_all(&base).unwrap();
+	}
+
+	#[test]
+	fn single_frame_links_do_not_flash() {
+		let bus = VirtualCanBus::new();
+		let server = testing::spawn(&bus, Mazda1Ecu::new("MazdA", memory(), 0x1000, 0x1000).unwrap());
+		assert!(virtual_link(&bus, false).flasher().is_some());
+
+		// RequestDownload does not fit in a single frame, so the flash would stop after erasing
+		let link = virtual_link(&bus, true);
+		assert!(link.flasher().is_none());
+		assert!(link.uds().is_some());
+		drop(link);
+		assert_eq!(server.stop().memory(), &memory()[..]);
+	}
+}
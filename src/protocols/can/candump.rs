@@ -0,0 +1,615 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::thread;
+use std::time;
+
+use super::{CanInterface, Message, Filter, FrameFormat, Clock, Timestamp, filters_match, CAN_SFF_MAX, CAN_EFF_MAX};
+use crate::error::{Error, Result};
+
+
+/// Error frame flag used in candump identifiers
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+
+const CANFD_BRS: u8 = 0x01;
+const CANFD_ESI: u8 = 0x02;
+
+/// Direction of a logged frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A single line of a candump log
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    /// Time since the UNIX epoch
+    pub timestamp: time::Duration,
+    pub interface: String,
+    pub message: Message,
+    /// `None` if the log does not record the direction
+    pub direction: Option<Direction>,
+}
+
+impl LogEntry {
+    /// Formats the entry as a line in the `candump -l` format, without the newline.
+    /// The direction is appended as ` T` or ` R` like `candump -l -x`.
+    pub fn format(&self) -> String {
+        let message = &self.message;
+        let mut line = format!("({}.{:06}) {} ", self.timestamp.as_secs(), self.timestamp.subsec_micros(), self.interface);
+
+        if message.error {
+            write!(line, "{:08X}", message.id | CAN_ERR_FLAG).unwrap();
+        } else if message.is_extended() {
+            write!(line, "{:08X}", message.id).unwrap();
+        } else {
+            write!(line, "{:03X}", message.id).unwrap();
+        }
+
+        line.push('#');
+        if message.fd {
+            let mut flags = 0;
+            if message.brs {
+                flags |= CANFD_BRS;
+            }
+            if message.esi {
+                flags |= CANFD_ESI;
+            }
+            write!(line, "#{:X}", flags).unwrap();
+        }
+        if message.remote {
+            line.push('R');
+            if !message.data.is_empty() {
+                write!(line, "{}", message.data.len()).unwrap();
+            }
+        } else {
+            for byte in &message.data {
+                write!(line, "{:02X}", byte).unwrap();
+            }
+        }
+
+        match self.direction {
+            Some(Direction::Sent) => line.push_str(" T"),
+            Some(Direction::Received) => line.push_str(" R"),
+            None => (),
+        }
+        line
+    }
+
+    /// Parses a line in the `candump -l` format
+    pub fn parse(line: &str) -> Result<LogEntry> {
+        let mut parts = line.split_whitespace();
+        let timestamp = parts.next().ok_or(Error::InvalidFrame)?;
+        let interface = parts.next().ok_or(Error::InvalidFrame)?;
+        let frame = parts.next().ok_or(Error::InvalidFrame)?;
+        let direction = match parts.next() {
+            Some("T") => Some(Direction::Sent),
+            Some("R") => Some(Direction::Received),
+            _ => None,
+        };
+
+        Ok(LogEntry {
+            timestamp: parse_timestamp(timestamp)?,
+            interface: interface.to_string(),
+            message: parse_frame(frame)?,
+            direction,
+        })
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<time::Duration> {
+    if !timestamp.starts_with('(') || !timestamp.ends_with(')') {
+        return Err(Error::InvalidFrame);
+    }
+    let timestamp = &timestamp[1..timestamp.len() - 1];
+    let mut parts = timestamp.splitn(2, '.');
+    let secs = parts.next().unwrap();
+    let fraction = parts.next().unwrap_or("0");
+    if !is_digits(secs) || !is_digits(fraction) || fraction.len() > 9 {
+        return Err(Error::InvalidFrame);
+    }
+    let secs = secs.parse::<u64>().map_err(|_| Error::InvalidFrame)?;
+    // Scale the fraction to nanoseconds
+    let nanos = fraction.parse::<u32>().map_err(|_| Error::InvalidFrame)? * 10u32.pow(9 - fraction.len() as u32);
+    Ok(time::Duration::new(secs, nanos))
+}
+
+/// Returns true if `s` is a non-empty string of ASCII digits
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Returns true if `s` is a non-empty string of ASCII hex digits
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn hex_digit(digit: u8) -> Result<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8).ok_or(Error::InvalidFrame)
+}
+
+fn parse_hex(data: &str) -> Result<Vec<u8>> {
+    // Work on bytes so non-ASCII characters can't split a slice
+    let bytes = data.as_bytes();
+    if bytes.len() % 2 != 0 {
+        return Err(Error::InvalidFrame);
+    }
+    bytes.chunks(2)
+        .map(|pair| Ok((hex_digit(pair[0])? << 4) | hex_digit(pair[1])?))
+        .collect()
+}
+
+fn parse_frame(frame: &str) -> Result<Message> {
+    let separator = frame.find('#').ok_or(Error::InvalidFrame)?;
+    let id_str = &frame[..separator];
+    if !is_hex(id_str) {
+        return Err(Error::InvalidFrame);
+    }
+    let raw_id = u32::from_str_radix(id_str, 16).map_err(|_| Error::InvalidFrame)?;
+    let mut data = &frame[separator + 1..];
+
+    let mut message = Message::default();
+    if id_str.len() == 8 {
+        message.error = raw_id & CAN_ERR_FLAG != 0;
+        message.id = raw_id & CAN_EFF_MAX;
+        if !message.error {
+            message.format = FrameFormat::Extended;
+        }
+    } else if id_str.len() == 3 && raw_id <= CAN_SFF_MAX {
+        message.id = raw_id;
+    } else {
+        return Err(Error::InvalidFrame);
+    }
+
+    if data.starts_with('#') {
+        // CAN FD frame. The first character holds the flags
+        let flags = hex_digit(*data.as_bytes().get(1).ok_or(Error::InvalidFrame)?)?;
+        message.fd = true;
+        message.brs = flags & CANFD_BRS != 0;
+        message.esi = flags & CANFD_ESI != 0;
+        data = &data[2..];
+    } else if data.starts_with('R') {
+        message.remote = true;
+        let len = if data.len() > 1 {
+            if !is_digits(&data[1..]) {
+                return Err(Error::InvalidFrame);
+            }
+            data[1..].parse::<usize>().map_err(|_| Error::InvalidFrame)?
+        } else {
+            0
+        };
+        if len > 8 {
+            return Err(Error::InvalidFrame);
+        }
+        message.data = vec![0; len];
+        return Ok(message);
+    }
+
+    // Data bytes may be separated by dots
+    message.data = parse_hex(&data.replace('.', ""))?;
+    message.validate().map_err(|_| Error::InvalidFrame)?;
+    Ok(message)
+}
+
+/// Wraps a CAN interface and records every sent and received frame in the `candump -l` format
+pub struct CandumpRecorder {
+    interface: Rc<CanInterface>,
+    writer: RefCell<Box<Write>>,
+    ifname: String,
+}
+
+impl CandumpRecorder {
+    /// Records frames to `writer` using the interface name `ifname`
+    pub fn new(interface: Rc<CanInterface>, writer: Box<Write>, ifname: &str) -> CandumpRecorder {
+        CandumpRecorder {
+            interface,
+            writer: RefCell::new(writer),
+            ifname: ifname.to_string(),
+        }
+    }
+
+    /// Records frames to a new file at `path`
+    pub fn create<P: AsRef<Path>>(interface: Rc<CanInterface>, path: P, ifname: &str) -> Result<CandumpRecorder> {
+        let file = io::BufWriter::new(fs::File::create(path)?);
+        Ok(CandumpRecorder::new(interface, Box::new(file), ifname))
+    }
+
+    /// Flushes the log
+    pub fn flush(&self) -> Result<()> {
+        self.writer.borrow_mut().flush()?;
+        Ok(())
+    }
+
+    fn log(&self, message: &Message, direction: Direction) -> Result<()> {
+        // Timestamps from the interfaces do not share an epoch, so use the system clock
+        let timestamp = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        let entry = LogEntry {
+            timestamp,
+            interface: self.ifname.clone(),
+            message: message.clone(),
+            direction: Some(direction),
+        };
+        writeln!(self.writer.borrow_mut(), "{}", entry.format())?;
+        Ok(())
+    }
+}
+
+impl Drop for CandumpRecorder {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl CanInterface for CandumpRecorder {
+    fn send(&self, id: u32, message: &[u8]) -> Result<()> {
+        self.send_msg(&Message::new(id, message))
+    }
+
+    fn send_msg(&self, message: &Message) -> Result<()> {
+        self.interface.send_msg(message)?;
+        self.log(message, Direction::Sent)
+    }
+
+    fn recv(&self, timeout: time::Duration) -> Result<Message> {
+        let message = self.interface.recv(timeout)?;
+        self.log(&message, Direction::Received)?;
+        Ok(message)
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> Result<()> {
+        self.interface.set_filters(filters)
+    }
+
+    fn try_recv(&self) -> Result<Option<Message>> {
+        let message = self.interface.try_recv()?;
+        if let Some(ref message) = message {
+            self.log(message, Direction::Received)?;
+        }
+        Ok(message)
+    }
+}
+
+struct ReplayState {
+    entries: VecDeque<LogEntry>,
+    /// Time the previous entry was consumed
+    last_instant: time::Instant,
+    /// Log timestamp of the previous entry
+    last_timestamp: Option<time::Duration>,
+}
+
+/// Replays a candump log. Received frames are returned by `recv` in log order.
+/// Frames logged as sent (` T`) are held until a frame is sent through the interface,
+/// so responses are not returned before the request that caused them.
+pub struct CandumpReplay {
+    state: RefCell<ReplayState>,
+    realtime: bool,
+    filters: RefCell<Vec<Filter>>,
+}
+
+impl CandumpReplay {
+    /// Creates a replay from log entries. If `realtime` is true, the
+    /// original time between frames is kept.
+    pub fn new(entries: Vec<LogEntry>, realtime: bool) -> CandumpReplay {
+        CandumpReplay {
+            state: RefCell::new(ReplayState {
+                entries: entries.into(),
+                last_instant: time::Instant::now(),
+                last_timestamp: None,
+            }),
+            realtime,
+            filters: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Loads a log from a reader. Empty lines are skipped
+    pub fn from_reader<R: BufRead>(reader: R, realtime: bool) -> Result<CandumpReplay> {
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            entries.push(LogEntry::parse(&line)?);
+        }
+        Ok(CandumpReplay::new(entries, realtime))
+    }
+
+    /// Loads a log file
+    pub fn open<P: AsRef<Path>>(path: P, realtime: bool) -> Result<CandumpReplay> {
+        CandumpReplay::from_reader(io::BufReader::new(fs::File::open(path)?), realtime)
+    }
+
+    /// Returns the number of entries that have not been replayed
+    pub fn remaining(&self) -> usize {
+        self.state.borrow().entries.len()
+    }
+}
+
+impl CanInterface for CandumpReplay {
+    fn send(&self, id: u32, message: &[u8]) -> Result<()> {
+        self.send_msg(&Message::new(id, message))
+    }
+
+    /// Consumes the next frame that was logged as sent. The data is not compared.
+    fn send_msg(&self, message: &Message) -> Result<()> {
+        message.validate()?;
+
+        let mut state = self.state.borrow_mut();
+        if let Some(pos) = state.entries.iter().position(|entry| entry.direction == Some(Direction::Sent)) {
+            let entry = state.entries.remove(pos).unwrap();
+            if pos == 0 {
+                state.last_instant = time::Instant::now();
+                state.last_timestamp = Some(entry.timestamp);
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&self, timeout: time::Duration) -> Result<Message> {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            let mut state = self.state.borrow_mut();
+            let due = match state.entries.front() {
+                // Waiting for a frame to be sent or the log has ended
+                Some(entry) if entry.direction == Some(Direction::Sent) => None,
+                None => None,
+                Some(entry) => {
+                    match state.last_timestamp {
+                        Some(last) if self.realtime => {
+                            let delay = entry.timestamp.checked_sub(last).unwrap_or_default();
+                            Some(state.last_instant + delay)
+                        },
+                        // Due as soon as the previous entry was consumed, so a
+                        // zero timeout still returns it
+                        _ => Some(state.last_instant),
+                    }
+                },
+            };
+
+            let due = match due {
+                Some(due) if due <= deadline => due,
+                _ => {
+                    drop(state);
+                    let now = time::Instant::now();
+                    if deadline > now {
+                        thread::sleep(deadline - now);
+                    }
+                    return Err(Error::Timeout);
+                },
+            };
+
+            let now = time::Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+
+            let entry = state.entries.pop_front().unwrap();
+            state.last_instant = time::Instant::now();
+            state.last_timestamp = Some(entry.timestamp);
+
+            if filters_match(&self.filters.borrow(), &entry.message) {
+                let mut message = entry.message;
+                message.timestamp = Some(Timestamp::new(entry.timestamp, Clock::System));
+                return Ok(message);
+            }
+        }
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> Result<()> {
+        *self.filters.borrow_mut() = filters.to_vec();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocols::{
+            can::VirtualCanBus,
+            isotp::{IsotpCan, Options},
+            uds::{UdsInterface, UdsIsotp, UDS_DID_VIN},
+        },
+        simulator::{testing, mazda::Mazda1Ecu},
+    };
+
+    const VIN: &[u8] = b"JM1GG12L061100000";
+
+    /// A log writer whose contents stay readable after the recorder is dropped
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Reads the VIN, a multi-frame response, and a short PID
+    fn exchange(tester: &UdsInterface) -> (Vec<u8>, Vec<u8>) {
+        (tester.read_data_by_identifier(UDS_DID_VIN).unwrap(), tester.read_data_by_identifier(0xF188).unwrap())
+    }
+
+    fn timed(message: Message, direction: Direction, millis: u64) -> LogEntry {
+        LogEntry {
+            timestamp: time::Duration::from_millis(1_000 + millis),
+            interface: "can0".to_string(),
+            message,
+            direction: Some(direction),
+        }
+    }
+
+    #[test]
+    fn recorded_exchanges_replay_to_the_same_responses() {
+        let bus = VirtualCanBus::new();
+        let server = testing::spawn(&bus, Mazda1Ecu::new("MazdA", vec![0; 0x100], 0, 0x100).unwrap()
+            .with_pid(UDS_DID_VIN, VIN)
+            .with_pid(0xF188, &[1, 2, 3, 4]));
+        let log = SharedBuffer::default();
+        let recorded = {
+            let recorder = CandumpRecorder::new(Rc::new(bus.open()), Box::new(log.clone()), "vcan0");
+            let tester = UdsIsotp::new(Rc::new(IsotpCan::new(Rc::new(recorder), Options::default())));
+            exchange(&tester)
+        };
+        server.stop();
+        assert_eq!(recorded, (VIN.to_vec(), vec![1, 2, 3, 4]));
+
+        let contents = log.0.borrow().clone();
+        let replay = Rc::new(CandumpReplay::from_reader(&contents[..], false).unwrap());
+        // Request, first frame, flow control, two consecutive frames, request and response
+        assert_eq!(replay.remaining(), 7);
+        let tester = UdsIsotp::new(Rc::new(IsotpCan::new(replay.clone(), Options::default())));
+        assert_eq!(exchange(&tester), recorded);
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn received_frames_wait_for_the_logged_send() {
+        let replay = CandumpReplay::new(vec![
+            timed(Message::new(0x7E0, &[0x02, 0x10, 0x85]), Direction::Sent, 0),
+            timed(Message::new(0x7E8, &[0x02, 0x50, 0x85]), Direction::Received, 0),
+            timed(Message::new(0x7E0, &[0x02, 0x3E, 0x00]), Direction::Sent, 0),
+            timed(Message::new(0x7E8, &[0x02, 0x7E, 0x00]), Direction::Received, 0),
+        ], false);
+
+        match replay.recv(time::Duration::from_millis(10)) {
+            Err(Error::Timeout) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        replay.send(0x7E0, &[0x02, 0x10, 0x85]).unwrap();
+        assert_eq!(replay.recv(time::Duration::from_millis(10)).unwrap().data, vec![0x02, 0x50, 0x85]);
+        // The next response is held until the next request
+        assert!(replay.try_recv().unwrap().is_none());
+        replay.send(0x7E0, &[0x02, 0x3E, 0x00]).unwrap();
+        assert_eq!(replay.try_recv().unwrap().unwrap().data, vec![0x02, 0x7E, 0x00]);
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn realtime_replay_keeps_the_logged_delays() {
+        let entries = vec![
+            timed(Message::new(0x7E0, &[0x02, 0x10, 0x85]), Direction::Sent, 0),
+            timed(Message::new(0x7E8, &[0x02, 0x50, 0x85]), Direction::Received, 100),
+        ];
+
+        let replay = CandumpReplay::new(entries.clone(), true);
+        replay.send(0x7E0, &[0x02, 0x10, 0x85]).unwrap();
+        let start = time::Instant::now();
+        // The response is not due before the timeout
+        match replay.recv(time::Duration::from_millis(20)) {
+            Err(Error::Timeout) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        replay.recv(time::Duration::from_secs(1)).unwrap();
+        assert!(start.elapsed() >= time::Duration::from_millis(100));
+
+        let replay = CandumpReplay::new(entries, false);
+        replay.send(0x7E0, &[0x02, 0x10, 0x85]).unwrap();
+        let start = time::Instant::now();
+        replay.recv(time::Duration::from_secs(1)).unwrap();
+        assert!(start.elapsed() < time::Duration::from_millis(50));
+    }
+
+    fn entry(message: Message, direction: Option<Direction>) -> LogEntry {
+        LogEntry {
+            timestamp: time::Duration::new(1_546_300_800, 123_456_000),
+            interface: "can0".to_string(),
+            message,
+            direction,
+        }
+    }
+
+    fn round_trip(entry: &LogEntry) -> LogEntry {
+        LogEntry::parse(&entry.format()).unwrap()
+    }
+
+    #[test]
+    fn format_and_parse_round_trip() {
+        let original = entry(Message::new(0x7E0, &[0x02, 0x10, 0x85]), Some(Direction::Sent));
+        assert_eq!(original.format(), "(1546300800.123456) can0 7E0#021085 T");
+        let parsed = round_trip(&original);
+        assert_eq!(parsed.timestamp, original.timestamp);
+        assert_eq!(parsed.interface, "can0");
+        assert_eq!(parsed.direction, Some(Direction::Sent));
+        assert_eq!(parsed.message.id, 0x7E0);
+        assert_eq!(parsed.message.data, vec![0x02, 0x10, 0x85]);
+        assert!(!parsed.message.is_extended());
+
+        let parsed = round_trip(&entry(Message::extended(0x18DA_10F1, &[1, 2]), Some(Direction::Received)));
+        assert!(parsed.message.is_extended());
+        assert_eq!(parsed.message.id, 0x18DA_10F1);
+        assert_eq!(parsed.direction, Some(Direction::Received));
+
+        // Extended identifiers are kept even if they fit 11 bits
+        assert!(round_trip(&entry(Message::extended(0x7E0, &[]), None)).message.is_extended());
+
+        let mut fd = Message::fd(0x123, FrameFormat::Standard, &[0xAA; 12], true);
+        fd.esi = true;
+        let parsed = round_trip(&entry(fd, None)).message;
+        assert!(parsed.fd && parsed.brs && parsed.esi);
+        assert_eq!(parsed.data, vec![0xAA; 12]);
+
+        let remote = Message { remote: true, ..Message::new(0x123, &[0; 4]) };
+        let parsed = round_trip(&entry(remote, None)).message;
+        assert!(parsed.remote);
+        assert_eq!(parsed.data.len(), 4);
+
+        let error = Message { error: true, ..Message::new(0x004, &[0; 8]) };
+        let parsed = round_trip(&entry(error, None)).message;
+        assert!(parsed.error);
+        assert_eq!(parsed.id, 0x004);
+    }
+
+    #[test]
+    fn parses_dotted_data_and_missing_direction() {
+        let parsed = LogEntry::parse("(0.5) vcan0 123#01.02.03").unwrap();
+        assert_eq!(parsed.timestamp, time::Duration::from_millis(500));
+        assert_eq!(parsed.message.data, vec![1, 2, 3]);
+        assert_eq!(parsed.direction, None);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let lines = [
+            "",
+            "(1.0) can0",
+            "1.0 can0 123#00",
+            "(1.0 can0 123#00",
+            "(1.x) can0 123#00",
+            "(1.0123456789) can0 123#00",
+            "(+1.0) can0 123#00",
+            "(1.0) can0 123",
+            "(1.0) can0 12#00",
+            "(1.0) can0 FFF#00",
+            "(1.0) can0 +12#00",
+            "(1.0) can0 123#0",
+            "(1.0) can0 123#0G",
+            "(1.0) can0 123#001122334455667788",
+            "(1.0) can0 123##",
+            "(1.0) can0 123#R9",
+            "(1.0) can0 123#R+1",
+            // Non-ASCII characters must not split a slice
+            "(1.0) can0 123#0\u{e9}",
+            "(1.0) can0 1\u{e9}#00",
+            "(1.\u{e9}) can0 123#00",
+            "(1.0) can0 123##\u{e9}00",
+            "(1.0) can0 123#\u{e9}",
+        ];
+        for line in lines.iter() {
+            match LogEntry::parse(line) {
+                Err(Error::InvalidFrame) => (),
+                res => panic!("{:?} parsed as {:?}", line, res.map(|entry| entry.message)),
+            }
+        }
+    }
+}
@@ -1,13 +1,18 @@
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::time;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use std::io::{Write, Read};
 use crate::{
-    protocols::can::{CanInterface, Message},
+    protocols::can::{CanInterface, Message, Filter, FrameFormat, Clock, Timestamp},
     error::{Error, Result},
 };
 
 
+/// TxFlags / RxStatus bit for 29-bit identifiers
+const CAN_29BIT_ID: u32 = 0x0000_0100;
+
+
 impl From<j2534::Error> for Error {
     fn from(err: j2534::Error) -> Self {
         Error::J2534(err)
@@ -17,12 +22,14 @@ impl From<j2534::Error> for Error {
 
 pub struct J2534Can {
     channel: j2534::Channel,
+    /// Ids of the active message filters
+    filters: RefCell<Vec<u32>>,
 }
 
 impl J2534Can {
     /// Creates a new device from a J2534 channel. The channel must be a CAN channel.
     pub fn new(channel: j2534::Channel) -> Result<J2534Can> {
-        Ok(J2534Can { channel })
+        Ok(J2534Can { channel, filters: RefCell::new(Vec::new()) })
     }
 
     /// Creates a CAN channel from a device with the specified baudrate
@@ -32,40 +39,62 @@ impl J2534Can {
 
     /// Applies a filter that wil allow all messages through
     pub fn apply_blank_filter(&self) -> Result<()> {
-        let msg_mask = j2534::PassThruMsg {
+        self.set_filters(&[])
+    }
+
+    /// Starts a pass filter. Returns the filter id
+    fn start_pass_filter(&self, id: u32, mask: u32, format: FrameFormat) -> Result<u32> {
+        let tx_flags = match format {
+            FrameFormat::Standard => 0,
+            FrameFormat::Extended => CAN_29BIT_ID,
+        };
+
+        let mut msg_mask = j2534::PassThruMsg {
             protocol_id: j2534::Protocol::CAN as u32,
-            data_size: 5,
-            // data[0..5] is already set to 0
+            tx_flags,
+            data_size: 4,
             ..Default::default()
         };
+        let mut msg_pattern = msg_mask;
+        {
+            let mut writer: &mut [u8] = &mut msg_mask.data;
+            writer.write_u32::<BigEndian>(mask)?;
+        }
+        {
+            let mut writer: &mut [u8] = &mut msg_pattern.data;
+            writer.write_u32::<BigEndian>(id & mask)?;
+        }
 
-        let msg_pattern = msg_mask;
-        let _id = self.channel.start_msg_filter(j2534::FilterType::Pass, Some(&msg_mask), Some(&msg_pattern), None)?;
-        Ok(())
+        Ok(self.channel.start_msg_filter(j2534::FilterType::Pass, Some(&msg_mask), Some(&msg_pattern), None)?)
     }
 }
 
 impl CanInterface for J2534Can {
-    /// Sends a CAN message through the PassThru channel.
-    /// 
-    /// # Arguments
-    /// 
-    /// * `id` - The CAN id of the message
-    /// * `message` - The message data. Must not be larger than 8 bytes
     fn send(&self, id: u32, message: &[u8]) -> Result<()> {
-        if message.len() > 8 {
-            return Err(Error::TooMuchData);
+        self.send_msg(&Message::new(id, message))
+    }
+
+    /// Sends a CAN message through the PassThru channel.
+    /// PassThru devices cannot send remote, error or CAN FD frames.
+    fn send_msg(&self, message: &Message) -> Result<()> {
+        message.validate()?;
+        if message.remote || message.error || message.fd {
+            return Err(Error::Unsupported);
         }
         let data = {
             let mut d: [u8; 4128] = [0; 4128];
             {
                 let mut writer: &mut [u8] = &mut d;
-                writer.write_u32::<BigEndian>(id)?;
-                writer.write(message)?;
+                writer.write_u32::<BigEndian>(message.id)?;
+                writer.write(&message.data)?;
             }
             d
         };
-        let mut msg = [j2534::PassThruMsg::new_raw(j2534::Protocol::CAN, 0, 0, 0, message.len() as u32 + 4, 0, data)];
+        let tx_flags = match message.format {
+            FrameFormat::Standard => 0,
+            FrameFormat::Extended => CAN_29BIT_ID,
+        };
+        let mut msg = [j2534::PassThruMsg::new_raw(j2534::Protocol::CAN, 0, tx_flags, 0, message.data.len() as u32 + 4, 0, data)];
 
         // Use a timeout of 100ms
         let num_msgs = self.channel.write_msgs(&mut msg, 100)?;
@@ -75,6 +104,27 @@ impl CanInterface for J2534Can {
         Ok(())
     }
 
+    /// Replaces the PassThru message filters. PassThru channels block all messages
+    /// without a pass filter, so an empty slice installs a filter that passes everything.
+    fn set_filters(&self, filters: &[Filter]) -> Result<()> {
+        let mut active = self.filters.borrow_mut();
+        // Filters are only forgotten once they are stopped, so a failure can be retried
+        while let Some(&id) = active.last() {
+            self.channel.stop_msg_filter(id)?;
+            active.pop();
+        }
+
+        if filters.is_empty() {
+            // Pass both frame formats
+            active.push(self.start_pass_filter(0, 0, FrameFormat::Standard)?);
+            active.push(self.start_pass_filter(0, 0, FrameFormat::Extended)?);
+        }
+        for filter in filters {
+            active.push(self.start_pass_filter(filter.id, filter.mask, filter.format)?);
+        }
+        Ok(())
+    }
+
     /// Received a single message from the PassThru channel.
     /// If no messages are received before the timeout, returns `Error::Timeout`
     /// 
@@ -94,9 +144,19 @@ impl CanInterface for J2534Can {
             let mut buffer = vec![0; (msg.data_size - 4) as usize];
             let _amount = reader.read(&mut buffer)?;
 
+            let format = if msg.rx_status & CAN_29BIT_ID != 0 {
+                FrameFormat::Extended
+            } else {
+                FrameFormat::Standard
+            };
+
             break Ok(Message {
                 id,
                 data: buffer,
+                format,
+                // The PassThru timestamp is in microseconds
+                timestamp: Some(Timestamp::new(time::Duration::from_micros(msg.timestamp as u64), Clock::Hardware)),
+                ..Default::default()
             });
         }
     }
@@ -1,7 +1,7 @@
 use std::time;
 use std::fmt;
 use std::iter;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use itertools::Itertools;
 
 
@@ -15,10 +15,119 @@ pub mod socketcan;
 #[cfg(feature = "socketcan")]
 pub use self::socketcan::SocketCan;
 
-#[derive(Debug)]
+#[cfg(feature = "slcan")]
+pub mod slcan;
+#[cfg(feature = "slcan")]
+pub use self::slcan::SlcanCan;
+
+pub mod virtualcan;
+pub use self::virtualcan::{VirtualCan, VirtualCanBus};
+
+pub mod candump;
+pub use self::candump::{CandumpRecorder, CandumpReplay};
+
+pub mod tcpcan;
+pub use self::tcpcan::{TcpCan, TcpCanServer};
+
+/// Largest standard (11-bit) identifier
+pub const CAN_SFF_MAX: u32 = 0x7FF;
+/// Largest extended (29-bit) identifier
+pub const CAN_EFF_MAX: u32 = 0x1FFF_FFFF;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameFormat {
+    /// 11-bit identifier
+    Standard,
+    /// 29-bit identifier
+    Extended,
+}
+
+impl FrameFormat {
+    /// Returns the largest identifier of the format
+    pub fn max_id(self) -> u32 {
+        match self {
+            FrameFormat::Standard => CAN_SFF_MAX,
+            FrameFormat::Extended => CAN_EFF_MAX,
+        }
+    }
+}
+
+/// Clock a receive timestamp was taken from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Clock {
+    /// The system clock. Time since the UNIX epoch
+    System,
+    /// The clock of the CAN controller or adapter. The epoch is device specific:
+    /// adapter startup for J2534 and a counter that wraps every 60 seconds for SLCAN
+    Hardware,
+    /// A monotonic clock. Time since the `VirtualCanBus` was created
+    Monotonic,
+}
+
+/// Receive time of a message
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timestamp {
+    pub time: time::Duration,
+    pub clock: Clock,
+}
+
+impl Timestamp {
+    pub fn new(time: time::Duration, clock: Clock) -> Timestamp {
+        Timestamp {
+            time,
+            clock,
+        }
+    }
+}
+
+/// An acceptance filter. Matches messages of the same frame format where
+/// `message.id & mask == id & mask`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Filter {
+    pub id: u32,
+    pub mask: u32,
+    pub format: FrameFormat,
+}
+
+impl Filter {
+    pub fn new(id: u32, mask: u32, format: FrameFormat) -> Filter {
+        Filter {
+            id,
+            mask,
+            format,
+        }
+    }
+
+    /// Creates a filter that only matches `id`
+    pub fn exact(id: u32, format: FrameFormat) -> Filter {
+        Filter::new(id, format.max_id(), format)
+    }
+
+    /// Returns true if the message passes the filter
+    pub fn matches(&self, message: &Message) -> bool {
+        message.format == self.format && message.id & self.mask == self.id & self.mask
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Message {
     pub id: u32,
     pub data: Vec<u8>,
+    pub format: FrameFormat,
+    /// Remote transmission request. `data` is not transmitted; its length is the requested length
+    pub remote: bool,
+    /// Error frame. `id` holds the error class
+    pub error: bool,
+    /// CAN FD frame. May hold up to 64 bytes
+    pub fd: bool,
+    /// CAN FD bit rate switch
+    pub brs: bool,
+    /// CAN FD error state indicator
+    pub esi: bool,
+    /// Time the message was received. SocketCan prefers hardware timestamps from the
+    /// controller's clock and falls back to the system clock; see `Timestamp::clock`.
+    /// `None` for messages that have not been received.
+    pub timestamp: Option<Timestamp>,
 }
 
 impl Default for Message {
@@ -26,10 +135,91 @@ impl Default for Message {
         Message {
             id: 0,
             data: Vec::new(),
+            format: FrameFormat::Standard,
+            remote: false,
+            error: false,
+            fd: false,
+            brs: false,
+            esi: false,
+            timestamp: None,
         }
     }
 }
 
+impl Message {
+    /// Creates a data frame with a standard identifier
+    pub fn new(id: u32, data: &[u8]) -> Message {
+        Message::with_format(id, FrameFormat::Standard, data)
+    }
+
+    /// Creates a data frame with an extended identifier
+    pub fn extended(id: u32, data: &[u8]) -> Message {
+        Message::with_format(id, FrameFormat::Extended, data)
+    }
+
+    /// Creates a data frame with the frame format `format`
+    pub fn with_format(id: u32, format: FrameFormat, data: &[u8]) -> Message {
+        Message {
+            id,
+            data: data.to_vec(),
+            format,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a CAN FD data frame
+    pub fn fd(id: u32, format: FrameFormat, data: &[u8], brs: bool) -> Message {
+        Message {
+            fd: true,
+            brs,
+            ..Message::with_format(id, format, data)
+        }
+    }
+
+    /// Returns true if the identifier uses the extended format
+    pub fn is_extended(&self) -> bool {
+        self.format == FrameFormat::Extended
+    }
+
+    /// Returns `Error::InvalidId` if the identifier does not fit the frame format
+    /// and `Error::TooMuchData` if the data is longer than 8 bytes (64 for CAN FD)
+    pub fn validate(&self) -> Result<()> {
+        if self.id > self.format.max_id() {
+            return Err(Error::InvalidId);
+        }
+        if self.fd && self.remote {
+            // CAN FD has no remote frames
+            return Err(Error::Unsupported);
+        }
+        if self.data.len() > if self.fd { 64 } else { 8 } {
+            return Err(Error::TooMuchData);
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if the message passes any of the acceptance filters.
+/// An empty filter list accepts every message.
+pub fn filters_match(filters: &[Filter], message: &Message) -> bool {
+    filters.is_empty() || filters.iter().any(|filter| filter.matches(message))
+}
+
+/// Returns the smallest CAN FD data length that can hold `len` bytes,
+/// or `None` if `len` is larger than 64
+pub fn fd_frame_len(len: usize) -> Option<usize> {
+    match len {
+        0..=8 => Some(len),
+        9..=12 => Some(12),
+        13..=16 => Some(16),
+        17..=20 => Some(20),
+        21..=24 => Some(24),
+        25..=32 => Some(32),
+        33..=48 => Some(48),
+        49..=64 => Some(64),
+        _ => None,
+    }
+}
+
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "[{:X}] {}", self.id, self.data.iter()
@@ -49,11 +239,19 @@ pub trait CanInterface: CanInterfaceIterator {
     /// 
     /// # Arguments
     /// 
-    /// * `id` - The CAN id of the message
+    /// * `id` - The standard (11-bit) CAN id of the message
     /// * `message` - The message data. Must not be larger than 8 bytes
+    ///
+    /// Use `send_msg` for extended identifiers and CAN FD frames.
     fn send(&self, id: u32, message: &[u8]) -> Result<()>;
 
+    /// Sends a CAN message with an explicit frame format and flags.
+    /// Interfaces that don't override this only support standard data frames
+    /// and return `Error::Unsupported` for anything else.
     fn send_msg(&self, message: &Message) -> Result<()> {
+        if message.is_extended() || message.remote || message.error || message.fd {
+            return Err(Error::Unsupported);
+        }
         self.send(message.id, &message.data)
     }
 
@@ -64,6 +262,24 @@ pub trait CanInterface: CanInterfaceIterator {
     /// 
     /// * `timeout` - The time to wait for a message before returning
     fn recv(&self, timeout: time::Duration) -> Result<Message>;
+
+    /// Restricts received messages to those matching the acceptance filters.
+    /// See `filters_match` for the filter semantics. An empty slice removes all filters.
+    /// The filters apply to every user of the interface.
+    /// Returns `Error::Unsupported` if the interface cannot filter messages.
+    fn set_filters(&self, _filters: &[Filter]) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+
+    /// Receives a single message without blocking.
+    /// Returns `None` if no messages are waiting.
+    fn try_recv(&self) -> Result<Option<Message>> {
+        match self.recv(time::Duration::from_secs(0)) {
+            Ok(message) => Ok(Some(message)),
+            Err(Error::Timeout) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 }
 
 impl<S: Sized + CanInterface> CanInterfaceIterator for S {
@@ -86,4 +302,62 @@ impl<'a> iter::Iterator for RecvIterator<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         Some(self.interface.recv(self.timeout))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_match_the_frame_format() {
+        let standard = Message::new(0x7E8, &[1]);
+        let extended = Message::extended(0x7E8, &[1]);
+
+        let filter = Filter::exact(0x7E8, FrameFormat::Extended);
+        assert!(filter.matches(&extended));
+        assert!(!filter.matches(&standard));
+        assert!(Filter::exact(0x7E8, FrameFormat::Standard).matches(&standard));
+        assert!(!Filter::exact(0x7E0, FrameFormat::Standard).matches(&standard));
+
+        let masked = Filter::new(0x18DA_F100, 0x1FFF_FF00, FrameFormat::Extended);
+        assert!(masked.matches(&Message::extended(0x18DA_F110, &[])));
+        assert!(!masked.matches(&Message::extended(0x18DB_F110, &[])));
+
+        assert!(filters_match(&[], &standard));
+        assert!(filters_match(&[filter, Filter::exact(0x7E8, FrameFormat::Standard)], &standard));
+    }
+
+    #[test]
+    fn message_format_is_explicit() {
+        // Extended identifiers are not inferred from the value
+        assert!(!Message::new(0x18DA_10F1, &[]).is_extended());
+        assert!(Message::new(0x18DA_10F1, &[]).validate().is_err());
+        assert!(Message::extended(0x7E0, &[]).validate().is_ok());
+        assert!(Message::fd(0x7E0, FrameFormat::Extended, &[0; 12], true).is_extended());
+    }
+
+    struct SendOnly {
+        sent: std::cell::RefCell<Vec<u32>>,
+    }
+
+    impl CanInterface for SendOnly {
+        fn send(&self, id: u32, _message: &[u8]) -> Result<()> {
+            self.sent.borrow_mut().push(id);
+            Ok(())
+        }
+
+        fn recv(&self, _timeout: time::Duration) -> Result<Message> {
+            Err(Error::Timeout)
+        }
+    }
+
+    #[test]
+    fn send_msg_defaults_to_send_for_standard_frames() {
+        let interface = SendOnly { sent: std::cell::RefCell::new(Vec::new()) };
+        interface.send_msg(&Message::new(0x7E0, &[1, 2])).unwrap();
+        match interface.send_msg(&Message::extended(0x7E0, &[1, 2])) {
+            Err(Error::Unsupported) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(*interface.sent.borrow(), vec![0x7E0]);
+    }
+}
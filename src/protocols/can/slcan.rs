@@ -0,0 +1,363 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time;
+use std::path::Path;
+
+use super::{CanInterface, Message, Filter, FrameFormat, Clock, Timestamp, filters_match};
+use crate::{
+    protocols::serial::{SerialInterface, SerialPort},
+    error::{Error, Result},
+};
+
+
+/// Bitrates selected by the `Sn` command
+const BITRATES: [u32; 9] = [10_000, 20_000, 50_000, 100_000, 125_000, 250_000, 500_000, 800_000, 1_000_000];
+
+/// Time to wait for the adapter to acknowledge a command
+const COMMAND_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+
+const BELL: u8 = 0x07;
+
+/// A CAN adapter using the SLCAN (Lawicel) ASCII protocol over a serial device
+pub struct SlcanCan {
+    port: Box<SerialInterface>,
+    /// Received bytes that have not been parsed
+    buffer: RefCell<Vec<u8>>,
+    /// Frames received while waiting for a send acknowledgement
+    received: RefCell<VecDeque<Message>>,
+    filters: RefCell<Vec<Filter>>,
+}
+
+impl SlcanCan {
+    /// Opens the serial device at `path` and opens the CAN channel at `bitrate`.
+    /// Supported bitrates are 10k, 20k, 50k, 100k, 125k, 250k, 500k, 800k and 1M.
+    pub fn open<P: AsRef<Path>>(path: P, bitrate: u32) -> Result<SlcanCan> {
+        // USB adapters ignore the serial speed
+        SlcanCan::new(Box::new(SerialPort::open(path, 115_200)?), bitrate)
+    }
+
+    /// Opens the CAN channel at `bitrate` on an adapter connected through `port`
+    pub fn new(port: Box<SerialInterface>, bitrate: u32) -> Result<SlcanCan> {
+        let bitrate_index = BITRATES.iter().position(|&b| b == bitrate).ok_or(Error::Unsupported)?;
+
+        let slcan = SlcanCan {
+            port,
+            buffer: RefCell::new(Vec::new()),
+            received: RefCell::new(VecDeque::new()),
+            filters: RefCell::new(Vec::new()),
+        };
+
+        // The channel may have been left open
+        let _ = slcan.command(b"C");
+        slcan.command(format!("S{}", bitrate_index).as_bytes())?;
+        // Timestamps are optional; some adapters reject the command
+        let _ = slcan.command(b"Z1");
+        slcan.command(b"O")?;
+        Ok(slcan)
+    }
+
+    /// Reads available bytes into the buffer, waiting up to `timeout`.
+    /// Returns false if nothing was read.
+    fn fill_buffer(&self, timeout: time::Duration) -> Result<bool> {
+        let data = self.port.read(timeout)?;
+        self.buffer.borrow_mut().extend_from_slice(&data);
+        Ok(!data.is_empty())
+    }
+
+    /// Removes the next response from the buffer. Responses end with a carriage
+    /// return or a bell on error. The terminator is included.
+    fn next_response(&self) -> Option<Vec<u8>> {
+        let mut buffer = self.buffer.borrow_mut();
+        let end = buffer.iter().position(|&b| b == b'\r' || b == BELL)?;
+        Some(buffer.drain(..=end).collect())
+    }
+
+    /// Sends a command and waits for the adapter to acknowledge it.
+    /// Frames received while waiting are discarded.
+    fn command(&self, command: &[u8]) -> Result<()> {
+        let mut data = command.to_vec();
+        data.push(b'\r');
+        self.port.write_all(&data)?;
+
+        let start_time = time::Instant::now();
+        loop {
+            while let Some(response) = self.next_response() {
+                match response[..] {
+                    [b'\r'] => return Ok(()),
+                    [BELL] => return Err(Error::InvalidResponse),
+                    _ => (),
+                }
+            }
+            let remaining = COMMAND_TIMEOUT.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            self.fill_buffer(remaining)?;
+        }
+    }
+
+    /// Waits for the adapter to acknowledge a transmitted frame with `z` or `Z`, or a
+    /// bare carriage return from Lawicel adapters with Auto Poll disabled.
+    /// Frames received while waiting are kept for `recv`.
+    fn wait_send_ack(&self) -> Result<()> {
+        let start_time = time::Instant::now();
+        loop {
+            while let Some(response) = self.next_response() {
+                match response[..] {
+                    [b'z', b'\r'] | [b'Z', b'\r'] | [b'\r'] => return Ok(()),
+                    [BELL] => return Err(Error::SendFailed),
+                    // A malformed frame does not mean the send failed
+                    _ => if let Ok(Some(message)) = parse_frame(&response[..response.len() - 1]) {
+                        self.received.borrow_mut().push_back(message);
+                    },
+                }
+            }
+            let remaining = COMMAND_TIMEOUT.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            self.fill_buffer(remaining)?;
+        }
+    }
+
+    fn next_message(&self) -> Result<Option<Message>> {
+        loop {
+            let message = self.received.borrow_mut().pop_front();
+            match message {
+                Some(message) => if filters_match(&self.filters.borrow(), &message) {
+                    return Ok(Some(message));
+                },
+                None => break,
+            }
+        }
+        while let Some(response) = self.next_response() {
+            if let Some(message) = parse_frame(&response[..response.len() - 1])? {
+                if filters_match(&self.filters.borrow(), &message) {
+                    return Ok(Some(message));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for SlcanCan {
+    fn drop(&mut self) {
+        let _ = self.port.write_all(b"C\r");
+    }
+}
+
+fn parse_hex(data: &[u8]) -> Result<u32> {
+    let s = std::str::from_utf8(data).map_err(|_| Error::InvalidFrame)?;
+    u32::from_str_radix(s, 16).map_err(|_| Error::InvalidFrame)
+}
+
+/// Parses a received frame without the terminator. Returns `None` for
+/// acknowledgements and other responses.
+fn parse_frame(line: &[u8]) -> Result<Option<Message>> {
+    let (id_len, format, remote) = match line.first() {
+        Some(b't') => (3, FrameFormat::Standard, false),
+        Some(b'T') => (8, FrameFormat::Extended, false),
+        Some(b'r') => (3, FrameFormat::Standard, true),
+        Some(b'R') => (8, FrameFormat::Extended, true),
+        _ => return Ok(None),
+    };
+    if line.len() < id_len + 2 {
+        return Err(Error::InvalidFrame);
+    }
+    let id = parse_hex(&line[1..=id_len])?;
+    let len = parse_hex(&line[id_len + 1..id_len + 2])? as usize;
+    if len > 8 {
+        return Err(Error::InvalidFrame);
+    }
+
+    let mut rest = &line[id_len + 2..];
+    let data = if remote {
+        vec![0; len]
+    } else {
+        if rest.len() < len * 2 {
+            return Err(Error::InvalidFrame);
+        }
+        let data = (0..len).map(|i| parse_hex(&rest[i * 2..i * 2 + 2]).map(|b| b as u8)).collect::<Result<Vec<u8>>>()?;
+        rest = &rest[len * 2..];
+        data
+    };
+
+    // Optional 16-bit millisecond timestamp
+    let timestamp = if rest.len() == 4 {
+        Some(Timestamp::new(time::Duration::from_millis(parse_hex(rest)? as u64), Clock::Hardware))
+    } else {
+        None
+    };
+
+    Ok(Some(Message {
+        id,
+        data,
+        format,
+        remote,
+        timestamp,
+        ..Default::default()
+    }))
+}
+
+/// Encodes a frame command without the terminator
+fn encode_frame(message: &Message) -> Vec<u8> {
+    let command = match (message.format, message.remote) {
+        (FrameFormat::Standard, false) => format!("t{:03X}", message.id),
+        (FrameFormat::Extended, false) => format!("T{:08X}", message.id),
+        (FrameFormat::Standard, true) => format!("r{:03X}", message.id),
+        (FrameFormat::Extended, true) => format!("R{:08X}", message.id),
+    };
+    let mut command = command.into_bytes();
+    command.extend_from_slice(format!("{:X}", message.data.len()).as_bytes());
+    if !message.remote {
+        for byte in &message.data {
+            command.extend_from_slice(format!("{:02X}", byte).as_bytes());
+        }
+    }
+    command
+}
+
+impl CanInterface for SlcanCan {
+    fn send(&self, id: u32, message: &[u8]) -> Result<()> {
+        self.send_msg(&Message::new(id, message))
+    }
+
+    /// Sends a CAN message through the adapter and waits for the acknowledgement.
+    /// Returns `Error::SendFailed` if the adapter rejects the frame.
+    /// SLCAN adapters cannot send error or CAN FD frames.
+    fn send_msg(&self, message: &Message) -> Result<()> {
+        message.validate()?;
+        if message.error || message.fd {
+            return Err(Error::Unsupported);
+        }
+        let mut command = encode_frame(message);
+        command.push(b'\r');
+        self.port.write_all(&command)?;
+        self.wait_send_ack()
+    }
+
+    fn recv(&self, timeout: time::Duration) -> Result<Message> {
+        let start_time = time::Instant::now();
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(message);
+            }
+            let remaining = timeout.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            if !self.fill_buffer(remaining)? && start_time.elapsed() >= timeout {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> Result<()> {
+        *self.filters.borrow_mut() = filters.to_vec();
+        Ok(())
+    }
+
+    fn try_recv(&self) -> Result<Option<Message>> {
+        if let Some(message) = self.next_message()? {
+            return Ok(Some(message));
+        }
+        self.fill_buffer(time::Duration::from_secs(0))?;
+        self.next_message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+
+    /// Opens a pseudo-terminal and returns the master and the path of the slave
+    fn open_pty() -> (File, String) {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0);
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+            let mut name = [0 as libc::c_char; 64];
+            assert_eq!(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+            let path = CStr::from_ptr(name.as_ptr()).to_str().unwrap().to_string();
+            (File::from_raw_fd(fd), path)
+        }
+    }
+
+    /// Runs a fake adapter on the master side of a pty. `respond` returns the
+    /// reply to each command. Returns the commands received once the slave closes.
+    fn fake_adapter<F>(mut master: File, mut respond: F) -> thread::JoinHandle<Vec<Vec<u8>>>
+    where
+        F: FnMut(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        thread::spawn(move || {
+            let mut commands = Vec::new();
+            let mut line = Vec::new();
+            let mut byte = [0; 1];
+            // Reading fails once the slave is closed
+            while let Ok(1) = master.read(&mut byte) {
+                if byte[0] != b'\r' {
+                    line.push(byte[0]);
+                    continue;
+                }
+                let reply = respond(&line);
+                master.write_all(&reply).unwrap();
+                commands.push(line.split_off(0));
+                if commands.last().map_or(false, |c| c == b"C") && commands.len() > 1 {
+                    break;
+                }
+            }
+            commands
+        })
+    }
+
+    fn respond(command: &[u8]) -> Vec<u8> {
+        match command {
+            b"t1231AA" => vec![BELL],
+            // A frame arrives before the acknowledgement
+            b"t1231BB" => b"t4562CAFE\rz\r".to_vec(),
+            b"T000007E8100" => b"Z\r".to_vec(),
+            _ => b"\r".to_vec(),
+        }
+    }
+
+    #[test]
+    fn send_reports_adapter_errors_over_a_pty() {
+        let (master, path) = open_pty();
+        let adapter = fake_adapter(master, respond);
+
+        let slcan = SlcanCan::new(Box::new(SerialPort::open(&path, 115_200).unwrap()), 500_000).unwrap();
+        match slcan.send_msg(&Message::new(0x123, &[0xAA])) {
+            Err(Error::SendFailed) => (),
+            other => panic!("expected SendFailed, got {:?}", other.err()),
+        }
+        slcan.send_msg(&Message::new(0x123, &[0xBB])).unwrap();
+        slcan.send_msg(&Message::extended(0x7E8, &[0x00])).unwrap();
+
+        let message = slcan.recv(time::Duration::from_millis(100)).unwrap();
+        assert_eq!(message.id, 0x456);
+        assert_eq!(message.format, FrameFormat::Standard);
+        assert_eq!(message.data, vec![0xCA, 0xFE]);
+        drop(slcan);
+
+        let commands = adapter.join().unwrap();
+        let commands: Vec<&[u8]> = commands.iter().map(|c| &c[..]).collect();
+        assert_eq!(commands, vec![
+            &b"C"[..], b"S6", b"Z1", b"O", b"t1231AA", b"t1231BB", b"T000007E8100", b"C",
+        ]);
+    }
+
+    #[test]
+    fn bare_carriage_return_acknowledges_sends() {
+        let (master, path) = open_pty();
+        // Adapters with Auto Poll disabled answer every command with a carriage return
+        let adapter = fake_adapter(master, |_| b"\r".to_vec());
+
+        let slcan = SlcanCan::new(Box::new(SerialPort::open(&path, 115_200).unwrap()), 500_000).unwrap();
+        let start = time::Instant::now();
+        slcan.send_msg(&Message::new(0x7E0, &[0x02, 0x10, 0x85])).unwrap();
+        slcan.send_msg(&Message::new(0x7E0, &[0x02, 0x3E, 0x00])).unwrap();
+        assert!(start.elapsed() < COMMAND_TIMEOUT);
+        drop(slcan);
+
+        let commands = adapter.join().unwrap();
+        assert_eq!(&commands[4..], &[b"t7E03021085".to_vec(), b"t7E03023E00".to_vec(), b"C".to_vec()]);
+    }
+}
@@ -1,32 +1,77 @@
 use std::io;
+use std::cmp;
 use std::mem;
+use std::ptr;
 use std::time;
 use std::ffi;
+use std::fs;
 
-use super::{CanInterface, Message};
+use byteorder::{ByteOrder, NativeEndian};
+
+use super::{CanInterface, Message, Filter, FrameFormat, Clock, Timestamp};
 use crate::error::{Error, Result};
 
 
-const AF_CAN: libc::c_int = 29;
-const PF_CAN: libc::c_int = 29;
+pub(crate) const AF_CAN: libc::c_int = 29;
+pub(crate) const PF_CAN: libc::c_int = 29;
 const CAN_RAW: libc::c_int = 1;
-const SOL_CAN_BASE: libc::c_int = 100;
+pub(crate) const SOL_CAN_BASE: libc::c_int = 100;
 const SOL_CAN_RAW: libc::c_int = SOL_CAN_BASE + CAN_RAW;
 const CAN_RAW_FILTER: libc::c_int = 1;
 const CAN_RAW_ERR_FILTER: libc::c_int = 2;
 const CAN_RAW_LOOPBACK: libc::c_int = 3;
 const CAN_RAW_RECV_OWN_MSGS: libc::c_int = 4;
+const CAN_RAW_FD_FRAMES: libc::c_int = 5;
+
+/// Size of a classic CAN frame
+const CAN_MTU: usize = 16;
+/// Size of a CAN FD frame
+const CANFD_MTU: usize = 72;
+
+const SO_TIMESTAMPNS: libc::c_int = 35;
+const SO_TIMESTAMPING: libc::c_int = 37;
+const SCM_TIMESTAMPNS: libc::c_int = SO_TIMESTAMPNS;
+const SCM_TIMESTAMPING: libc::c_int = SO_TIMESTAMPING;
+
+const SOF_TIMESTAMPING_RX_HARDWARE: libc::c_int = 1 << 2;
+const SOF_TIMESTAMPING_RX_SOFTWARE: libc::c_int = 1 << 3;
+const SOF_TIMESTAMPING_SOFTWARE: libc::c_int = 1 << 4;
+const SOF_TIMESTAMPING_RAW_HARDWARE: libc::c_int = 1 << 6;
+
+/// Bit rate switch
+const CANFD_BRS: u8 = 0x01;
+/// Error state indicator
+const CANFD_ESI: u8 = 0x02;
+
+/// Extended frame format
+pub(crate) const CAN_EFF_FLAG: u32 = 0x8000_0000;
+/// Remote transmission request
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+/// Error frame
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+const CAN_SFF_MASK: u32 = 0x0000_07FF;
+const CAN_EFF_MASK: u32 = 0x1FFF_FFFF;
+const CAN_ERR_MASK: u32 = 0x1FFF_FFFF;
 
 #[repr(C)]
-struct CanAddr {
-    can_family: libc::c_short,
-    if_index: libc::c_int, // address familiy,
-    rx_id: u32,
-    tx_id: u32,
+struct CanFilter {
+    can_id: u32,
+    can_mask: u32,
+}
+
+#[repr(C)]
+pub(crate) struct CanAddr {
+    pub can_family: libc::c_short,
+    pub if_index: libc::c_int, // address familiy,
+    pub rx_id: u32,
+    pub tx_id: u32,
 }
 
 pub struct SocketCan {
     fd: i32,
+    if_index: libc::c_uint,
+    /// True if CAN FD frames have been enabled on the socket
+    fd_frames: bool,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -51,6 +96,35 @@ pub struct CanFrame {
     data: [u8; 8],
 }
 
+/// Builds the raw can_id with EFF/RTR/ERR flags
+fn raw_can_id(message: &Message) -> u32 {
+    let mut can_id = message.id;
+    if message.format == FrameFormat::Extended {
+        can_id |= CAN_EFF_FLAG;
+    }
+    if message.remote {
+        can_id |= CAN_RTR_FLAG;
+    }
+    if message.error {
+        can_id |= CAN_ERR_FLAG;
+    }
+    can_id
+}
+
+impl CanFrame {
+    fn from_message(message: &Message) -> Result<CanFrame> {
+        message.validate()?;
+
+        let mut frame = CanFrame::default();
+        frame.can_dlc = message.data.len() as u8;
+        frame.can_id = raw_can_id(message);
+        if !message.remote {
+            frame.data[..message.data.len()].clone_from_slice(&message.data);
+        }
+        Ok(frame)
+    }
+}
+
 impl Default for CanFrame {
     fn default() -> CanFrame {
         CanFrame {
@@ -64,6 +138,268 @@ impl Default for CanFrame {
     }
 }
 
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct CanFdFrame {
+    /// 32 bit CAN_ID + EFF/RTR/ERR flags
+    can_id: u32,
+
+    /// data length. Bytes beyond are not valid
+    len: u8,
+
+    /// CAN FD flags (BRS/ESI)
+    flags: u8,
+
+    /// reserved
+    _res0: u8,
+
+    /// reserved
+    _res1: u8,
+
+    /// buffer for data
+    data: [u8; 64],
+}
+
+impl CanFdFrame {
+    fn from_message(message: &Message) -> Result<CanFdFrame> {
+        message.validate()?;
+
+        let mut frame = CanFdFrame::default();
+        frame.len = message.data.len() as u8;
+        frame.can_id = raw_can_id(message);
+        if message.brs {
+            frame.flags |= CANFD_BRS;
+        }
+        if message.esi {
+            frame.flags |= CANFD_ESI;
+        }
+        frame.data[..message.data.len()].clone_from_slice(&message.data);
+        Ok(frame)
+    }
+
+    /// Converts the frame to a message. `fd` should be true if
+    /// the frame was received as a CAN FD frame.
+    fn to_message(&self, fd: bool) -> Message {
+        let len = cmp::min(self.len as usize, if fd { 64 } else { 8 });
+        let remote = !fd && self.can_id & CAN_RTR_FLAG != 0;
+        let error = self.can_id & CAN_ERR_FLAG != 0;
+        let (format, id) = if error {
+            (FrameFormat::Standard, self.can_id & CAN_ERR_MASK)
+        } else if self.can_id & CAN_EFF_FLAG != 0 {
+            (FrameFormat::Extended, self.can_id & CAN_EFF_MASK)
+        } else {
+            (FrameFormat::Standard, self.can_id & CAN_SFF_MASK)
+        };
+        Message {
+            id,
+            data: if remote { vec![0; len] } else { self.data[..len].to_vec() },
+            format,
+            remote,
+            error,
+            fd,
+            brs: fd && self.flags & CANFD_BRS != 0,
+            esi: fd && self.flags & CANFD_ESI != 0,
+            timestamp: None,
+        }
+    }
+}
+
+impl Default for CanFdFrame {
+    fn default() -> CanFdFrame {
+        CanFdFrame {
+            can_id: 0,
+            len: 0,
+            flags: 0,
+            _res0: 0,
+            _res1: 0,
+            data: [0; 64],
+        }
+    }
+}
+
+fn timespec_to_duration(ts: &libc::timespec) -> Option<time::Duration> {
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        return None;
+    }
+    Some(time::Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// Reads the receive timestamp from the control messages. Hardware timestamps are preferred.
+unsafe fn read_timestamp(msg: &libc::msghdr) -> Option<Timestamp> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_SOCKET {
+            let data = libc::CMSG_DATA(cmsg);
+            match (*cmsg).cmsg_type {
+                SCM_TIMESTAMPING => {
+                    // [software, legacy, hardware]
+                    let ts = ptr::read_unaligned(data as *const [libc::timespec; 3]);
+                    if let Some(time) = timespec_to_duration(&ts[2]) {
+                        return Some(Timestamp::new(time, Clock::Hardware));
+                    }
+                    return timespec_to_duration(&ts[0]).map(|time| Timestamp::new(time, Clock::System));
+                },
+                SCM_TIMESTAMPNS => {
+                    let ts = ptr::read_unaligned(data as *const libc::timespec);
+                    return timespec_to_duration(&ts).map(|time| Timestamp::new(time, Clock::System));
+                },
+                _ => (),
+            }
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    None
+}
+
+/// ARPHRD_CAN link type
+const ARPHRD_CAN: u32 = 280;
+
+const NETLINK_ROUTE: libc::c_int = 0;
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const NLM_F_REQUEST: u16 = 1;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_HDRLEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_DATA: u16 = 2;
+const IFLA_CAN_BITTIMING: u16 = 1;
+/// Mask of the attribute type without the nested/byteorder flags
+const NLA_TYPE_MASK: u16 = 0x3FFF;
+
+/// A CAN network interface
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo {
+    pub name: String,
+    /// Operational state, e.g. "up", "down" or "unknown" (virtual interfaces)
+    pub state: String,
+    /// Configured bitrate. `None` for virtual interfaces or if it could not be read
+    pub bitrate: Option<u32>,
+}
+
+/// Lists the CAN network interfaces
+pub fn list() -> io::Result<Vec<InterfaceInfo>> {
+    let mut interfaces = Vec::new();
+    for entry in fs::read_dir("/sys/class/net")? {
+        let entry = entry?;
+        let path = entry.path();
+
+        let link_type = match fs::read_to_string(path.join("type")) {
+            Ok(link_type) => link_type,
+            Err(_) => continue,
+        };
+        if link_type.trim().parse::<u32>().ok() != Some(ARPHRD_CAN) {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let state = fs::read_to_string(path.join("operstate")).map(|s| s.trim().to_string()).unwrap_or_default();
+        let bitrate = read_bitrate(&name).unwrap_or(None);
+        interfaces.push(InterfaceInfo {
+            name,
+            state,
+            bitrate,
+        });
+    }
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(interfaces)
+}
+
+/// Returns the attributes in a netlink attribute buffer as (type, payload) pairs
+fn parse_attributes(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attributes = Vec::new();
+    while data.len() >= 4 {
+        let len = NativeEndian::read_u16(&data[0..2]) as usize;
+        let attr_type = NativeEndian::read_u16(&data[2..4]) & NLA_TYPE_MASK;
+        if len < 4 || len > data.len() {
+            break;
+        }
+        attributes.push((attr_type, &data[4..len]));
+        // Attributes are aligned to 4 bytes
+        let aligned = cmp::min((len + 3) & !3, data.len());
+        data = &data[aligned..];
+    }
+    attributes
+}
+
+/// Reads the bitrate of a CAN interface through rtnetlink
+fn read_bitrate(ifname: &str) -> io::Result<Option<u32>> {
+    let c_string = ffi::CString::new(ifname).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let if_index = unsafe { libc::if_nametoindex(c_string.as_ptr()) };
+    if if_index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, NETLINK_ROUTE) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // nlmsghdr followed by ifinfomsg
+    let mut request = [0u8; NLMSG_HDRLEN + IFINFOMSG_LEN];
+    NativeEndian::write_u32(&mut request[0..4], (NLMSG_HDRLEN + IFINFOMSG_LEN) as u32);
+    NativeEndian::write_u16(&mut request[4..6], RTM_GETLINK);
+    NativeEndian::write_u16(&mut request[6..8], NLM_F_REQUEST);
+    NativeEndian::write_u32(&mut request[8..12], 1);
+    request[NLMSG_HDRLEN] = libc::AF_UNSPEC as u8;
+    NativeEndian::write_i32(&mut request[NLMSG_HDRLEN + 4..NLMSG_HDRLEN + 8], if_index as i32);
+
+    let mut response = vec![0u8; 16384];
+    let res = unsafe {
+        if libc::send(fd, request.as_ptr() as *const libc::c_void, request.len(), 0) < 0 {
+            -1
+        } else {
+            libc::recv(fd, response.as_mut_ptr() as *mut libc::c_void, response.len(), 0)
+        }
+    };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if res < 0 {
+        return Err(err);
+    }
+    Ok(parse_link_bitrate(&response[..res as usize]))
+}
+
+/// Returns the bitrate from an RTM_NEWLINK response, or `None` if the
+/// response is not a link message or has no bitrate
+fn parse_link_bitrate(response: &[u8]) -> Option<u32> {
+    if response.len() < NLMSG_HDRLEN + IFINFOMSG_LEN {
+        return None;
+    }
+    let msg_type = NativeEndian::read_u16(&response[4..6]);
+    if msg_type == NLMSG_ERROR {
+        // The kernel rejected the request, e.g. the interface is gone
+        return None;
+    }
+    if msg_type != RTM_NEWLINK {
+        return None;
+    }
+    let msg_len = cmp::min(NativeEndian::read_u32(&response[0..4]) as usize, response.len());
+    // A malformed length would not cover the headers
+    if msg_len < NLMSG_HDRLEN + IFINFOMSG_LEN {
+        return None;
+    }
+
+    for (attr_type, payload) in parse_attributes(&response[NLMSG_HDRLEN + IFINFOMSG_LEN..msg_len]) {
+        if attr_type != IFLA_LINKINFO {
+            continue;
+        }
+        for (info_type, info) in parse_attributes(payload) {
+            if info_type != IFLA_INFO_DATA {
+                continue;
+            }
+            for (can_type, can_data) in parse_attributes(info) {
+                // struct can_bittiming starts with the bitrate
+                if can_type == IFLA_CAN_BITTIMING && can_data.len() >= 4 {
+                    let bitrate = NativeEndian::read_u32(&can_data[0..4]);
+                    return if bitrate == 0 { None } else { Some(bitrate) };
+                }
+            }
+        }
+    }
+    None
+}
+
 impl SocketCan {
     /// Opens a new SocketCan device
     /// 
@@ -116,10 +452,74 @@ impl SocketCan {
             return Err(err);
         }
 
+        // Try to enable CAN FD frames. This fails on kernels without CAN FD support
+        let enable: libc::c_int = 1;
+        let fd_frames = unsafe {
+            libc::setsockopt(sock_fd, SOL_CAN_RAW, CAN_RAW_FD_FRAMES,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t)
+        } == 0;
+
+        // Request hardware timestamps with a fallback to software timestamps
+        let timestamping: libc::c_int = SOF_TIMESTAMPING_RX_HARDWARE | SOF_TIMESTAMPING_RAW_HARDWARE
+            | SOF_TIMESTAMPING_RX_SOFTWARE | SOF_TIMESTAMPING_SOFTWARE;
+        let res = unsafe {
+            libc::setsockopt(sock_fd, libc::SOL_SOCKET, SO_TIMESTAMPING,
+                &timestamping as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if res == -1 {
+            unsafe {
+                libc::setsockopt(sock_fd, libc::SOL_SOCKET, SO_TIMESTAMPNS,
+                    &enable as *const libc::c_int as *const libc::c_void,
+                    mem::size_of::<libc::c_int>() as libc::socklen_t)
+            };
+        }
+
         Ok(SocketCan {
             fd: sock_fd,
+            if_index,
+            fd_frames,
         })
     }
+
+    /// Returns the index of the network interface
+    pub fn if_index(&self) -> libc::c_uint {
+        self.if_index
+    }
+
+    /// Returns true if the socket can send and receive CAN FD frames.
+    /// The interface itself must also be configured for CAN FD.
+    pub fn fd_frames(&self) -> bool {
+        self.fd_frames
+    }
+
+    fn write_frame<T>(&self, frame: &T, size: usize) -> Result<()> {
+        let frame_ptr = frame as *const T;
+
+        let res = unsafe { libc::write(self.fd, frame_ptr as *const libc::c_void, size) };
+        if res != size as isize {
+            if res < 0 {
+                return Err(Error::Io(io::Error::last_os_error()));
+            }
+            return Err(Error::IncompleteWrite);
+        }
+        Ok(())
+    }
+
+    /// Enables or disables receiving error frames. Error frames are disabled by default.
+    pub fn receive_error_frames(&self, enable: bool) -> io::Result<()> {
+        let mask: u32 = if enable { CAN_ERR_MASK } else { 0 };
+        let res = unsafe {
+            libc::setsockopt(self.fd, SOL_CAN_RAW, CAN_RAW_ERR_FILTER,
+                &mask as *const u32 as *const libc::c_void,
+                mem::size_of::<u32>() as libc::socklen_t)
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
 }
 
 impl Drop for SocketCan {
@@ -130,39 +530,154 @@ impl Drop for SocketCan {
 
 impl CanInterface for SocketCan {
     fn send(&self, id: u32, message: &[u8]) -> Result<()> {
-        if message.len() > 8 {
-            return Err(Error::TooMuchData);
+        self.send_msg(&Message::new(id, message))
+    }
+
+    fn send_msg(&self, message: &Message) -> Result<()> {
+        if message.fd {
+            if !self.fd_frames {
+                return Err(Error::Unsupported);
+            }
+            let frame = CanFdFrame::from_message(message)?;
+            return self.write_frame(&frame, CANFD_MTU);
         }
 
-        let mut frame = CanFrame::default();
-        frame.can_dlc = message.len() as u8;
-        frame.can_id = id;
-        frame.data[..message.len()].clone_from_slice(message);
+        let frame = CanFrame::from_message(message)?;
+        self.write_frame(&frame, mem::size_of::<CanFrame>())
+    }
 
-        let frame_ptr = &frame as *const CanFrame;
+    fn recv(&self, timeout: time::Duration) -> Result<Message> {
+        let start_time = time::Instant::now();
+        loop {
+            let remaining = match timeout.checked_sub(start_time.elapsed()) {
+                Some(remaining) => remaining,
+                None => return Err(Error::Timeout),
+            };
+            // Round up so sub-millisecond timeouts still wait
+            let millis = (remaining.as_secs() * 1000 + ((remaining.subsec_nanos() + 999_999) / 1_000_000) as u64)
+                .min(libc::c_int::max_value() as u64) as libc::c_int;
 
-        let res = unsafe { libc::write(self.fd, frame_ptr as *const libc::c_void, mem::size_of::<CanFrame>()) };
-        if res != mem::size_of::<CanFrame>() as isize {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let res = unsafe { libc::poll(&mut pollfd, 1, millis) };
             if res < 0 {
-                return Err(Error::Io(io::Error::last_os_error()));
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::Io(err));
             }
-            return Err(Error::IncompleteWrite);
+            if res == 0 {
+                return Err(Error::Timeout);
+            }
+
+            if let Some(message) = self.try_recv()? {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> Result<()> {
+        let mut raw_filters: Vec<CanFilter> = filters.iter().map(|filter| {
+            // Match the frame format as well as the identifier
+            let eff = match filter.format {
+                FrameFormat::Standard => 0,
+                FrameFormat::Extended => CAN_EFF_FLAG,
+            };
+            CanFilter {
+                can_id: (filter.id & CAN_EFF_MASK) | eff,
+                can_mask: (filter.mask & CAN_EFF_MASK) | CAN_EFF_FLAG,
+            }
+        }).collect();
+        if raw_filters.is_empty() {
+            // No filters would block everything
+            raw_filters.push(CanFilter { can_id: 0, can_mask: 0 });
+        }
+
+        let res = unsafe {
+            libc::setsockopt(self.fd, SOL_CAN_RAW, CAN_RAW_FILTER,
+                raw_filters.as_ptr() as *const libc::c_void,
+                (mem::size_of::<CanFilter>() * raw_filters.len()) as libc::socklen_t)
+        };
+        if res == -1 {
+            return Err(Error::Io(io::Error::last_os_error()));
         }
         Ok(())
     }
 
-    // FIXME: implement timeout
-    fn recv(&self, timeout: time::Duration) -> Result<Message> {
-        let mut frame = CanFrame::default();
-        let frame_ptr = &mut frame as *mut CanFrame;
-        
-        let res = unsafe { libc::recv(self.fd, frame_ptr as *mut libc::c_void, mem::size_of::<CanFrame>(), 0) };
+    fn try_recv(&self) -> Result<Option<Message>> {
+        // A CAN FD frame shares the layout of a classic frame
+        let mut frame = CanFdFrame::default();
+        let frame_ptr = &mut frame as *mut CanFdFrame;
+
+        let mut iov = libc::iovec {
+            iov_base: frame_ptr as *mut libc::c_void,
+            iov_len: CANFD_MTU,
+        };
+        // u64 for cmsghdr alignment
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let res = unsafe { libc::recvmsg(self.fd, &mut msg, libc::MSG_DONTWAIT) };
         if res < 0 {
-            return Err(Error::Io(io::Error::last_os_error()));
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(Error::Io(err));
         }
-        Ok(Message {
-            id: frame.can_id,
-            data: frame.data[..(frame.can_dlc as usize)].to_vec(),
-        })
+        let mut message = match res as usize {
+            CAN_MTU => frame.to_message(false),
+            CANFD_MTU => frame.to_message(true),
+            _ => return Err(Error::Read),
+        };
+        message.timestamp = unsafe { read_timestamp(&msg) };
+        Ok(Some(message))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an RTM_NEWLINK message with `nlmsg_len` and a bitrate attribute
+    fn newlink(nlmsg_len: u32, bitrate: u32) -> Vec<u8> {
+        let mut message = vec![0u8; NLMSG_HDRLEN + IFINFOMSG_LEN];
+        NativeEndian::write_u32(&mut message[0..4], nlmsg_len);
+        NativeEndian::write_u16(&mut message[4..6], RTM_NEWLINK);
+        // IFLA_LINKINFO { IFLA_INFO_DATA { IFLA_CAN_BITTIMING { bitrate } } }
+        for &(len, attr_type) in &[(16u16, IFLA_LINKINFO), (12, IFLA_INFO_DATA), (8, IFLA_CAN_BITTIMING)] {
+            let mut header = [0u8; 4];
+            NativeEndian::write_u16(&mut header[0..2], len);
+            NativeEndian::write_u16(&mut header[2..4], attr_type);
+            message.extend_from_slice(&header);
+        }
+        let mut payload = [0u8; 4];
+        NativeEndian::write_u32(&mut payload, bitrate);
+        message.extend_from_slice(&payload);
+        message
+    }
+
+    #[test]
+    fn parses_the_link_bitrate() {
+        assert_eq!(parse_link_bitrate(&newlink(48, 500_000)), Some(500_000));
+        assert_eq!(parse_link_bitrate(&newlink(48, 0)), None);
+        let mut error = newlink(48, 500_000);
+        NativeEndian::write_u16(&mut error[4..6], NLMSG_ERROR);
+        assert_eq!(parse_link_bitrate(&error), None);
+    }
+
+    #[test]
+    fn short_netlink_lengths_are_ignored() {
+        assert_eq!(parse_link_bitrate(&newlink(0, 500_000)), None);
+        assert_eq!(parse_link_bitrate(&newlink(31, 500_000)), None);
+        assert_eq!(parse_link_bitrate(&newlink(48, 500_000)[..20]), None);
+    }
+}
@@ -0,0 +1,346 @@
+//! Tunnels CAN frames over TCP.
+//!
+//! Each frame is sent as a record with big-endian fields:
+//!
+//! | Size | Field                                                          |
+//! | ---- | -------------------------------------------------------------- |
+//! | 4    | CAN id with flags: bit 31 extended, bit 30 remote, bit 29 error |
+//! | 1    | Flags: bit 0 CAN FD, bit 1 BRS, bit 2 ESI, bit 3 timestamp valid, bits 4-5 timestamp clock (0 system, 1 hardware, 2 monotonic) |
+//! | 1    | Data length (0-64)                                             |
+//! | 8    | Timestamp in microseconds                                      |
+//! | n    | Data                                                           |
+//!
+//! For remote frames the data length is the requested length and no data follows.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{CanInterface, Message, Filter, FrameFormat, Clock, Timestamp, filters_match, CAN_EFF_MAX};
+use crate::error::{Error, Result};
+
+
+const EFF_FLAG: u32 = 0x8000_0000;
+const RTR_FLAG: u32 = 0x4000_0000;
+const ERR_FLAG: u32 = 0x2000_0000;
+
+const FLAG_FD: u8 = 0x01;
+const FLAG_BRS: u8 = 0x02;
+const FLAG_ESI: u8 = 0x04;
+const FLAG_TIMESTAMP: u8 = 0x08;
+const CLOCK_SHIFT: u8 = 4;
+const CLOCK_MASK: u8 = 0x30;
+
+const HEADER_LEN: usize = 14;
+
+/// Time the server waits on each side before checking the other
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(1);
+
+fn encode(message: &Message) -> Vec<u8> {
+    let mut id = message.id;
+    if message.is_extended() {
+        id |= EFF_FLAG;
+    }
+    if message.remote {
+        id |= RTR_FLAG;
+    }
+    if message.error {
+        id |= ERR_FLAG;
+    }
+
+    let mut flags = 0;
+    if message.fd {
+        flags |= FLAG_FD;
+    }
+    if message.brs {
+        flags |= FLAG_BRS;
+    }
+    if message.esi {
+        flags |= FLAG_ESI;
+    }
+    let timestamp = match message.timestamp {
+        Some(timestamp) => {
+            let clock: u8 = match timestamp.clock {
+                Clock::System => 0,
+                Clock::Hardware => 1,
+                Clock::Monotonic => 2,
+            };
+            flags |= FLAG_TIMESTAMP | (clock << CLOCK_SHIFT);
+            timestamp.time.as_secs() * 1_000_000 + timestamp.time.subsec_micros() as u64
+        },
+        None => 0,
+    };
+
+    let mut record = vec![0; HEADER_LEN];
+    BigEndian::write_u32(&mut record[0..4], id);
+    record[4] = flags;
+    record[5] = message.data.len() as u8;
+    BigEndian::write_u64(&mut record[6..14], timestamp);
+    if !message.remote {
+        record.extend_from_slice(&message.data);
+    }
+    record
+}
+
+/// Decodes a record from the start of `buffer`. Returns the message and the
+/// record length, or `None` if the record is incomplete.
+fn decode(buffer: &[u8]) -> Result<Option<(Message, usize)>> {
+    if buffer.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let id = BigEndian::read_u32(&buffer[0..4]);
+    let flags = buffer[4];
+    let len = buffer[5] as usize;
+    let timestamp = BigEndian::read_u64(&buffer[6..14]);
+    if len > 64 {
+        return Err(Error::InvalidFrame);
+    }
+
+    let remote = id & RTR_FLAG != 0;
+    let record_len = if remote { HEADER_LEN } else { HEADER_LEN + len };
+    if buffer.len() < record_len {
+        return Ok(None);
+    }
+
+    let clock = match (flags & CLOCK_MASK) >> CLOCK_SHIFT {
+        0 => Clock::System,
+        1 => Clock::Hardware,
+        2 => Clock::Monotonic,
+        _ => return Err(Error::InvalidFrame),
+    };
+
+    let message = Message {
+        id: id & CAN_EFF_MAX,
+        data: if remote { vec![0; len] } else { buffer[HEADER_LEN..record_len].to_vec() },
+        format: if id & EFF_FLAG != 0 { FrameFormat::Extended } else { FrameFormat::Standard },
+        remote,
+        error: id & ERR_FLAG != 0,
+        fd: flags & FLAG_FD != 0,
+        brs: flags & FLAG_BRS != 0,
+        esi: flags & FLAG_ESI != 0,
+        timestamp: if flags & FLAG_TIMESTAMP != 0 { Some(Timestamp::new(time::Duration::from_micros(timestamp), clock)) } else { None },
+    };
+    Ok(Some((message, record_len)))
+}
+
+/// Returns true if the error is from a read timing out
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// A CAN interface on a remote machine, connected through a `TcpCanServer`
+pub struct TcpCan {
+    stream: TcpStream,
+    /// Received bytes that have not been decoded
+    buffer: RefCell<Vec<u8>>,
+    filters: RefCell<Vec<Filter>>,
+}
+
+impl TcpCan {
+    /// Connects to a server
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<TcpCan> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpCan {
+            stream,
+            buffer: RefCell::new(Vec::new()),
+            filters: RefCell::new(Vec::new()),
+        })
+    }
+
+    fn next_message(&self) -> Result<Option<Message>> {
+        let mut buffer = self.buffer.borrow_mut();
+        while let Some((message, len)) = decode(&buffer)? {
+            buffer.drain(..len);
+            if filters_match(&self.filters.borrow(), &message) {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads available data into the buffer, waiting up to `timeout`
+    fn fill_buffer(&self, timeout: time::Duration) -> Result<()> {
+        // A zero timeout is not allowed
+        self.stream.set_read_timeout(Some(std::cmp::max(timeout, time::Duration::from_micros(1))))?;
+        let mut data = [0; 1024];
+        match (&self.stream).read(&mut data) {
+            Ok(0) => Err(Error::InvalidConnection),
+            Ok(len) => {
+                self.buffer.borrow_mut().extend_from_slice(&data[..len]);
+                Ok(())
+            },
+            Err(ref err) if is_timeout(err) => Ok(()),
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+}
+
+impl CanInterface for TcpCan {
+    fn send(&self, id: u32, message: &[u8]) -> Result<()> {
+        self.send_msg(&Message::new(id, message))
+    }
+
+    fn send_msg(&self, message: &Message) -> Result<()> {
+        message.validate()?;
+        (&self.stream).write_all(&encode(message))?;
+        Ok(())
+    }
+
+    fn recv(&self, timeout: time::Duration) -> Result<Message> {
+        let start_time = time::Instant::now();
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(message);
+            }
+            let remaining = timeout.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            self.fill_buffer(remaining)?;
+        }
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> Result<()> {
+        *self.filters.borrow_mut() = filters.to_vec();
+        Ok(())
+    }
+
+    fn try_recv(&self) -> Result<Option<Message>> {
+        if let Some(message) = self.next_message()? {
+            return Ok(Some(message));
+        }
+        self.fill_buffer(time::Duration::from_secs(0))?;
+        self.next_message()
+    }
+}
+
+/// Exposes a local CAN interface to `TcpCan` clients. One client is served at a time.
+pub struct TcpCanServer {
+    listener: TcpListener,
+}
+
+impl TcpCanServer {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> Result<TcpCanServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpCanServer {listener})
+    }
+
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts clients and forwards frames between them and `can` until `running` is cleared
+    pub fn serve(&self, can: &CanInterface, running: &AtomicBool) -> Result<()> {
+        while running.load(Ordering::Relaxed) {
+            match self.listener.accept() {
+                // A misbehaving client is disconnected without stopping the server
+                Ok((stream, _)) => self.serve_client(stream, can, running)?,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL * 10),
+                Err(err) => return Err(Error::Io(err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Forwards frames for one client until it disconnects or sends an invalid
+    /// record. Only bus errors are returned.
+    fn serve_client(&self, mut stream: TcpStream, can: &CanInterface, running: &AtomicBool) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let mut buffer = Vec::new();
+        let mut data = [0; 1024];
+        while running.load(Ordering::Relaxed) {
+            // Client to bus
+            match stream.read(&mut data) {
+                // The client disconnected
+                Ok(0) => return Ok(()),
+                Ok(len) => buffer.extend_from_slice(&data[..len]),
+                Err(ref err) if is_timeout(err) => (),
+                Err(_) => return Ok(()),
+            }
+            loop {
+                let (message, len) = match decode(&buffer) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    // Drop the client
+                    Err(_) => return Ok(()),
+                };
+                buffer.drain(..len);
+                // Bus errors are not reported to the client
+                let _ = can.send_msg(&message);
+            }
+
+            // Bus to client
+            loop {
+                match can.recv(POLL_INTERVAL) {
+                    Ok(message) => {
+                        if stream.write_all(&encode(&message)).is_err() {
+                            return Ok(());
+                        }
+                    },
+                    Err(Error::Timeout) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::can::VirtualCanBus;
+    use std::sync::Arc;
+
+    #[test]
+    fn round_trip_over_localhost() {
+        let bus = VirtualCanBus::new();
+        let node = bus.open();
+        let server_can = bus.open();
+        let server = TcpCanServer::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_thread = {
+            let running = running.clone();
+            thread::spawn(move || server.serve(&server_can, &running))
+        };
+
+        // A client sending an invalid record is disconnected
+        let mut bad_client = TcpStream::connect(address).unwrap();
+        let mut record = encode(&Message::new(0x123, &[]));
+        record[5] = 65;
+        bad_client.write_all(&record).unwrap();
+        bad_client.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        match bad_client.read(&mut [0; 16]) {
+            Ok(0) => (),
+            Err(ref err) if !is_timeout(err) => (),
+            res => panic!("client was not disconnected: {:?}", res),
+        }
+
+        // The server keeps accepting clients
+        let client = TcpCan::connect(address).unwrap();
+        let request = Message::fd(0x18DA_F110, FrameFormat::Extended, &[0x55; 12], true);
+        client.send_msg(&request).unwrap();
+        let received = node.recv(time::Duration::from_secs(2)).unwrap();
+        assert_eq!((received.id, received.format, received.fd, received.brs), (0x18DA_F110, FrameFormat::Extended, true, true));
+        assert_eq!(received.data, request.data);
+
+        node.send_msg(&Message::new(0x7E8, &[0x04, 0x05])).unwrap();
+        let received = client.recv(time::Duration::from_secs(2)).unwrap();
+        assert_eq!((received.id, received.format), (0x7E8, FrameFormat::Standard));
+        assert_eq!(received.data, vec![0x04, 0x05]);
+        assert_eq!(received.timestamp.map(|t| t.clock), Some(Clock::Monotonic));
+
+        drop(client);
+        running.store(false, Ordering::Relaxed);
+        server_thread.join().unwrap().unwrap();
+    }
+}
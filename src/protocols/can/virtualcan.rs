@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time;
+
+use super::{CanInterface, Message, Filter, Clock, Timestamp, filters_match};
+use crate::error::{Error, Result};
+
+
+/// Receive queue of a single endpoint
+struct Endpoint {
+    queue: Mutex<VecDeque<Message>>,
+    signal: Condvar,
+    filters: Mutex<Vec<Filter>>,
+}
+
+impl Endpoint {
+    fn push(&self, message: Message) {
+        if !filters_match(&self.filters.lock().unwrap(), &message) {
+            return;
+        }
+        self.queue.lock().unwrap().push_back(message);
+        self.signal.notify_one();
+    }
+}
+
+/// An in-memory CAN bus. Every message sent by an endpoint is delivered to
+/// all other endpoints opened from the same bus.
+#[derive(Clone)]
+pub struct VirtualCanBus {
+    endpoints: Arc<Mutex<Vec<Weak<Endpoint>>>>,
+    /// Reference point of message timestamps
+    epoch: time::Instant,
+}
+
+impl Default for VirtualCanBus {
+    fn default() -> VirtualCanBus {
+        VirtualCanBus {
+            endpoints: Arc::new(Mutex::new(Vec::new())),
+            epoch: time::Instant::now(),
+        }
+    }
+}
+
+impl VirtualCanBus {
+    pub fn new() -> VirtualCanBus {
+        VirtualCanBus::default()
+    }
+
+    /// Opens a new endpoint on the bus
+    pub fn open(&self) -> VirtualCan {
+        let endpoint = Arc::new(Endpoint {
+            queue: Mutex::new(VecDeque::new()),
+            signal: Condvar::new(),
+            filters: Mutex::new(Vec::new()),
+        });
+        self.endpoints.lock().unwrap().push(Arc::downgrade(&endpoint));
+        VirtualCan {
+            bus: self.clone(),
+            endpoint,
+        }
+    }
+}
+
+/// An endpoint of a `VirtualCanBus`
+pub struct VirtualCan {
+    bus: VirtualCanBus,
+    endpoint: Arc<Endpoint>,
+}
+
+impl VirtualCan {
+    /// Returns the bus this endpoint is attached to
+    pub fn bus(&self) -> &VirtualCanBus {
+        &self.bus
+    }
+}
+
+impl CanInterface for VirtualCan {
+    fn send(&self, id: u32, message: &[u8]) -> Result<()> {
+        self.send_msg(&Message::new(id, message))
+    }
+
+    fn send_msg(&self, message: &Message) -> Result<()> {
+        message.validate()?;
+
+        let mut message = message.clone();
+        message.timestamp = Some(Timestamp::new(self.bus.epoch.elapsed(), Clock::Monotonic));
+
+        let mut endpoints = self.bus.endpoints.lock().unwrap();
+        // Remove endpoints that have been dropped
+        endpoints.retain(|endpoint| endpoint.upgrade().is_some());
+
+        for endpoint in endpoints.iter().filter_map(|endpoint| endpoint.upgrade()) {
+            if Arc::ptr_eq(&endpoint, &self.endpoint) {
+                continue;
+            }
+            endpoint.push(message.clone());
+        }
+        Ok(())
+    }
+
+    fn set_filters(&self, filters: &[Filter]) -> Result<()> {
+        *self.endpoint.filters.lock().unwrap() = filters.to_vec();
+        Ok(())
+    }
+
+    fn recv(&self, timeout: time::Duration) -> Result<Message> {
+        let start_time = time::Instant::now();
+        let mut queue = self.endpoint.queue.lock().unwrap();
+        loop {
+            if let Some(message) = queue.pop_front() {
+                return Ok(message);
+            }
+            let elapsed = start_time.elapsed();
+            if elapsed >= timeout {
+                return Err(Error::Timeout);
+            }
+            queue = self.endpoint.signal.wait_timeout(queue, timeout - elapsed).unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::can::FrameFormat;
+    use std::thread;
+
+    #[test]
+    fn messages_reach_every_other_endpoint() {
+        let bus = VirtualCanBus::new();
+        let a = bus.open();
+        let b = bus.open();
+        let c = bus.open();
+
+        a.send(0x7E0, &[0x01, 0x02]).unwrap();
+        for endpoint in &[&b, &c] {
+            let message = endpoint.recv(time::Duration::from_millis(100)).unwrap();
+            assert_eq!((message.id, &message.data[..]), (0x7E0, &[0x01, 0x02][..]));
+            assert_eq!(message.timestamp.map(|t| t.clock), Some(Clock::Monotonic));
+        }
+        // The sender does not receive its own message
+        match a.recv(time::Duration::from_millis(10)) {
+            Err(Error::Timeout) => (),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn filters_and_dropped_endpoints() {
+        let bus = VirtualCanBus::new();
+        let a = bus.open();
+        let b = bus.open();
+        drop(bus.open());
+        b.set_filters(&[Filter::exact(0x7E8, FrameFormat::Standard)]).unwrap();
+
+        a.send(0x7E0, &[0x01]).unwrap();
+        a.send(0x7E8, &[0x02]).unwrap();
+        assert_eq!(b.recv(time::Duration::from_millis(100)).unwrap().data, vec![0x02]);
+        assert!(b.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn recv_waits_for_another_thread() {
+        let bus = VirtualCanBus::new();
+        let a = bus.open();
+        let b = bus.open();
+        let sender = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(20));
+            a.send(0x123, &[0xFF]).unwrap();
+        });
+        assert_eq!(b.recv(time::Duration::from_secs(2)).unwrap().id, 0x123);
+        sender.join().unwrap();
+    }
+}
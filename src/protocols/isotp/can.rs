@@ -1,9 +1,9 @@
 use crate::{
-    protocols::can::CanInterface,
+    protocols::can::{self, CanInterface, Filter, Message},
     error::{Error, Result},
 };
 
-use super::{IsotpInterface, Frame, Options, FCFlag, FlowControlFrame, FirstFrame, SingleFrame};
+use super::{IsotpInterface, Frame, Options, FCFlag, FlowControlFrame, FirstFrame, SingleFrame, st_to_duration, MAX_FF_DL_12BIT};
 use std::cmp;
 use std::time;
 use std::thread;
@@ -12,48 +12,186 @@ use std::rc::Rc;
 pub struct IsotpCan {
     can: Rc<CanInterface>,
     options: Options,
+    /// Set if the acceptance filters of the CAN interface are managed by this interface
+    filtered: bool,
 }
 
-fn st_to_duration(st: u8) -> time::Duration {
-    if st <= 127 {
-        return time::Duration::from_millis(st as u64);
+/// Installs acceptance filters. Interfaces that can't filter fall back to
+/// discarding frames in `recv_frame`.
+fn set_filters(can: &CanInterface, filters: &[Filter]) -> Result<()> {
+    match can.set_filters(filters) {
+        Err(Error::Unsupported) => Ok(()),
+        res => res,
     }
-    time::Duration::from_micros(st as u64)
 }
 
 impl IsotpCan {
+    /// Creates an ISO-TP interface. The acceptance filters of the CAN interface
+    /// are not changed, so it can be shared with other users.
     pub fn new(can: Rc<CanInterface>, options: Options) -> IsotpCan {
-        IsotpCan {can, options}
+        IsotpCan {can, options, filtered: false}
+    }
+
+    /// Creates an ISO-TP interface that owns the CAN interface. Acceptance filters
+    /// for `dest_id` and `functional_id` are installed, replacing any existing filters,
+    /// so other users of the CAN interface stop receiving unrelated traffic.
+    pub fn with_filters(can: Rc<CanInterface>, options: Options) -> Result<IsotpCan> {
+        let isotp = IsotpCan {can, options, filtered: true};
+        set_filters(&*isotp.can, &isotp.filters())?;
+        Ok(isotp)
+    }
+
+    /// Returns the acceptance filters for received frames
+    fn filters(&self) -> Vec<Filter> {
+        let mut filters = vec![Filter::exact(self.options.dest_id, self.options.format)];
+        if let Some(id) = self.options.functional_id {
+            filters.push(Filter::exact(id, self.options.format));
+        }
+        filters
+    }
+
+    fn collect_responses(&self, request_id: u32, frame: &Frame, filter: Filter, window: time::Duration) -> Result<Vec<(u32, Vec<u8>)>> {
+        self.send_frame_to(request_id, frame)?;
+
+        let start_time = time::Instant::now();
+        let mut responses = Vec::new();
+        while let Some(remaining) = window.checked_sub(start_time.elapsed()) {
+            let msg = match self.can.recv(remaining) {
+                Err(Error::Timeout) => break,
+                res => res?,
+            };
+            if msg.remote || msg.error || !filter.matches(&msg) {
+                continue;
+            }
+            if let Some(data) = self.strip_address(msg.data) {
+                // Multi-frame responses are ignored
+                if let Ok(frame) = SingleFrame::new(&data) {
+                    responses.push((msg.id, frame.data));
+                }
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Returns the single frame for `data`, or `None` if it must be sent in
+    /// multiple frames. ISO 15765-2 requires the escape sequence whenever the
+    /// frame, including the address extension, is longer than 8 bytes (CAN_DL > 8).
+    fn single_frame(&self, data: &[u8]) -> Option<Frame> {
+        let prefix_len = self.options.tx_dl - self.options.tx_data_len();
+        if data.len() <= 7 && prefix_len + 1 + data.len() <= 8 {
+            Some(Frame::from_single_data(data, false))
+        } else if self.options.fd() && prefix_len + 2 + data.len() <= self.options.tx_dl {
+            Some(Frame::from_single_data(data, true))
+        } else {
+            None
+        }
+    }
+
+    /// Removes the address extension from received frame data. Returns `None`
+    /// if the frame is for another address.
+    fn strip_address(&self, mut data: Vec<u8>) -> Option<Vec<u8>> {
+        match self.options.addressing.rx_prefix() {
+            None => Some(data),
+            Some(prefix) if data.first() == Some(&prefix) => {
+                data.remove(0);
+                Some(data)
+            },
+            // Frames for other addresses may share the CAN identifier
+            Some(_) => None,
+        }
     }
 
     fn send_frame(&self, frame: &Frame) -> Result<()> {
-        self.can.send(self.options.source_id, &frame.data)?;
+        self.send_frame_to(self.options.source_id, frame)
+    }
+
+    fn send_frame_to(&self, id: u32, frame: &Frame) -> Result<()> {
+        let mut data = Vec::with_capacity(self.options.tx_dl);
+        if let Some(prefix) = self.options.addressing.tx_prefix() {
+            data.push(prefix);
+        }
+        data.extend_from_slice(&frame.data);
+
+        let start_time = time::Instant::now();
+        if self.options.fd() {
+            // CAN FD frames longer than 8 bytes must be padded to a valid length
+            let len = can::fd_frame_len(data.len()).ok_or(Error::TooMuchData)?;
+            match self.options.padding {
+                Some(padding) => data.resize(cmp::max(len, 8), padding),
+                None => data.resize(len, 0),
+            }
+            self.can.send_msg(&Message::fd(id, self.options.format, &data, self.options.brs))?;
+        } else {
+            if let Some(padding) = self.options.padding {
+                data.resize(8, padding);
+            }
+            self.can.send_msg(&Message::with_format(id, self.options.format, &data))?;
+        }
+        // Sends are synchronous, so an overrun of N_As is only detected once the
+        // interface returns. The frame may already have been transmitted.
+        if start_time.elapsed() > self.options.n_as {
+            return Err(Error::Timeout);
+        }
         Ok(())
     }
 
+    /// Returns the flow control frame refusing a message that is too long
+    fn overflow(&self) -> FlowControlFrame {
+        FlowControlFrame {
+            flag: FCFlag::Overflow,
+            block_size: 0,
+            separation_time: time::Duration::from_millis(0),
+        }
+    }
+
+    /// Returns the flow control frame advertising our block size and separation time
+    fn flow_control(&self) -> FlowControlFrame {
+        FlowControlFrame {
+            flag: FCFlag::Continue,
+            block_size: self.options.rx_block_size,
+            separation_time: self.options.rx_separation_time,
+        }
+    }
+
     fn send_flow_control_frame(&self, flow: FlowControlFrame) -> Result<()> {
         self.send_frame(&Frame::from_flow(flow))
     }
 
-    fn recv_frame(&self) -> Result<Frame> {
+    /// Receives the next frame from `dest_id` or a single frame from `functional_id`,
+    /// waiting at most `timeout` in total. Frames for other ids do not extend the wait.
+    fn recv_frame(&self, timeout: time::Duration) -> Result<Frame> {
         let start_time = time::Instant::now();
-        for msg in self.can.recv_iter(self.options.timeout) {
-            let msg = msg?;
-            if msg.id == self.options.dest_id {
-                let mut data = [0; 8];
-                data[..msg.data.len()].copy_from_slice(&msg.data);
-                return Ok(Frame::new(data));
-            }
-            if start_time.elapsed() >= self.options.timeout {
-                return Err(Error::Timeout);
+        // The first receive uses the whole timeout so a zero timeout returns waiting frames
+        let mut remaining = timeout;
+        loop {
+            let msg = self.can.recv(remaining)?;
+            let physical = msg.id == self.options.dest_id;
+            let addressed = physical || Some(msg.id) == self.options.functional_id;
+            if addressed && msg.format == self.options.format && !msg.remote && !msg.error {
+                if let Some(data) = self.strip_address(msg.data) {
+                    if data.is_empty() {
+                        return Err(Error::InvalidFrame);
+                    }
+                    if physical || data[0] & 0xF0 == 0 {
+                        return Ok(Frame::new(data));
+                    }
+                }
             }
+            remaining = timeout.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
         }
-        Err(Error::Timeout)
     }
 
+    /// Receives a flow control frame within N_Bs. Other frames are ignored.
     fn recv_flow_control_frame(&self) -> Result<FlowControlFrame> {
-        let frame = self.recv_frame()?;
-        if frame.data[0] & 0xF0 != 0x30 {
+        let start_time = time::Instant::now();
+        let frame = loop {
+            let remaining = self.options.n_bs.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            let frame = self.recv_frame(remaining)?;
+            if frame.data[0] & 0xF0 == 0x30 {
+                break frame;
+            }
+        };
+        if frame.data.len() < 3 {
             return Err(Error::InvalidFrame);
         }
 
@@ -74,31 +212,53 @@ impl IsotpCan {
             separation_time: st_to_duration(frame.data[2])
         })
     }
+
+    /// Waits until the receiver allows sending the next block. Wait frames restart
+    /// the N_Bs timer, up to `wft_max` times.
+    fn wait_flow_control(&self) -> Result<FlowControlFrame> {
+        let mut waits = 0;
+        loop {
+            let flow_control = self.recv_flow_control_frame()?;
+            match flow_control.flag {
+                FCFlag::Continue => return Ok(flow_control),
+                FCFlag::Wait => {
+                    waits += 1;
+                    if self.options.wft_max > 0 && waits > self.options.wft_max {
+                        return Err(Error::WaitLimit);
+                    }
+                },
+                FCFlag::Overflow => return Err(Error::Overflow),
+            }
+        }
+    }
 }
 
 struct SendPacket<'a> {
     buffer: &'a [u8],
     index: u8,
+    /// Maximum frame data length
+    tx_dl: usize,
 }
 
 /// Used for sending mutli-frame packets.
 /// It is NOT used for single-frame packets.
 impl<'a> SendPacket<'a> {
-    fn new(buffer: &[u8]) -> SendPacket {
-        assert!(buffer.len() <= 4095);
-        SendPacket {buffer, index: 0}
+    fn new(buffer: &[u8], tx_dl: usize) -> SendPacket {
+        SendPacket {buffer, index: 0, tx_dl}
     }
 
     fn first_frame(&mut self) -> Frame {
-        let len = cmp::min(self.buffer.len(), 6);
-        let frame = Frame::from_first_data(&self.buffer[..len], self.buffer.len() as u16);
+        // The escape sequence for long messages takes 4 more bytes
+        let header_len = if self.buffer.len() as u32 > MAX_FF_DL_12BIT { 6 } else { 2 };
+        let len = cmp::min(self.buffer.len(), self.tx_dl - header_len);
+        let frame = Frame::from_first_data(&self.buffer[..len], self.buffer.len() as u32);
         self.buffer = &self.buffer[len..];
         self.index = 1;
         frame
     }
 
     fn next_consec_frame(&mut self) -> Frame {
-        let len = cmp::min(self.buffer.len(), 7);
+        let len = cmp::min(self.buffer.len(), self.tx_dl - 1);
         let frame = Frame::from_consec_data(&self.buffer[..len], self.index);
         self.buffer = &self.buffer[len..];
         self.index += 1;
@@ -115,67 +275,102 @@ impl<'a> SendPacket<'a> {
 
 impl IsotpInterface for IsotpCan {
     fn recv(&self) -> Result<Vec<u8>> {
+        self.recv_timeout(self.options.timeout)
+    }
+
+    fn recv_timeout(&self, timeout: time::Duration) -> Result<Vec<u8>> {
+        let start_time = time::Instant::now();
         // Receive first or single frame
-        let frame = self.recv_frame()?;
-        let frame_id = frame.data[0] & 0xF0;
-
-        if frame_id == 0 {
-            // Single frame
-            let single_frame = SingleFrame::new(&frame.data)?;
-            return Ok(single_frame.data[..single_frame.length as usize].to_vec());
-        } else if frame_id == 0x10 {
+        let mut frame = self.recv_frame(timeout)?;
+        'message: loop {
+            let frame_id = frame.data[0] & 0xF0;
+            if frame_id == 0 {
+                // Single frame
+                let single_frame = SingleFrame::new(&frame.data)?;
+                return Ok(single_frame.data);
+            } else if frame_id != 0x10 {
+                // Flow control and consecutive frames are ignored until a message
+                // starts, without restarting the timeout
+                let remaining = timeout.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+                frame = self.recv_frame(remaining)?;
+                continue;
+            }
+
             // First frame
             let first_frame = FirstFrame::new(&frame.data)?;
+            if first_frame.length > self.options.max_rx_len {
+                self.send_flow_control_frame(self.overflow())?;
+                return Err(Error::Overflow);
+            }
+            // The length of the first frame sets the length of all consecutive frames
+            let rx_dl = frame.data.len();
 
-            let mut buffer = Vec::new();
-            buffer.extend_from_slice(&first_frame.data);
+            let mut buffer = first_frame.data;
 
-            let mut remaining = first_frame.length as usize - buffer.len();
+            let mut remaining = first_frame.length - buffer.len();
             // Send the flow control frame
-            self.send_flow_control_frame(FlowControlFrame {
-                flag: FCFlag::Continue,
-                block_size: 0,
-                separation_time: st_to_duration(0)
-            })?;
+            self.send_flow_control_frame(self.flow_control())?;
 
             // Wait for all consecutive packets
             let mut index = 1;
+            let mut block_remaining = self.options.rx_block_size;
             while remaining > 0 {
-                let frame = self.recv_frame()?;
-                let id = frame.data[0] & 0xF0;
-                if id != 0x20 {
-                    // Not a consec frame
-                    return Err(Error::InvalidFrame);
+                // N_Cr runs from the previous frame; ignored frames do not restart it
+                let cr_start = time::Instant::now();
+                let next = loop {
+                    let wait = self.options.n_cr.checked_sub(cr_start.elapsed()).ok_or(Error::Timeout)?;
+                    let next = self.recv_frame(wait)?;
+                    if next.data[0] & 0xF0 <= 0x20 {
+                        break next;
+                    }
+                };
+                if next.data[0] & 0xF0 != 0x20 {
+                    // A new message aborts the current reception
+                    frame = next;
+                    continue 'message;
                 }
-                if frame.data[0] & 0x0F != index {
-                    // Invalid index
-                    return Err(Error::InvalidFrame);
+                if next.data[0] & 0x0F != index {
+                    return Err(Error::InvalidSequence);
                 }
 
-                let len = cmp::min(remaining, 7);
-                buffer.extend_from_slice(&frame.data[1..=len]);
+                let len = cmp::min(remaining, rx_dl - 1);
+                if next.data.len() <= len {
+                    return Err(Error::InvalidFrame);
+                }
+                buffer.extend_from_slice(&next.data[1..=len]);
                 remaining -= len;
 
                 index += 1;
                 if index == 16 {
                     index = 0;
                 }
+
+                if remaining > 0 && self.options.rx_block_size > 0 {
+                    block_remaining -= 1;
+                    if block_remaining == 0 {
+                        // Allow the next block
+                        self.send_flow_control_frame(self.flow_control())?;
+                        block_remaining = self.options.rx_block_size;
+                    }
+                }
             }
             return Ok(buffer);
         }
-        Err(Error::InvalidFrame)
     }
 
     fn send(&self, data: &[u8]) -> Result<()> {
-        if data.len() <= 7 {
-            // Send a single frame
-            self.send_frame(&Frame::from_single_data(&data))?;
+        if data.len() > u32::max_value() as usize {
+            return Err(Error::TooMuchData);
+        }
+        let tx_dl = self.options.tx_data_len();
+        if let Some(frame) = self.single_frame(data) {
+            self.send_frame(&frame)?;
         } else {
-            let mut packet = SendPacket::new(&data);
+            let mut packet = SendPacket::new(&data, tx_dl);
             // Send a first frame
             self.send_frame(&packet.first_frame())?;
             // Get flow control and send consecutive frames
-            let mut flow_control = self.recv_flow_control_frame()?;
+            let mut flow_control = self.wait_flow_control()?;
             while !packet.eof() {
                 // Loop until the buffer is empty
                 if flow_control.separation_time != time::Duration::new(0, 0) {
@@ -188,11 +383,433 @@ impl IsotpInterface for IsotpCan {
                     flow_control.block_size -= 1;
                     if flow_control.block_size == 0 {
                         // Get the next flow control packet
-                        flow_control = self.recv_flow_control_frame()?;
+                        flow_control = self.wait_flow_control()?;
                     }
                 }
             }
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Sends a functionally addressed request. If this interface manages the
+    /// acceptance filters, `responses` is added to them while waiting.
+    fn functional_request(&self, request_id: u32, data: &[u8], responses: Filter, window: time::Duration) -> Result<Vec<(u32, Vec<u8>)>> {
+        if data.is_empty() {
+            return Err(Error::EmptyPacket);
+        }
+        // Functionally addressed requests are limited to single frames
+        let frame = self.single_frame(data).ok_or(Error::TooMuchData)?;
+        if !self.filtered {
+            return self.collect_responses(request_id, &frame, responses, window);
+        }
+
+        let mut filters = self.filters();
+        filters.push(responses);
+        set_filters(&*self.can, &filters)?;
+        let result = self.collect_responses(request_id, &frame, responses, window);
+        // Restore the filters even if collecting failed
+        let restored = set_filters(&*self.can, &self.filters());
+        let responses = result?;
+        restored?;
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::{
+        can::VirtualCanBus,
+        isotp::Addressing,
+    };
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn fd_extended_options() -> Options {
+        Options {
+            tx_dl: 64,
+            addressing: Addressing::Extended { tx: 0xF1, rx: 0x10 },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fd_single_frame_longer_than_8_bytes_uses_escape_sequence() {
+        let bus = VirtualCanBus::new();
+        let raw = bus.open();
+        let isotp = IsotpCan::new(Rc::new(bus.open()), fd_extended_options());
+
+        // The address byte makes the frame 9 bytes long, which pads to CAN_DL 12
+        isotp.send(&[1, 2, 3, 4, 5, 6, 7]).unwrap();
+        let msg = raw.recv(time::Duration::from_millis(100)).unwrap();
+        assert!(msg.fd);
+        assert_eq!(msg.data.len(), 12);
+        assert_eq!(&msg.data[..10], &[0xF1, 0x00, 0x07, 1, 2, 3, 4, 5, 6, 7]);
+
+        // Six bytes still fit in a classic single frame
+        isotp.send(&[1, 2, 3, 4, 5, 6]).unwrap();
+        let msg = raw.recv(time::Duration::from_millis(100)).unwrap();
+        assert_eq!(msg.data, vec![0xF1, 0x06, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn fd_escape_single_frame_is_received() {
+        let bus = VirtualCanBus::new();
+        let sender = IsotpCan::new(Rc::new(bus.open()), fd_extended_options());
+        let receiver = IsotpCan::new(Rc::new(bus.open()), Options {
+            source_id: 0x7E8,
+            dest_id: 0x7E0,
+            addressing: Addressing::Extended { tx: 0x10, rx: 0xF1 },
+            ..fd_extended_options()
+        });
+
+        sender.send(&[1, 2, 3, 4, 5, 6, 7]).unwrap();
+        assert_eq!(receiver.recv().unwrap(), vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn mixed_addressing_prefixes_frames_in_both_directions() {
+        let bus = VirtualCanBus::new();
+        let peer = bus.open();
+        let isotp = IsotpCan::new(Rc::new(bus.open()), Options {
+            addressing: Addressing::Mixed(0x55),
+            timeout: time::Duration::from_millis(50),
+            ..Default::default()
+        });
+
+        isotp.send(&[0x3E, 0x00]).unwrap();
+        let msg = peer.recv(time::Duration::from_millis(100)).unwrap();
+        assert_eq!(msg.data, vec![0x55, 0x02, 0x3E, 0x00, 0, 0, 0, 0]);
+
+        // Frames with another address extension are ignored
+        peer.send(0x7E8, &[0x66, 0x02, 0x7E, 0x00]).unwrap();
+        peer.send(0x7E8, &[0x55, 0x02, 0x7E, 0x00]).unwrap();
+        assert_eq!(isotp.recv().unwrap(), vec![0x7E, 0x00]);
+        peer.send(0x7E8, &[0x66, 0x02, 0x7E, 0x00]).unwrap();
+        match isotp.recv() {
+            Err(Error::Timeout) => (),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn frames_are_not_padded_without_padding() {
+        let bus = VirtualCanBus::new();
+        let peer = bus.open();
+        let isotp = IsotpCan::new(Rc::new(bus.open()), Options {
+            padding: None,
+            ..Default::default()
+        });
+
+        isotp.send(&[0x3E, 0x00]).unwrap();
+        assert_eq!(peer.recv(time::Duration::from_millis(100)).unwrap().data, vec![0x02, 0x3E, 0x00]);
+        // Only the last consecutive frame is short
+        let raw = bus.open();
+        let listener = thread::spawn(move || {
+            let isotp = IsotpCan::new(Rc::new(peer), Options::listener(0x7E0, 0x7E8));
+            isotp.recv().unwrap()
+        });
+        isotp.send(&[0xAA; 10]).unwrap();
+        assert_eq!(listener.join().unwrap(), vec![0xAA; 10]);
+        let frames: Vec<usize> = (0..3).map(|_| raw.recv(time::Duration::from_millis(100)).unwrap().data.len()).collect();
+        // First frame, padded flow control from the listener and a 5 byte consecutive frame
+        assert_eq!(frames, vec![8, 8, 5]);
+    }
+
+    #[test]
+    fn flow_control_advertises_block_size_and_separation_time() {
+        let bus = VirtualCanBus::new();
+        let peer = bus.open();
+        let can = bus.open();
+        let receiver = thread::spawn(move || {
+            let isotp = IsotpCan::new(Rc::new(can), Options {
+                rx_block_size: 2,
+                rx_separation_time: time::Duration::from_millis(5),
+                ..Default::default()
+            });
+            isotp.recv().unwrap()
+        });
+        let expect_flow_control = || {
+            let msg = peer.recv(time::Duration::from_secs(1)).unwrap();
+            assert_eq!((msg.id, &msg.data[..3]), (0x7E0, &[0x30, 2, 5][..]));
+        };
+
+        let message: Vec<u8> = (0..30).collect();
+        peer.send(0x7E8, &[0x10, 30, 0, 1, 2, 3, 4, 5]).unwrap();
+        expect_flow_control();
+        peer.send(0x7E8, &[0x21, 6, 7, 8, 9, 10, 11, 12]).unwrap();
+        peer.send(0x7E8, &[0x22, 13, 14, 15, 16, 17, 18, 19]).unwrap();
+        // A new flow control frame after every block of 2
+        expect_flow_control();
+        peer.send(0x7E8, &[0x23, 20, 21, 22, 23, 24, 25, 26]).unwrap();
+        peer.send(0x7E8, &[0x24, 27, 28, 29, 0, 0, 0, 0]).unwrap();
+
+        assert_eq!(receiver.join().unwrap(), message);
+        // No flow control follows the last frame
+        assert!(peer.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn messages_over_4095_bytes_use_the_32_bit_length() {
+        let bus = VirtualCanBus::new();
+        let raw = bus.open();
+        let server = bus.open();
+        let request: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let response: Vec<u8> = (0..6000).map(|i| (i * 3) as u8).collect();
+        let listener = {
+            let (request, response) = (request.clone(), response.clone());
+            thread::spawn(move || {
+                let isotp = IsotpCan::new(Rc::new(server), Options::listener(0x7E0, 0x7E8));
+                assert_eq!(isotp.recv().unwrap(), request);
+                isotp.send(&response).unwrap();
+            })
+        };
+
+        let isotp = IsotpCan::new(Rc::new(bus.open()), Options::default());
+        assert_eq!(isotp.request(&request).unwrap(), response);
+        listener.join().unwrap();
+
+        // The escape sequence is followed by the 32-bit length in both directions
+        let first_frames: Vec<Message> = std::iter::from_fn(|| raw.try_recv().unwrap())
+            .filter(|msg| msg.data[0] & 0xF0 == 0x10)
+            .collect();
+        assert_eq!(first_frames.len(), 2);
+        assert_eq!((first_frames[0].id, &first_frames[0].data[..]), (0x7E0, &[0x10, 0x00, 0x00, 0x00, 0x13, 0x88, 0, 1][..]));
+        assert_eq!((first_frames[1].id, &first_frames[1].data[..]), (0x7E8, &[0x10, 0x00, 0x00, 0x00, 0x17, 0x70, 0, 3][..]));
+    }
+
+    #[test]
+    fn messages_over_max_rx_len_are_refused() {
+        let bus = VirtualCanBus::new();
+        let peer = bus.open();
+        let isotp = IsotpCan::new(Rc::new(bus.open()), Options {
+            max_rx_len: 4096,
+            ..Default::default()
+        });
+
+        peer.send(0x7E8, &[0x10, 0x00, 0x00, 0x00, 0x10, 0x01, 1, 2]).unwrap();
+        match isotp.recv() {
+            Err(Error::Overflow) => (),
+            res => panic!("expected Overflow, got {:?}", res),
+        }
+        let flow_control = peer.recv(time::Duration::from_millis(100)).unwrap();
+        assert_eq!(&flow_control.data[..3], &[0x32, 0, 0]);
+
+        // A message of the maximum length is accepted
+        let can = bus.open();
+        let receiver = thread::spawn(move || {
+            let isotp = IsotpCan::new(Rc::new(can), Options {
+                max_rx_len: 4096,
+                ..Options::listener(0x7E0, 0x7E8)
+            });
+            isotp.recv().unwrap().len()
+        });
+        isotp.send(&[0x55; 4096]).unwrap();
+        assert_eq!(receiver.join().unwrap(), 4096);
+    }
+
+    #[test]
+    fn new_does_not_filter_a_shared_interface() {
+        let bus = VirtualCanBus::new();
+        let sender = bus.open();
+        let shared: Rc<CanInterface> = Rc::new(bus.open());
+        let _isotp = IsotpCan::new(shared.clone(), Options::default());
+
+        sender.send(0x123, &[1]).unwrap();
+        assert_eq!(shared.recv(time::Duration::from_millis(100)).unwrap().id, 0x123);
+    }
+
+    #[test]
+    fn with_filters_restricts_to_the_destination_id() {
+        let bus = VirtualCanBus::new();
+        let sender = bus.open();
+        let can: Rc<CanInterface> = Rc::new(bus.open());
+        let isotp = IsotpCan::with_filters(can.clone(), Options::default()).unwrap();
+
+        sender.send(0x123, &[1]).unwrap();
+        sender.send(0x7E8, &[0x01, 0xAA]).unwrap();
+        assert_eq!(isotp.recv().unwrap(), vec![0xAA]);
+        assert!(can.try_recv().unwrap().is_none());
+    }
+
+    struct FailingFilters;
+
+    impl CanInterface for FailingFilters {
+        fn send(&self, _id: u32, _message: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn recv(&self, _timeout: time::Duration) -> Result<Message> {
+            Err(Error::Timeout)
+        }
+
+        fn set_filters(&self, _filters: &[Filter]) -> Result<()> {
+            Err(Error::Io(std::io::Error::new(std::io::ErrorKind::Other, "adapter error")))
+        }
+    }
+
+    #[test]
+    fn with_filters_propagates_errors() {
+        assert!(IsotpCan::with_filters(Rc::new(FailingFilters), Options::default()).is_err());
+        // Interfaces without filter support are accepted
+        let bus = VirtualCanBus::new();
+        assert!(IsotpCan::with_filters(Rc::new(Unfiltered(bus.open())), Options::default()).is_ok());
+    }
+
+    /// Interface that only implements the required methods
+    struct Unfiltered(can::VirtualCan);
+
+    impl CanInterface for Unfiltered {
+        fn send(&self, id: u32, message: &[u8]) -> Result<()> {
+            self.0.send(id, message)
+        }
+
+        fn recv(&self, timeout: time::Duration) -> Result<Message> {
+            self.0.recv(timeout)
+        }
+    }
+
+    fn timer_options() -> Options {
+        Options {
+            n_as: time::Duration::from_millis(50),
+            n_bs: time::Duration::from_millis(50),
+            n_cr: time::Duration::from_millis(50),
+            ..Default::default()
+        }
+    }
+
+    /// Sends a frame every 10ms from another thread until dropped
+    struct Spammer {
+        running: Arc<AtomicBool>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Spammer {
+        fn new(bus: &VirtualCanBus, id: u32, data: &'static [u8]) -> Spammer {
+            let can = bus.open();
+            let running = Arc::new(AtomicBool::new(true));
+            let thread = {
+                let running = running.clone();
+                thread::spawn(move || {
+                    while running.load(Ordering::Relaxed) {
+                        can.send(id, data).unwrap();
+                        thread::sleep(time::Duration::from_millis(10));
+                    }
+                })
+            };
+            Spammer {running, thread: Some(thread)}
+        }
+    }
+
+    impl Drop for Spammer {
+        fn drop(&mut self) {
+            self.running.store(false, Ordering::Relaxed);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn assert_timeout<T: std::fmt::Debug>(result: Result<T>, start_time: time::Instant, limit: time::Duration) {
+        match result {
+            Err(Error::Timeout) => (),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+        assert!(start_time.elapsed() < limit, "timed out after {:?}", start_time.elapsed());
+    }
+
+    /// Interface whose sends take `delay`
+    struct SlowCan(can::VirtualCan, time::Duration);
+
+    impl CanInterface for SlowCan {
+        fn send(&self, id: u32, message: &[u8]) -> Result<()> {
+            thread::sleep(self.1);
+            self.0.send(id, message)
+        }
+
+        fn recv(&self, timeout: time::Duration) -> Result<Message> {
+            self.0.recv(timeout)
+        }
+    }
+
+    #[test]
+    fn n_as_overrun_is_reported() {
+        let bus = VirtualCanBus::new();
+        let raw = bus.open();
+        let isotp = IsotpCan::new(Rc::new(SlowCan(bus.open(), time::Duration::from_millis(80))), timer_options());
+        match isotp.send(&[0x3E, 0x00]) {
+            Err(Error::Timeout) => (),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+        // The frame was still sent
+        assert_eq!(raw.recv(time::Duration::from_millis(100)).unwrap().data[..3], [0x02, 0x3E, 0x00]);
+    }
+
+    #[test]
+    fn n_bs_is_not_extended_by_other_frames() {
+        let bus = VirtualCanBus::new();
+        let isotp = IsotpCan::new(Rc::new(bus.open()), timer_options());
+        // Consecutive frames from the peer are not flow control frames
+        let _spammer = Spammer::new(&bus, 0x7E8, &[0x21, 0, 0, 0, 0, 0, 0, 0]);
+
+        let start_time = time::Instant::now();
+        assert_timeout(isotp.send(&[0x55; 20]), start_time, time::Duration::from_millis(200));
+    }
+
+    #[test]
+    fn n_cr_is_not_extended_by_other_frames() {
+        let bus = VirtualCanBus::new();
+        let peer = bus.open();
+        let isotp = IsotpCan::new(Rc::new(bus.open()), timer_options());
+        // The peer starts a message and only sends flow control frames afterwards
+        peer.send(0x7E8, &[0x10, 20, 1, 2, 3, 4, 5, 6]).unwrap();
+        let _spammer = Spammer::new(&bus, 0x7E8, &[0x30, 0, 0, 0, 0, 0, 0, 0]);
+
+        let start_time = time::Instant::now();
+        assert_timeout(isotp.recv_timeout(time::Duration::from_millis(50)), start_time, time::Duration::from_millis(200));
+    }
+
+    #[test]
+    fn recv_timeout_is_not_extended_by_stray_frames() {
+        let bus = VirtualCanBus::new();
+        let isotp = IsotpCan::new(Rc::new(bus.open()), timer_options());
+        let _spammer = Spammer::new(&bus, 0x7E8, &[0x21, 0, 0, 0, 0, 0, 0, 0]);
+
+        let start_time = time::Instant::now();
+        assert_timeout(isotp.recv_timeout(time::Duration::from_millis(50)), start_time, time::Duration::from_millis(200));
+    }
+
+    #[test]
+    fn wait_frames_are_limited_by_wft_max() {
+        let bus = VirtualCanBus::new();
+        let isotp = IsotpCan::new(Rc::new(bus.open()), Options {
+            wft_max: 2,
+            ..timer_options()
+        });
+        let _spammer = Spammer::new(&bus, 0x7E8, &[0x31, 0, 0, 0, 0, 0, 0, 0]);
+
+        match isotp.send(&[0x55; 20]) {
+            Err(Error::WaitLimit) => (),
+            res => panic!("expected WaitLimit, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn functional_request_through_the_trait() {
+        let bus = VirtualCanBus::new();
+        let ecus = [bus.open(), bus.open()];
+        let isotp: Rc<IsotpInterface> = Rc::new(IsotpCan::new(Rc::new(bus.open()), Options::default()));
+        // Both ECUs answer the broadcast
+        let responder = thread::spawn(move || {
+            for (i, ecu) in ecus.iter().enumerate() {
+                let request = ecu.recv(time::Duration::from_secs(1)).unwrap();
+                assert_eq!((request.id, &request.data[..3]), (0x7DF, &[0x02, 0x01, 0x00][..]));
+                ecu.send(0x7E8 + i as u32, &[0x02, 0x41, 0x00]).unwrap();
+            }
+        });
+
+        let responses = isotp.functional_request(0x7DF, &[0x01, 0x00], Filter::new(0x7E8, 0x7F8, can::FrameFormat::Standard), time::Duration::from_millis(200)).unwrap();
+        responder.join().unwrap();
+        assert_eq!(responses, vec![(0x7E8, vec![0x41, 0x00]), (0x7E9, vec![0x41, 0x00])]);
+    }
+}
@@ -0,0 +1,393 @@
+use crate::{
+    protocols::{
+        can::FrameFormat,
+        serial::{SerialInterface, SerialPort},
+    },
+    error::{Error, Result},
+};
+
+use super::{IsotpInterface, Options, Addressing, SingleFrame, FirstFrame, duration_to_st};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::path::Path;
+use std::rc::Rc;
+use std::time;
+
+/// Time to wait for the adapter to answer an AT command
+const COMMAND_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// A line of adapter output
+enum Line {
+    Text(String),
+    /// The adapter is ready for a new command
+    Prompt,
+}
+
+/// An ELM327-compatible adapter connected through a serial port
+pub struct Elm327 {
+    port: Box<SerialInterface>,
+    /// Received bytes that have not been parsed
+    buffer: RefCell<Vec<u8>>,
+    /// True if a command was sent and the prompt has not been received
+    busy: Cell<bool>,
+    /// Source and destination ids, frame format, block size and separation time the adapter is configured for
+    configured: Cell<Option<(u32, u32, FrameFormat, u8, u8)>>,
+    version: String,
+}
+
+impl Elm327 {
+    /// Opens the serial device at `path` with the speed `baudrate` and resets the adapter
+    pub fn open<P: AsRef<Path>>(path: P, baudrate: u32) -> Result<Elm327> {
+        Elm327::new(Box::new(SerialPort::open(path, baudrate)?))
+    }
+
+    /// Resets and initializes an adapter connected through `port`
+    pub fn new(port: Box<SerialInterface>) -> Result<Elm327> {
+        let mut elm = Elm327 {
+            port,
+            buffer: RefCell::new(Vec::new()),
+            busy: Cell::new(false),
+            configured: Cell::new(None),
+            version: String::new(),
+        };
+
+        // Cancel anything in progress and discard its output
+        elm.port.write_all(b"\r")?;
+        elm.busy.set(true);
+        let _ = elm.wait_ready(time::Duration::from_millis(200));
+        elm.busy.set(false);
+        elm.buffer.borrow_mut().clear();
+
+        let lines = elm.command("ATZ")?;
+        elm.version = lines.into_iter().find(|line| line.starts_with("ELM") || line.starts_with("STN"))
+            .ok_or(Error::InvalidResponse)?;
+
+        // Echo off, linefeeds off, spaces off, headers on
+        elm.at("ATE0")?;
+        elm.at("ATL0")?;
+        elm.at("ATS0")?;
+        elm.at("ATH1")?;
+        // Let the adapter format ISO-TP frames
+        elm.at("ATCAF1")?;
+        Ok(elm)
+    }
+
+    /// Returns the version string reported on reset, e.g. "ELM327 v1.5"
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Reads the next line or prompt, waiting up to `timeout`
+    fn read_line(&self, timeout: time::Duration) -> Result<Line> {
+        let start_time = time::Instant::now();
+        loop {
+            {
+                let mut buffer = self.buffer.borrow_mut();
+                // Skip empty lines and NUL bytes some clones send
+                while !buffer.is_empty() && (buffer[0] == b'\r' || buffer[0] == b'\n' || buffer[0] == 0) {
+                    buffer.remove(0);
+                }
+                if !buffer.is_empty() && buffer[0] == b'>' {
+                    buffer.remove(0);
+                    self.busy.set(false);
+                    return Ok(Line::Prompt);
+                }
+                if let Some(end) = buffer.iter().position(|&b| b == b'\r' || b == b'\n' || b == b'>') {
+                    let line: Vec<u8> = buffer.drain(..end).collect();
+                    return Ok(Line::Text(String::from_utf8_lossy(&line).trim().to_string()));
+                }
+            }
+
+            let remaining = timeout.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            let data = self.port.read(remaining)?;
+            if data.is_empty() {
+                return Err(Error::Timeout);
+            }
+            self.buffer.borrow_mut().extend_from_slice(&data);
+        }
+    }
+
+    /// Waits for the prompt of the previous command, discarding its output
+    fn wait_ready(&self, timeout: time::Duration) -> Result<()> {
+        while self.busy.get() {
+            self.read_line(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a command or data line
+    fn write_line(&self, line: &str) -> Result<()> {
+        self.wait_ready(COMMAND_TIMEOUT)?;
+        self.port.write_all(line.as_bytes())?;
+        self.port.write_all(b"\r")?;
+        self.busy.set(true);
+        Ok(())
+    }
+
+    /// Sends a command and returns the output lines
+    pub fn command(&self, command: &str) -> Result<Vec<String>> {
+        self.write_line(command)?;
+        let mut lines = Vec::new();
+        loop {
+            match self.read_line(COMMAND_TIMEOUT)? {
+                Line::Text(line) => lines.push(line),
+                Line::Prompt => return Ok(lines),
+            }
+        }
+    }
+
+    /// Sends an AT command that responds with OK
+    fn at(&self, command: &str) -> Result<()> {
+        if self.command(command)?.iter().any(|line| line == "OK") {
+            return Ok(());
+        }
+        Err(Error::InvalidResponse)
+    }
+
+    /// Configures the protocol, headers and flow control for the ISO-TP options
+    fn configure(&self, options: &Options) -> Result<()> {
+        let st = duration_to_st(options.rx_separation_time);
+        let key = (options.source_id, options.dest_id, options.format, options.rx_block_size, st);
+        let extended = options.format == FrameFormat::Extended;
+        if self.configured.get() == Some(key) {
+            return Ok(());
+        }
+        self.configured.set(None);
+
+        if extended {
+            // ISO 15765-4 CAN (29 bit ID, 500 kbaud)
+            self.at("ATSP7")?;
+            self.at(&format!("ATCP{:02X}", options.source_id >> 24))?;
+            self.at(&format!("ATSH{:06X}", options.source_id & 0xFF_FFFF))?;
+        } else {
+            // ISO 15765-4 CAN (11 bit ID, 500 kbaud)
+            self.at("ATSP6")?;
+            self.at(&format!("ATSH{:03X}", options.source_id))?;
+        }
+        if extended {
+            self.at(&format!("ATCRA{:08X}", options.dest_id))?;
+        } else {
+            self.at(&format!("ATCRA{:03X}", options.dest_id))?;
+        }
+
+        // Send flow control frames from our source id
+        if extended {
+            self.at(&format!("ATFCSH{:08X}", options.source_id))?;
+        } else {
+            self.at(&format!("ATFCSH{:03X}", options.source_id))?;
+        }
+        self.at(&format!("ATFCSD30{:02X}{:02X}", options.rx_block_size, st))?;
+        self.at("ATFCSM1")?;
+
+        // The response timeout is set in units of 4ms
+        let millis = options.timeout.as_secs() * 1000 + options.timeout.subsec_millis() as u64;
+        self.at(&format!("ATST{:02X}", cmp::min(cmp::max(millis / 4, 1), 0xFF)))?;
+
+        self.configured.set(Some(key));
+        Ok(())
+    }
+}
+
+/// ISO-TP interface using the adapter's ISO-TP handling. ELM327 adapters
+/// can only send single frames, so requests are limited to 7 bytes and
+/// longer requests fail with `Error::Unsupported`. Responses of any length
+/// are received.
+pub struct Elm327Isotp {
+    elm: Rc<Elm327>,
+    options: Options,
+}
+
+impl Elm327Isotp {
+    /// Configures the adapter for `options`. Only normal addressing is supported.
+    pub fn new(elm: Rc<Elm327>, options: Options) -> Result<Elm327Isotp> {
+        if options.addressing != Addressing::Normal || options.functional_id.is_some() {
+            return Err(Error::Unsupported);
+        }
+        elm.configure(&options)?;
+        Ok(Elm327Isotp {elm, options})
+    }
+
+    /// Parses a response line into the frame data without the header
+    fn parse_frame(&self, line: &str) -> Result<Option<Vec<u8>>> {
+        let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        let header_len = match self.options.format {
+            FrameFormat::Standard => 3,
+            FrameFormat::Extended => 8,
+        };
+        if line.len() <= header_len || (line.len() - header_len) % 2 != 0 || !line.chars().all(|c| c.is_ascii_hexdigit()) {
+            return match &line[..] {
+                "NODATA" => Err(Error::Timeout),
+                // Adapter status messages
+                "SEARCHING..." | "OK" => Ok(None),
+                _ => Err(Error::InvalidResponse),
+            };
+        }
+        let id = u32::from_str_radix(&line[..header_len], 16).map_err(|_| Error::InvalidFrame)?;
+        if id != self.options.dest_id {
+            return Ok(None);
+        }
+        let data = (header_len..line.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&line[i..i + 2], 16).map_err(|_| Error::InvalidFrame))
+            .collect::<Result<Vec<u8>>>()?;
+        Ok(Some(data))
+    }
+
+    /// Receives the data of the next frame from the destination id, waiting up to `timeout`
+    fn recv_frame(&self, timeout: time::Duration) -> Result<Vec<u8>> {
+        let start_time = time::Instant::now();
+        loop {
+            let remaining = timeout.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            match self.elm.read_line(remaining)? {
+                // The adapter stopped waiting for responses
+                Line::Prompt => return Err(Error::Timeout),
+                Line::Text(line) => {
+                    if let Some(frame) = self.parse_frame(&line)? {
+                        if !frame.is_empty() {
+                            return Ok(frame);
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl IsotpInterface for Elm327Isotp {
+    fn recv(&self) -> Result<Vec<u8>> {
+        self.recv_timeout(self.options.timeout)
+    }
+
+    fn recv_timeout(&self, timeout: time::Duration) -> Result<Vec<u8>> {
+        let frame = self.recv_frame(timeout)?;
+        match frame[0] & 0xF0 {
+            0x00 => Ok(SingleFrame::new(&frame)?.data),
+            0x10 => {
+                // The adapter sends the flow control frames
+                let first_frame = FirstFrame::new(&frame)?;
+                let mut buffer = first_frame.data;
+                let mut index = 1;
+                while buffer.len() < first_frame.length {
+                    let frame = self.recv_frame(self.options.timeout)?;
+                    if frame[0] & 0xF0 != 0x20 || frame[0] & 0x0F != index {
+                        return Err(Error::InvalidFrame);
+                    }
+                    let len = cmp::min(first_frame.length - buffer.len(), frame.len() - 1);
+                    buffer.extend_from_slice(&frame[1..=len]);
+                    index = (index + 1) & 0x0F;
+                }
+                Ok(buffer)
+            },
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+
+    /// Sends a single frame. Returns `Error::Unsupported` for more than 7 bytes
+    /// as the adapter cannot send multi-frame messages.
+    fn send(&self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Err(Error::EmptyPacket);
+        }
+        if data.len() > 7 {
+            return Err(Error::Unsupported);
+        }
+        self.elm.configure(&self.options)?;
+
+        let line: String = data.iter().map(|b| format!("{:02X}", b)).collect();
+        self.elm.write_line(&line)
+    }
+
+    fn supports_multi_frame_send(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// A serial port that checks each written line against a script and
+    /// answers with the scripted reply
+    struct ScriptedSerial {
+        script: RefCell<VecDeque<(&'static str, &'static str)>>,
+        line: RefCell<Vec<u8>>,
+        output: RefCell<Vec<u8>>,
+    }
+
+    impl ScriptedSerial {
+        fn new(script: &[(&'static str, &'static str)]) -> ScriptedSerial {
+            ScriptedSerial {
+                script: RefCell::new(script.iter().cloned().collect()),
+                line: RefCell::new(Vec::new()),
+                output: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl SerialInterface for ScriptedSerial {
+        fn write_all(&self, data: &[u8]) -> Result<()> {
+            for &byte in data {
+                if byte != b'\r' {
+                    self.line.borrow_mut().push(byte);
+                    continue;
+                }
+                let line = String::from_utf8(self.line.borrow_mut().split_off(0)).unwrap();
+                let (expected, reply) = self.script.borrow_mut().pop_front().expect("unexpected write");
+                assert_eq!(line, expected);
+                self.output.borrow_mut().extend_from_slice(reply.as_bytes());
+            }
+            Ok(())
+        }
+
+        fn read(&self, _timeout: time::Duration) -> Result<Vec<u8>> {
+            Ok(self.output.borrow_mut().split_off(0))
+        }
+    }
+
+    const INIT: [(&str, &str); 7] = [
+        ("", ">"),
+        ("ATZ", "\r\rELM327 v1.5\r\r>"),
+        ("ATE0", "ATE0\rOK\r\r>"),
+        ("ATL0", "OK\r\r>"),
+        ("ATS0", "OK\r\r>"),
+        ("ATH1", "OK\r\r>"),
+        ("ATCAF1", "OK\r\r>"),
+    ];
+
+    const CONFIGURE: [(&str, &str); 7] = [
+        ("ATSP6", "OK\r\r>"),
+        ("ATSH7E0", "OK\r\r>"),
+        ("ATCRA7E8", "OK\r\r>"),
+        ("ATFCSH7E0", "OK\r\r>"),
+        ("ATFCSD300000", "OK\r\r>"),
+        ("ATFCSM1", "OK\r\r>"),
+        ("ATSTFA", "OK\r\r>"),
+    ];
+
+    #[test]
+    fn request_receives_a_multi_frame_response() {
+        let mut script = INIT.to_vec();
+        script.extend_from_slice(&CONFIGURE);
+        script.push(("0902", "7E8100B490201313233\r7E82134353637383900\r\r>"));
+        let elm = Rc::new(Elm327::new(Box::new(ScriptedSerial::new(&script))).unwrap());
+        assert_eq!(elm.version(), "ELM327 v1.5");
+
+        let isotp = Elm327Isotp::new(elm, Options::default()).unwrap();
+        assert_eq!(isotp.request(&[0x09, 0x02]).unwrap(), vec![0x49, 0x02, 0x01, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38]);
+    }
+
+    #[test]
+    fn send_over_seven_bytes_is_unsupported() {
+        let mut script = INIT.to_vec();
+        script.extend_from_slice(&CONFIGURE);
+        let elm = Rc::new(Elm327::new(Box::new(ScriptedSerial::new(&script))).unwrap());
+        let isotp = Elm327Isotp::new(elm, Options::default()).unwrap();
+        assert!(!isotp.supports_multi_frame_send());
+
+        // Nothing is written to the adapter
+        match isotp.send(&[0x36; 8]) {
+            Err(Error::Unsupported) => (),
+            res => panic!("expected Unsupported, got {:?}", res),
+        }
+    }
+}
@@ -1,17 +1,108 @@
 use std::time;
 use std::default::Default;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub mod can;
 
 pub use self::can::IsotpCan;
-use crate::error::{Error, Result};
+
+#[cfg(feature = "socketcan")]
+pub mod socket;
+#[cfg(feature = "socketcan")]
+pub use self::socket::IsotpSocket;
+
+#[cfg(feature = "elm327")]
+pub mod elm327;
+#[cfg(feature = "elm327")]
+pub use self::elm327::{Elm327, Elm327Isotp};
+use crate::{
+    protocols::can::{Filter, FrameFormat},
+    error::{Error, Result},
+};
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 
 use std::cmp;
 
+/// Largest message length that fits in the 12-bit length of a first frame
+pub const MAX_FF_DL_12BIT: u32 = 4095;
+
+/// Default largest message length accepted by receivers
+pub const DEFAULT_MAX_RX_LEN: usize = 0x10_0000;
+
+#[derive(Debug, Clone)]
 pub struct Options {
     pub source_id: u32,
     pub dest_id: u32,
+    /// Frame format of the source, destination and functional ids
+    pub format: FrameFormat,
+    /// Time to wait for the first or single frame of a received message
     pub timeout: time::Duration,
+    /// Maximum data length of transmitted CAN frames (TX_DL). 8 for classic CAN;
+    /// 12, 16, 20, 24, 32, 48 or 64 to send CAN FD frames.
+    pub tx_dl: usize,
+    /// Use bit rate switching for CAN FD frames
+    pub brs: bool,
+    /// Byte used to pad transmitted frames to the full frame length. If `None`,
+    /// classic CAN frames are sent with only the used bytes.
+    pub padding: Option<u8>,
+    pub addressing: Addressing,
+    /// Block size advertised in our flow control frames. 0 sends all
+    /// consecutive frames without waiting for further flow control.
+    pub rx_block_size: u8,
+    /// Minimum separation time between consecutive frames advertised in our
+    /// flow control frames
+    pub rx_separation_time: time::Duration,
+    /// Time allowed to transmit a frame (N_As). CAN interfaces send synchronously,
+    /// so a frame taking longer fails with `Error::Timeout` after the interface
+    /// returns; the send is not aborted.
+    pub n_as: time::Duration,
+    /// Time to wait for a flow control frame after sending a first frame or
+    /// the last frame of a block (N_Bs)
+    pub n_bs: time::Duration,
+    /// Time to wait for the next consecutive frame (N_Cr)
+    pub n_cr: time::Duration,
+    /// Maximum number of consecutive wait flow control frames accepted before
+    /// aborting the transmission (WFTmax). 0 accepts any number of wait frames.
+    pub wft_max: u8,
+    /// Additional id on which single frames are received, such as the 0x7DF
+    /// functional (broadcast) request id. Used by listeners.
+    pub functional_id: Option<u32>,
+    /// Largest message length accepted. Longer messages are refused with an
+    /// overflow flow control frame, as 32-bit lengths allow up to 4 GiB.
+    pub max_rx_len: usize,
+}
+
+/// ISO-TP addressing format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Addressing {
+    /// The CAN identifiers alone address the ECU
+    Normal,
+    /// The first data byte is the target address. `tx` is prepended to sent
+    /// frames and only received frames starting with `rx` are accepted.
+    Extended { tx: u8, rx: u8 },
+    /// The first data byte is the address extension in both directions
+    Mixed(u8),
+}
+
+impl Addressing {
+    /// Returns the byte prepended to transmitted frames
+    pub fn tx_prefix(&self) -> Option<u8> {
+        match *self {
+            Addressing::Normal => None,
+            Addressing::Extended { tx, .. } => Some(tx),
+            Addressing::Mixed(ae) => Some(ae),
+        }
+    }
+
+    /// Returns the byte received frames must start with
+    pub fn rx_prefix(&self) -> Option<u8> {
+        match *self {
+            Addressing::Normal => None,
+            Addressing::Extended { rx, .. } => Some(rx),
+            Addressing::Mixed(ae) => Some(ae),
+        }
+    }
 }
 
 impl Default for Options {
@@ -19,11 +110,47 @@ impl Default for Options {
         Options {
             source_id: 0x7e0,
             dest_id: 0x7e8,
+            format: FrameFormat::Standard,
             timeout: time::Duration::from_secs(1),
+            tx_dl: 8,
+            brs: false,
+            padding: Some(0),
+            addressing: Addressing::Normal,
+            rx_block_size: 0,
+            rx_separation_time: time::Duration::from_millis(0),
+            n_as: time::Duration::from_millis(1000),
+            n_bs: time::Duration::from_millis(1000),
+            n_cr: time::Duration::from_millis(1000),
+            wft_max: 0,
+            functional_id: None,
+            max_rx_len: DEFAULT_MAX_RX_LEN,
         }
     }
 }
 
+impl Options {
+    /// Returns options for a listener receiving requests on `request_id`
+    /// and answering on `response_id`
+    pub fn listener(request_id: u32, response_id: u32) -> Options {
+        Options {
+            source_id: response_id,
+            dest_id: request_id,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if frames are sent as CAN FD frames
+    pub fn fd(&self) -> bool {
+        self.tx_dl > 8
+    }
+
+    /// Returns the number of data bytes available to ISO-TP in transmitted frames
+    /// after the address extension
+    pub fn tx_data_len(&self) -> usize {
+        self.tx_dl - self.addressing.tx_prefix().map_or(0, |_| 1)
+    }
+}
+
 pub enum FrameType {
     Single,
     First,
@@ -47,8 +174,7 @@ pub struct FlowControlFrame {
 
 #[derive(Debug)]
 pub struct SingleFrame {
-    length: u8,
-    data: [u8; 7],
+    data: Vec<u8>,
 }
 
 impl SingleFrame {
@@ -60,125 +186,221 @@ impl SingleFrame {
             // Not a single frame
             return Err(Error::InvalidFrame);
         }
-        let len = cmp::min(7, frame[0] & 0x0F) as usize;
-        let mut data = [0; 7];
-        data[0..len].copy_from_slice(&frame[1..=len]);
+        let (offset, len) = if frame[0] & 0x0F == 0 && frame.len() > 8 {
+            // CAN FD escape sequence. The length is in the second byte
+            (2, frame[1] as usize)
+        } else {
+            // SF_DL above 7 requires the CAN FD escape sequence
+            let len = (frame[0] & 0x0F) as usize;
+            if len > 7 {
+                return Err(Error::InvalidFrame);
+            }
+            (1, len)
+        };
+        if frame.len() < offset + len {
+            return Err(Error::InvalidFrame);
+        }
         Ok(SingleFrame {
-            data,
-            length: len as u8,
+            data: frame[offset..offset + len].to_vec(),
         })
     }
 }
 
 #[derive(Debug)]
 pub struct FirstFrame {
-    length: u16,
-    data: [u8; 6],
+    length: usize,
+    data: Vec<u8>,
 }
 
 impl FirstFrame {
     fn new(frame: &[u8]) -> Result<FirstFrame> {
-        assert!(frame.len() <= 8);
-        if frame.len() < 2 {
+        // Frames using extended or mixed addressing carry one less data byte
+        if frame.len() < 7 {
             return Err(Error::InvalidFrame);
         }
         if frame[0] & 0xF0 != 0x10 {
             // Not a first frame
             return Err(Error::InvalidFrame);
         }
-        let length = ((frame[0] as u16 & 0x0F) << 8) | frame[1] as u16;
-        let data_length = cmp::min(frame.len() - 2, cmp::min(length as usize, 6));
-        let mut data = [0; 6];
-        data[..data_length].copy_from_slice(&frame[2..data_length+2]);
+        let mut length = ((frame[0] as usize & 0x0F) << 8) | frame[1] as usize;
+        let mut offset = 2;
+        if length == 0 {
+            // Messages longer than 4095 bytes have a 32-bit length after the escape sequence
+            length = BigEndian::read_u32(&frame[2..6]) as usize;
+            offset = 6;
+            if length <= MAX_FF_DL_12BIT as usize {
+                return Err(Error::InvalidFrame);
+            }
+        }
+        let data_length = cmp::min(frame.len() - offset, length);
         Ok(FirstFrame {
             length,
-            data,
+            data: frame[offset..data_length + offset].to_vec(),
         })
     }
 }
 
 pub struct ConsecutiveFrame {
     index: u8,
-    data: [u8; 7],
-    data_length: u8,
+    data: Vec<u8>,
 }
 
 #[derive(Debug)]
 pub struct Frame {
-    data: [u8; 8],
+    data: Vec<u8>,
 }
 
 pub trait IsotpInterface {
     /// Receives an ISO-TP packet
     fn recv(&self) -> Result<Vec<u8>>;
 
+    /// Receives an ISO-TP packet, waiting up to `timeout` instead of the
+    /// configured timeout for it to start.
+    ///
+    /// The default implementation retries `recv`, which waits the configured
+    /// timeout, and does not start a retry that would end after `timeout`.
+    /// Interfaces that can wait for an arbitrary time should override it.
+    fn recv_timeout(&self, timeout: time::Duration) -> Result<Vec<u8>> {
+        let start_time = time::Instant::now();
+        loop {
+            let attempt_time = time::Instant::now();
+            match self.recv() {
+                Err(Error::Timeout) => {
+                    // The next attempt is expected to wait as long as this one
+                    if start_time.elapsed() + attempt_time.elapsed() > timeout {
+                        return Err(Error::Timeout);
+                    }
+                },
+                res => return res,
+            }
+        }
+    }
+
     /// Sends an ISO-TP packet
     fn send(&self, data: &[u8]) -> Result<()>;
 
+    /// Returns false if the interface can only send single frames, so requests
+    /// over 7 bytes fail with `Error::Unsupported`
+    fn supports_multi_frame_send(&self) -> bool {
+        true
+    }
+
     fn request(&self, request: &[u8]) -> Result<Vec<u8>> {
         self.send(&request)?;
         self.recv()
     }
-    
 
+    /// Sends `data` as a single frame to the functional (broadcast) id `request_id`
+    /// and collects the single frame responses received within `window` from ids
+    /// matching the `responses` filter. Returns the CAN id and data of each response.
+    ///
+    /// Interfaces bound to a single pair of ids return `Error::Unsupported`.
+    fn functional_request(&self, _request_id: u32, _data: &[u8], _responses: Filter, _window: time::Duration) -> Result<Vec<(u32, Vec<u8>)>> {
+        Err(Error::Unsupported)
+    }
+}
+
+/// Receives messages and answers them until `running` is cleared. `handler`
+/// returns the response to a message, or `None` to not respond.
+pub fn listen<F>(interface: &IsotpInterface, running: &AtomicBool, mut handler: F) -> Result<()>
+        where F: FnMut(&[u8]) -> Option<Vec<u8>> {
+    while running.load(Ordering::Relaxed) {
+        let message = match interface.recv() {
+            // Timeouts are expected while no one is sending
+            Err(Error::Timeout) => continue,
+            res => res?,
+        };
+        if let Some(response) = handler(&message) {
+            interface.send(&response)?;
+        }
+    }
+    Ok(())
 }
 
 fn duration_to_st(duration: time::Duration) -> u8 {
-    if duration.subsec_micros() <= 900 && duration.subsec_micros() >= 100 {
-        return (cmp::max(duration.subsec_micros() / 100, 1) + 0xF0) as u8;
+    if duration.as_secs() == 0 && duration.subsec_micros() < 1000 {
+        if duration.subsec_micros() >= 100 {
+            // 0xF1 - 0xF9 encode 100us - 900us
+            return (duration.subsec_micros() / 100) as u8 + 0xF0;
+        }
+        return 0;
+    }
+    cmp::min(duration.as_secs() * 1000 + duration.subsec_millis() as u64, 127) as u8
+}
+
+fn st_to_duration(st: u8) -> time::Duration {
+    match st {
+        0..=0x7F => time::Duration::from_millis(st as u64),
+        0xF1..=0xF9 => time::Duration::from_micros((st - 0xF0) as u64 * 100),
+        // Reserved values are interpreted as the maximum separation time
+        _ => time::Duration::from_millis(127),
     }
-    duration.subsec_micros() as u8
 }
 
 impl Frame {
-    fn new(data: [u8; 8]) -> Frame {
+    fn new(data: Vec<u8>) -> Frame {
         Frame {data}
     }
 
-    fn from_single_data(data: &[u8]) -> Frame {
-        assert!(data.len() <= 7);
+    /// Creates a single frame. If `escape` is set, the CAN FD escape sequence
+    /// is used, which is required for frames longer than 8 bytes.
+    fn from_single_data(data: &[u8], escape: bool) -> Frame {
+        assert!(data.len() <= 62);
+        assert!(escape || data.len() <= 7);
 
-        let mut d = [0; 8];
-        d[0] = data.len() as u8; // Single Frame id (0 << 4) | size
-        d[1..=data.len()].copy_from_slice(&data);
+        let mut d = Vec::with_capacity(data.len() + 2);
+        if !escape {
+            d.push(data.len() as u8); // Single Frame id (0 << 4) | size
+        } else {
+            d.push(0);
+            d.push(data.len() as u8);
+        }
+        d.extend_from_slice(&data);
         Frame {
             data: d,
         }
     }
 
     fn from_flow(flow: FlowControlFrame) -> Frame {
-        let mut frame = Frame {data: [0; 8]};
+        let mut frame = Frame {data: vec![0; 3]};
         frame.data[0] = 0x30 | (flow.flag as u8);
         frame.data[1] = flow.block_size;
         frame.data[2] = duration_to_st(flow.separation_time);
         frame
     }
 
-    fn from_first_data(data: &[u8], size: u16) -> Frame {
-        assert!(data.len() <= 6);
+    /// Creates a first frame. Sizes above 4095 bytes use the escape sequence
+    /// with a 32-bit length.
+    fn from_first_data(data: &[u8], size: u32) -> Frame {
+        assert!(data.len() <= 62);
 
-        let mut d = [0; 8];
-        d[0] = (0x10 | ((size & 0xF00) >> 8)) as u8;
-        d[1] = (size & 0xFF) as u8;
-        d[2..data.len() + 2].copy_from_slice(&data);
+        let mut d = Vec::with_capacity(data.len() + 6);
+        if size <= MAX_FF_DL_12BIT {
+            d.push((0x10 | ((size & 0xF00) >> 8)) as u8);
+            d.push((size & 0xFF) as u8);
+        } else {
+            d.extend_from_slice(&[0x10, 0x00]);
+            d.write_u32::<BigEndian>(size).unwrap();
+        }
+        d.extend_from_slice(&data);
         Frame {
             data: d
         }
     }
 
     fn from_consec_data(data: &[u8], index: u8) -> Frame {
-        assert!(data.len() <= 7);
+        assert!(data.len() <= 63);
 
-        let mut d = [0; 8];
-        d[0] = ((0x20) | (index & 0xF)) as u8;
-        d[1..=data.len()].copy_from_slice(&data);
+        let mut d = Vec::with_capacity(data.len() + 1);
+        d.push(((0x20) | (index & 0xF)) as u8);
+        d.extend_from_slice(&data);
         Frame {
             data: d
         }
     }
 
     pub fn from_single(frame: &SingleFrame) -> Frame {
-        Self::from_single_data(&frame.data[..frame.length as usize])
+        Self::from_single_data(&frame.data, frame.data.len() > 7)
     }
 
     fn get_type(&self) -> Option<FrameType> {
@@ -196,4 +418,59 @@ impl Frame {
             _ => None,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Interface whose `recv` always times out after `wait`
+    struct Silent {
+        wait: time::Duration,
+    }
+
+    impl IsotpInterface for Silent {
+        fn recv(&self) -> Result<Vec<u8>> {
+            thread::sleep(self.wait);
+            Err(Error::Timeout)
+        }
+
+        fn send(&self, _data: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn default_recv_timeout_does_not_overrun() {
+        let interface = Silent { wait: time::Duration::from_millis(40) };
+        let start_time = time::Instant::now();
+        match interface.recv_timeout(time::Duration::from_millis(100)) {
+            Err(Error::Timeout) => (),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+        assert!(start_time.elapsed() <= time::Duration::from_millis(100), "waited {:?}", start_time.elapsed());
+    }
+
+    #[test]
+    fn classic_single_frames_are_limited_to_7_bytes() {
+        assert_eq!(SingleFrame::new(&[0x07, 1, 2, 3, 4, 5, 6, 7]).unwrap().data, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(SingleFrame::new(&[0x02, 0x50, 0x85, 0, 0, 0, 0, 0]).unwrap().data, vec![0x50, 0x85]);
+        for &sf_dl in &[0x08, 0x0F] {
+            match SingleFrame::new(&[sf_dl, 1, 2, 3, 4, 5, 6, 7]) {
+                Err(Error::InvalidFrame) => (),
+                res => panic!("SF_DL {} parsed as {:?}", sf_dl, res),
+            }
+        }
+    }
+
+    #[test]
+    fn functional_requests_are_unsupported_by_default() {
+        let interface: &IsotpInterface = &Silent { wait: time::Duration::from_millis(0) };
+        let responses = Filter::new(0x7E8, 0x7F8, FrameFormat::Standard);
+        match interface.functional_request(0x7DF, &[0x3E, 0x80], responses, time::Duration::from_millis(10)) {
+            Err(Error::Unsupported) => (),
+            res => panic!("expected Unsupported, got {:?}", res),
+        }
+    }
+}
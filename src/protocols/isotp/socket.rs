@@ -0,0 +1,306 @@
+use crate::{
+    protocols::can::{
+        FrameFormat,
+        socketcan::{CanAddr, AF_CAN, PF_CAN, SOL_CAN_BASE, CAN_EFF_FLAG},
+    },
+    error::{Error, Result},
+};
+
+use super::{IsotpInterface, Options, Addressing, duration_to_st};
+use std::ffi;
+use std::io;
+use std::mem;
+use std::time;
+
+const CAN_ISOTP: libc::c_int = 6;
+const SOL_CAN_ISOTP: libc::c_int = SOL_CAN_BASE + CAN_ISOTP;
+
+const CAN_ISOTP_OPTS: libc::c_int = 1;
+const CAN_ISOTP_RECV_FC: libc::c_int = 2;
+const CAN_ISOTP_LL_OPTS: libc::c_int = 5;
+
+const CAN_ISOTP_EXTEND_ADDR: u32 = 0x001;
+const CAN_ISOTP_TX_PADDING: u32 = 0x004;
+const CAN_ISOTP_RX_EXT_ADDR: u32 = 0x200;
+
+const CANFD_MTU: u8 = 72;
+const CANFD_BRS: u8 = 0x01;
+
+/// Receive buffer size used if the kernel does not report the message length
+const DEFAULT_RECV_LEN: usize = 8300;
+
+#[repr(C)]
+struct IsotpOptions {
+    flags: u32,
+    frame_txtime: u32,
+    ext_address: u8,
+    txpad_content: u8,
+    rxpad_content: u8,
+    rx_ext_address: u8,
+}
+
+#[repr(C)]
+struct IsotpFlowControlOptions {
+    bs: u8,
+    stmin: u8,
+    wftmax: u8,
+}
+
+#[repr(C)]
+struct IsotpLinkLayerOptions {
+    mtu: u8,
+    tx_dl: u8,
+    tx_flags: u8,
+}
+
+/// ISO-TP interface using the Linux kernel's CAN_ISOTP sockets. Segmentation,
+/// flow control and separation times are handled by the kernel.
+pub struct IsotpSocket {
+    fd: libc::c_int,
+    options: Options,
+}
+
+fn can_id(id: u32, format: FrameFormat) -> u32 {
+    match format {
+        FrameFormat::Standard => id,
+        FrameFormat::Extended => id | CAN_EFF_FLAG,
+    }
+}
+
+fn setsockopt<T>(fd: libc::c_int, option: libc::c_int, value: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(fd, SOL_CAN_ISOTP, option,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t)
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Maps errors reported by the kernel's ISO-TP state machine
+fn map_error(err: io::Error) -> Error {
+    match err.raw_os_error() {
+        // N_Bs or N_Cr timeout
+        Some(libc::ECOMM) | Some(libc::ETIMEDOUT) => Error::Timeout,
+        Some(libc::EILSEQ) => Error::InvalidSequence,
+        // The receiver sent an overflow flow control frame
+        Some(libc::EMSGSIZE) => Error::Overflow,
+        // Invalid flow control frame
+        Some(libc::EBADMSG) => Error::InvalidFrame,
+        _ => Error::Io(err),
+    }
+}
+
+impl IsotpSocket {
+    /// Opens an ISO-TP socket on the interface `ifname`
+    pub fn open(ifname: &str, options: Options) -> io::Result<IsotpSocket> {
+        let c_string = ffi::CString::new(ifname)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "interface name contains a NUL byte"))?;
+        let if_index = unsafe { libc::if_nametoindex(c_string.as_ptr()) };
+        if if_index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        IsotpSocket::open_idx(if_index, options)
+    }
+
+    /// Opens an ISO-TP socket on the interface with the index `if_index`.
+    /// Fails if the kernel does not support CAN_ISOTP or `functional_id` is set.
+    pub fn open_idx(if_index: libc::c_uint, options: Options) -> io::Result<IsotpSocket> {
+        if options.functional_id.is_some() {
+            // A socket is bound to a single receive id
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "functional ids are not supported by CAN_ISOTP sockets"));
+        }
+        let fd = unsafe { libc::socket(PF_CAN, libc::SOCK_DGRAM, CAN_ISOTP) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Closes the socket if configuring fails
+        let socket = IsotpSocket { fd, options };
+
+        let mut flags = 0;
+        if socket.options.padding.is_some() {
+            flags |= CAN_ISOTP_TX_PADDING;
+        }
+        // With mixed addressing the kernel uses ext_address in both directions
+        let (ext_address, rx_ext_address) = match socket.options.addressing {
+            Addressing::Normal => (0, 0),
+            Addressing::Extended { tx, rx } => {
+                flags |= CAN_ISOTP_EXTEND_ADDR | CAN_ISOTP_RX_EXT_ADDR;
+                (tx, rx)
+            },
+            Addressing::Mixed(ae) => {
+                flags |= CAN_ISOTP_EXTEND_ADDR;
+                (ae, 0)
+            },
+        };
+        setsockopt(fd, CAN_ISOTP_OPTS, &IsotpOptions {
+            flags,
+            frame_txtime: 0,
+            ext_address,
+            txpad_content: socket.options.padding.unwrap_or(0),
+            rxpad_content: 0,
+            rx_ext_address,
+        })?;
+
+        setsockopt(fd, CAN_ISOTP_RECV_FC, &IsotpFlowControlOptions {
+            bs: socket.options.rx_block_size,
+            stmin: duration_to_st(socket.options.rx_separation_time),
+            wftmax: socket.options.wft_max,
+        })?;
+
+        if socket.options.fd() {
+            setsockopt(fd, CAN_ISOTP_LL_OPTS, &IsotpLinkLayerOptions {
+                mtu: CANFD_MTU,
+                tx_dl: socket.options.tx_dl as u8,
+                tx_flags: if socket.options.brs { CANFD_BRS } else { 0 },
+            })?;
+        }
+
+        let addr = CanAddr {
+            can_family: AF_CAN as libc::c_short,
+            if_index: if_index as libc::c_int,
+            rx_id: can_id(socket.options.dest_id, socket.options.format),
+            tx_id: can_id(socket.options.source_id, socket.options.format),
+        };
+        let res = unsafe {
+            libc::bind(fd, &addr as *const CanAddr as *const libc::sockaddr, mem::size_of::<CanAddr>() as u32)
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+impl Drop for IsotpSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl IsotpInterface for IsotpSocket {
+    fn recv(&self) -> Result<Vec<u8>> {
+        self.recv_timeout(self.options.timeout)
+    }
+
+    fn recv_timeout(&self, timeout: time::Duration) -> Result<Vec<u8>> {
+        let start_time = time::Instant::now();
+        loop {
+            let remaining = timeout.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            // Round up so sub-millisecond timeouts still wait
+            let millis = (remaining.as_secs() * 1000 + ((remaining.subsec_nanos() + 999_999) / 1_000_000) as u64)
+                .min(libc::c_int::max_value() as u64) as libc::c_int;
+
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let res = unsafe { libc::poll(&mut pollfd, 1, millis) };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::Io(err));
+            }
+            if res == 0 {
+                return Err(Error::Timeout);
+            }
+
+            // Messages may use 32-bit lengths. Peek at the length to size the buffer.
+            let len = unsafe {
+                libc::recv(self.fd, std::ptr::null_mut(), 0, libc::MSG_PEEK | libc::MSG_TRUNC)
+            };
+            let len = if len > 0 { len as usize } else { DEFAULT_RECV_LEN };
+
+            let mut buffer = vec![0; len];
+            let res = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                return Err(map_error(err));
+            }
+            buffer.truncate(res as usize);
+            return Ok(buffer);
+        }
+    }
+
+    /// Sends an ISO-TP packet. The largest supported length depends on the
+    /// kernel's max_pdu_size.
+    fn send(&self, data: &[u8]) -> Result<()> {
+        let res = unsafe { libc::write(self.fd, data.as_ptr() as *const libc::c_void, data.len()) };
+        if res < 0 {
+            return Err(map_error(io::Error::last_os_error()));
+        }
+        if res as usize != data.len() {
+            return Err(Error::IncompleteWrite);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn option_layouts_match_the_kernel() {
+        // struct can_isotp_options
+        assert_eq!(mem::size_of::<IsotpOptions>(), 12);
+        assert_eq!(mem::align_of::<IsotpOptions>(), 4);
+        // struct can_isotp_fc_options
+        assert_eq!(mem::size_of::<IsotpFlowControlOptions>(), 3);
+        assert_eq!(mem::align_of::<IsotpFlowControlOptions>(), 1);
+        // struct can_isotp_ll_options
+        assert_eq!(mem::size_of::<IsotpLinkLayerOptions>(), 3);
+        assert_eq!(mem::align_of::<IsotpLinkLayerOptions>(), 1);
+    }
+
+    #[test]
+    fn kernel_errors_are_mapped() {
+        let map = |errno| map_error(io::Error::from_raw_os_error(errno));
+        match (map(libc::ECOMM), map(libc::ETIMEDOUT)) {
+            (Error::Timeout, Error::Timeout) => (),
+            other => panic!("unexpected errors {:?}", other),
+        }
+        match (map(libc::EMSGSIZE), map(libc::EILSEQ), map(libc::EBADMSG)) {
+            (Error::Overflow, Error::InvalidSequence, Error::InvalidFrame) => (),
+            other => panic!("unexpected errors {:?}", other),
+        }
+        match map(libc::EIO) {
+            Error::Io(ref err) if err.raw_os_error() == Some(libc::EIO) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn interface_names_with_nul_are_rejected() {
+        match IsotpSocket::open("vcan\00", Options::default()) {
+            Err(ref err) if err.kind() == io::ErrorKind::InvalidInput => (),
+            other => panic!("unexpected result {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn round_trip_over_vcan() {
+        let tester = IsotpSocket::open("vcan0", Options::default());
+        let server = IsotpSocket::open("vcan0", Options::listener(0x7E0, 0x7E8));
+        let (tester, server) = match (tester, server) {
+            (Ok(tester), Ok(server)) => (tester, server),
+            // Requires a vcan0 interface and the can-isotp module
+            (Err(err), _) | (_, Err(err)) => {
+                eprintln!("skipping CAN_ISOTP test: {}", err);
+                return;
+            },
+        };
+
+        let request: Vec<u8> = (0..100).map(|i| i as u8).collect();
+        tester.send(&request).unwrap();
+        assert_eq!(server.recv().unwrap(), request);
+        server.send(&[0x50, 0x85]).unwrap();
+        assert_eq!(tester.recv().unwrap(), vec![0x50, 0x85]);
+    }
+}
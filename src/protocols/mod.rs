@@ -1,3 +1,5 @@
 pub mod can;
 pub mod isotp;
-pub mod uds;
\ No newline at end of file
+pub mod uds;
+#[cfg(any(feature = "slcan", feature = "elm327"))]
+pub mod serial;
\ No newline at end of file
@@ -0,0 +1,126 @@
+use std::ffi;
+use std::io;
+use std::mem;
+use std::time;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+
+/// A byte stream to a serial adapter
+pub trait SerialInterface {
+    /// Writes all data to the port
+    fn write_all(&self, data: &[u8]) -> Result<()>;
+
+    /// Reads the available data, waiting up to `timeout` for data to arrive.
+    /// Returns an empty buffer if the timeout elapsed.
+    fn read(&self, timeout: time::Duration) -> Result<Vec<u8>>;
+}
+
+/// A serial device in raw mode
+pub struct SerialPort {
+    fd: libc::c_int,
+}
+
+fn speed_from_baudrate(baudrate: u32) -> Result<libc::speed_t> {
+    Ok(match baudrate {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115_200 => libc::B115200,
+        230_400 => libc::B230400,
+        460_800 => libc::B460800,
+        500_000 => libc::B500000,
+        921_600 => libc::B921600,
+        1_000_000 => libc::B1000000,
+        2_000_000 => libc::B2000000,
+        _ => return Err(Error::Unsupported),
+    })
+}
+
+impl SerialPort {
+    /// Opens the serial device at `path` with the speed `baudrate`
+    pub fn open<P: AsRef<Path>>(path: P, baudrate: u32) -> Result<SerialPort> {
+        let speed = speed_from_baudrate(baudrate)?;
+
+        let c_path = ffi::CString::new(path.as_ref().as_os_str().as_bytes()).map_err(|_| Error::InvalidConnection)?;
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY) };
+        if fd == -1 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+        // Close the descriptor if configuring fails
+        let port = SerialPort { fd };
+
+        unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) == -1 {
+                return Err(Error::Io(io::Error::last_os_error()));
+            }
+            libc::cfmakeraw(&mut termios);
+            libc::cfsetspeed(&mut termios, speed);
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) == -1 {
+                return Err(Error::Io(io::Error::last_os_error()));
+            }
+            libc::tcflush(fd, libc::TCIOFLUSH);
+        }
+        Ok(port)
+    }
+}
+
+impl Drop for SerialPort {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl SerialInterface for SerialPort {
+    fn write_all(&self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let res = unsafe { libc::write(self.fd, data.as_ptr() as *const libc::c_void, data.len()) };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::Io(err));
+            }
+            data = &data[res as usize..];
+        }
+        Ok(())
+    }
+
+    fn read(&self, timeout: time::Duration) -> Result<Vec<u8>> {
+        // Round up so sub-millisecond timeouts still wait
+        let millis = (timeout.as_secs() * 1000 + ((timeout.subsec_nanos() + 999_999) / 1_000_000) as u64)
+            .min(libc::c_int::max_value() as u64) as libc::c_int;
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let res = unsafe { libc::poll(&mut pollfd, 1, millis) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(Error::Io(err));
+        }
+        if res == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut data = [0; 256];
+        let res = unsafe { libc::read(self.fd, data.as_mut_ptr() as *mut libc::c_void, data.len()) };
+        if res < 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+        if res == 0 {
+            // The device was closed
+            return Err(Error::Read);
+        }
+        Ok(data[..res as usize].to_vec())
+    }
+}
@@ -2,51 +2,192 @@ use crate::{
     protocols::isotp::IsotpInterface,
     error::{Error, Result},
 };
-use super::{UdsInterface, UDS_NRES_RCRRP};
+use super::{UdsInterface, UDS_RES_NEGATIVE, UDS_NRES_RCRRP, UDS_REQ_TESTERPRESENT, UDS_SUPPRESS_RESPONSE};
 
+use std::cmp;
 use std::rc::Rc;
+use std::time;
+
+/// Default time to wait for a response after a response pending code (P2*)
+const DEFAULT_P2_STAR: time::Duration = time::Duration::from_millis(5000);
 
 pub struct UdsIsotp {
     interface: Rc<IsotpInterface>,
+    p2_star: time::Duration,
+    keep_alive: Option<time::Duration>,
 }
 
 impl UdsIsotp {
     pub fn new(interface: Rc<IsotpInterface>) -> UdsIsotp {
-        UdsIsotp {interface}
+        UdsIsotp {interface, p2_star: DEFAULT_P2_STAR, keep_alive: None}
+    }
+
+    /// Sends a suppressed-response TesterPresent every `interval` while waiting
+    /// for a pending response, e.g. during an erase routine
+    pub fn with_keep_alive(mut self, interval: time::Duration) -> UdsIsotp {
+        self.keep_alive = Some(interval);
+        self
+    }
+
+    /// Sets the time to wait for a response after the server responds
+    /// with requestCorrectlyReceivedResponsePending (P2*)
+    pub fn with_p2_star(mut self, p2_star: time::Duration) -> UdsIsotp {
+        self.p2_star = p2_star;
+        self
+    }
+}
+
+impl UdsIsotp {
+    /// Waits up to P2* for the response after a response pending code
+    fn recv_pending(&self) -> Result<Vec<u8>> {
+        let interval = match self.keep_alive {
+            Some(interval) => interval,
+            None => return self.interface.recv_timeout(self.p2_star),
+        };
+        let start_time = time::Instant::now();
+        loop {
+            let remaining = self.p2_star.checked_sub(start_time.elapsed()).ok_or(Error::Timeout)?;
+            match self.interface.recv_timeout(cmp::min(remaining, interval)) {
+                Err(Error::Timeout) if start_time.elapsed() < self.p2_star => {
+                    self.send_request(UDS_REQ_TESTERPRESENT, &[UDS_SUPPRESS_RESPONSE])?;
+                },
+                res => return res,
+            }
+        }
     }
 }
 
 impl UdsInterface for UdsIsotp {
-    fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>> {
-        let mut v = Vec::new();
+    /// Sends a request without waiting for a response
+    fn send_request(&self, request_sid: u8, data: &[u8]) -> Result<()> {
+        let mut v = Vec::with_capacity(data.len() + 1);
         v.push(request_sid);
         v.extend_from_slice(&data);
 
-        self.interface.send(&v)?;
+        self.interface.send(&v)
+    }
+
+    fn discard_responses(&self) -> Result<()> {
+        loop {
+            match self.interface.recv_timeout(time::Duration::from_millis(0)) {
+                Ok(_) => (),
+                Err(Error::Timeout) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>> {
+        self.send_request(request_sid, data)?;
         // Receive packets until we get a non-response-pending packet
+        let mut pending = false;
         loop {
-            let response = self.interface.recv()?;
+            let response = if pending {
+                self.recv_pending()?
+            } else {
+                self.interface.recv()?
+            };
             if response.is_empty() {
                 return Err(Error::InvalidPacket);
             }
 
-            if response[0] == 0x7F {
-                // Negative code
-                if response.len() > 1 {
-                    if response[1] == UDS_NRES_RCRRP {
-                        // Request correctly received, response pending
-                        continue;
-                    }
-                    return Err(Error::NegativeResponse(response[1]));
+            if response[0] == UDS_RES_NEGATIVE {
+                // Negative responses contain the request SID and the response code
+                if response.len() < 3 {
+                    return Err(Error::InvalidPacket);
+                }
+                if response[1] != request_sid {
+                    // A late negative response to an earlier request, e.g. one sent
+                    // with the suppressPosRspMsgIndicationBit
+                    continue;
                 }
-                return Err(Error::NegativeResponse(0));
+                if response[2] == UDS_NRES_RCRRP {
+                    // Request correctly received, response pending
+                    pending = true;
+                    continue;
+                }
+                return Err(Error::NegativeResponse(request_sid, response[2].into()));
             }
 
-            if response[0] != request_sid + 0x40 {
+            if response[0] != request_sid.wrapping_add(0x40) {
+                // Responses to a TesterPresent sent while the response was pending
+                if response[0] == UDS_REQ_TESTERPRESENT + 0x40 {
+                    continue;
+                }
                 return Err(Error::InvalidPacket);
             }
 
            return Ok(response[1..].to_vec())
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::{
+        can::VirtualCanBus,
+        isotp::{IsotpCan, Options},
+        uds::UDS_REQ_ROUTINECONTROL,
+    };
+    use std::thread;
+
+    fn server_options() -> Options {
+        Options::listener(0x7E0, 0x7E8)
+    }
+
+    #[test]
+    fn pending_responses_keep_the_session_alive() {
+        let bus = VirtualCanBus::new();
+        let can = bus.open();
+        let server = thread::spawn(move || {
+            let server = IsotpCan::new(Rc::new(can), server_options());
+            assert_eq!(server.recv().unwrap()[0], UDS_REQ_ROUTINECONTROL);
+            server.send(&[UDS_RES_NEGATIVE, UDS_REQ_ROUTINECONTROL, UDS_NRES_RCRRP]).unwrap();
+
+            // The routine runs for 100ms
+            let start_time = time::Instant::now();
+            let mut tester_present = 0;
+            while let Some(remaining) = time::Duration::from_millis(100).checked_sub(start_time.elapsed()) {
+                match server.recv_timeout(remaining) {
+                    Ok(request) => {
+                        assert_eq!(request, vec![UDS_REQ_TESTERPRESENT, UDS_SUPPRESS_RESPONSE]);
+                        tester_present += 1;
+                    },
+                    Err(Error::Timeout) => break,
+                    Err(err) => panic!("{}", err),
+                }
+            }
+            server.send(&[UDS_REQ_ROUTINECONTROL + 0x40, 0x01, 0xFF, 0x00]).unwrap();
+            tester_present
+        });
+
+        let tester = UdsIsotp::new(Rc::new(IsotpCan::new(Rc::new(bus.open()), Options::default())))
+            .with_keep_alive(time::Duration::from_millis(20));
+        assert_eq!(tester.routine_control(0x01, 0xFF00, &[]).unwrap(), Vec::<u8>::new());
+        assert!(server.join().unwrap() >= 3);
+    }
+
+    #[test]
+    fn suppressed_requests_do_not_wait_and_late_negative_responses_are_skipped() {
+        let bus = VirtualCanBus::new();
+        let can = bus.open();
+        let server = thread::spawn(move || {
+            let server = IsotpCan::new(Rc::new(can), server_options());
+            // The server does not support the sub-function and responds despite the suppress bit
+            assert_eq!(server.recv().unwrap(), vec![UDS_REQ_TESTERPRESENT, UDS_SUPPRESS_RESPONSE]);
+            server.send(&[UDS_RES_NEGATIVE, UDS_REQ_TESTERPRESENT, 0x12]).unwrap();
+            assert_eq!(server.recv().unwrap(), vec![0x22, 0xF1, 0x90]);
+            server.send(&[0x62, 0xF1, 0x90, 0x01]).unwrap();
+        });
+
+        let tester = UdsIsotp::new(Rc::new(IsotpCan::new(Rc::new(bus.open()), Options::default())));
+        let start_time = time::Instant::now();
+        tester.tester_present(true).unwrap();
+        assert!(start_time.elapsed() < time::Duration::from_millis(100));
+        // Let the negative response arrive before the next request
+        thread::sleep(time::Duration::from_millis(20));
+        assert_eq!(tester.read_data_by_identifier(0xF190).unwrap(), vec![0x01]);
+        server.join().unwrap();
+    }
+}
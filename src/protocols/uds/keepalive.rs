@@ -0,0 +1,130 @@
+use crate::error::Result;
+use super::UdsInterface;
+
+use std::cell::Cell;
+use std::cmp;
+use std::rc::Rc;
+use std::thread;
+use std::time;
+
+/// Default time the link may be idle before TesterPresent is sent. Servers
+/// return to the default session after S3 (5 seconds) without requests.
+pub const DEFAULT_S3: time::Duration = time::Duration::from_millis(2000);
+
+/// Wraps a UDS interface to keep non-default sessions active. `poll` sends a
+/// suppressed-response TesterPresent if no request was sent within the S3 interval.
+/// It is called before each request, so requests after an idle gap such as a slow
+/// progress callback are preceded by TesterPresent. Long response pending waits
+/// are covered by `UdsIsotp::with_keep_alive`.
+pub struct UdsKeepAlive {
+    interface: Rc<UdsInterface>,
+    interval: time::Duration,
+    last_request: Cell<time::Instant>,
+}
+
+impl UdsKeepAlive {
+    pub fn new(interface: Rc<UdsInterface>, interval: time::Duration) -> UdsKeepAlive {
+        UdsKeepAlive {
+            interface,
+            interval,
+            last_request: Cell::new(time::Instant::now()),
+        }
+    }
+
+    /// Sets the idle interval after which TesterPresent is sent
+    pub fn set_interval(&mut self, interval: time::Duration) {
+        self.interval = interval;
+    }
+
+    /// Sends TesterPresent if the link has been idle for the interval
+    pub fn poll(&self) -> Result<()> {
+        if self.last_request.get().elapsed() >= self.interval {
+            let res = self.interface.tester_present(true);
+            self.last_request.set(time::Instant::now());
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Sleeps for `duration` while keeping the session active
+    pub fn sleep(&self, duration: time::Duration) -> Result<()> {
+        let start_time = time::Instant::now();
+        while let Some(remaining) = duration.checked_sub(start_time.elapsed()) {
+            self.poll()?;
+            let until_poll = self.interval.checked_sub(self.last_request.get().elapsed())
+                .unwrap_or_else(|| time::Duration::from_millis(0));
+            thread::sleep(cmp::min(remaining, until_poll));
+        }
+        Ok(())
+    }
+}
+
+impl UdsInterface for UdsKeepAlive {
+    fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>> {
+        self.poll()?;
+        let response = self.interface.request(request_sid, data);
+        self.last_request.set(time::Instant::now());
+        response
+    }
+
+    fn send_request(&self, request_sid: u8, data: &[u8]) -> Result<()> {
+        self.poll()?;
+        let res = self.interface.send_request(request_sid, data);
+        self.last_request.set(time::Instant::now());
+        res
+    }
+
+    fn discard_responses(&self) -> Result<()> {
+        self.interface.discard_responses()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::can::{CanInterface, VirtualCan, VirtualCanBus};
+    use crate::simulator::{testing, mazda::Mazda1Ecu};
+
+    /// Counts the suppressed TesterPresent requests received by `monitor`
+    fn count_tester_present(monitor: &VirtualCan) -> usize {
+        let mut count = 0;
+        while let Some(msg) = monitor.try_recv().unwrap() {
+            if msg.id == 0x7E0 && msg.data[..3] == [0x02, 0x3E, 0x80] {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn sleep_sends_tester_present() {
+        let bus = VirtualCanBus::new();
+        let server = testing::spawn(&bus, Mazda1Ecu::new("MazdA", vec![0; 0x100], 0, 0x100).unwrap().with_pid(0xF190, &[0x01]));
+        let monitor = bus.open();
+        let keep_alive = UdsKeepAlive::new(testing::tester(bus.open()), time::Duration::from_millis(20));
+
+        keep_alive.sleep(time::Duration::from_millis(110)).unwrap();
+        assert!(count_tester_present(&monitor) >= 4);
+        // The server did not answer the suppressed requests
+        assert_eq!(keep_alive.read_data_by_identifier(0xF190).unwrap(), vec![0x01]);
+        server.stop();
+    }
+
+    #[test]
+    fn requests_after_an_idle_gap_send_tester_present_first() {
+        let bus = VirtualCanBus::new();
+        let server = testing::spawn(&bus, Mazda1Ecu::new("MazdA", vec![0; 0x100], 0, 0x100).unwrap().with_pid(0xF190, &[0x01]));
+        let monitor = bus.open();
+        let keep_alive = UdsKeepAlive::new(testing::tester(bus.open()), time::Duration::from_millis(20));
+
+        keep_alive.read_data_by_identifier(0xF190).unwrap();
+        assert_eq!(count_tester_present(&monitor), 0);
+
+        // e.g. a slow progress callback
+        thread::sleep(time::Duration::from_millis(30));
+        keep_alive.read_data_by_identifier(0xF190).unwrap();
+        let first = monitor.try_recv().unwrap().unwrap();
+        assert_eq!(first.data[..3], [0x02, 0x3E, 0x80]);
+        server.stop();
+    }
+}
@@ -1,15 +1,24 @@
 pub mod isotp;
+pub mod keepalive;
 
 pub use self::isotp::UdsIsotp;
+pub use self::keepalive::UdsKeepAlive;
 
 use crate::error::{Error, Result};
 
 use byteorder::{BigEndian, WriteBytesExt};
 
+use std::cmp;
+use std::fmt;
+
 pub struct Response {
     pub data: Vec<u8>,
 }
 
+/// Largest block of service data that fits with the SID in an ISO-TP message
+/// with a 12-bit length. Used for TransferData and ReadMemoryByAddress blocks.
+pub const DEFAULT_BLOCK_SIZE: usize = super::isotp::MAX_FF_DL_12BIT as usize - 1;
+
 // Request SIDs
 pub const UDS_REQ_SESSION: u8 = 0x10;
 pub const UDS_REQ_SECURITY: u8 = 0x27;
@@ -18,14 +27,281 @@ pub const UDS_REQ_REQUESTDOWNLOAD: u8 = 0x34;
 pub const UDS_REQ_REQUESTUPLOAD: u8 = 0x35;
 pub const UDS_REQ_TRANSFERDATA: u8 = 0x36;
 pub const UDS_REQ_READDATABYID: u8 = 0x22;
+pub const UDS_REQ_TESTERPRESENT: u8 = 0x3E;
+pub const UDS_REQ_ECURESET: u8 = 0x11;
+pub const UDS_REQ_CLEARDTC: u8 = 0x14;
+pub const UDS_REQ_READDTC: u8 = 0x19;
+pub const UDS_REQ_WRITEDATABYID: u8 = 0x2E;
+pub const UDS_REQ_IOCONTROLBYID: u8 = 0x2F;
+pub const UDS_REQ_ROUTINECONTROL: u8 = 0x31;
+pub const UDS_REQ_TRANSFEREXIT: u8 = 0x37;
+pub const UDS_REQ_COMMCONTROL: u8 = 0x28;
+pub const UDS_REQ_CONTROLDTCSETTING: u8 = 0x85;
+
+// Data identifiers
+pub const UDS_DID_VIN: u16 = 0xF190;
+
+// ECUReset types
+pub const UDS_RESET_HARD: u8 = 0x01;
+pub const UDS_RESET_KEYOFFON: u8 = 0x02;
+pub const UDS_RESET_SOFT: u8 = 0x03;
+pub const UDS_RESET_ENABLERAPIDPOWERSHUTDOWN: u8 = 0x04;
+pub const UDS_RESET_DISABLERAPIDPOWERSHUTDOWN: u8 = 0x05;
+
+// ClearDiagnosticInformation group of all DTCs
+pub const UDS_DTC_GROUP_ALL: u32 = 0xFF_FFFF;
+
+// ReadDTCInformation report types
+pub const UDS_DTC_REPORTNUMBERBYSTATUSMASK: u8 = 0x01;
+pub const UDS_DTC_REPORTBYSTATUSMASK: u8 = 0x02;
+
+// RoutineControl types
+pub const UDS_ROUTINE_START: u8 = 0x01;
+pub const UDS_ROUTINE_STOP: u8 = 0x02;
+pub const UDS_ROUTINE_RESULTS: u8 = 0x03;
+
+// InputOutputControlByIdentifier control parameters
+pub const UDS_IOCP_RETURNCONTROL: u8 = 0x00;
+pub const UDS_IOCP_RESETTODEFAULT: u8 = 0x01;
+pub const UDS_IOCP_FREEZE: u8 = 0x02;
+pub const UDS_IOCP_SHORTTERMADJUSTMENT: u8 = 0x03;
+
+// CommunicationControl types
+pub const UDS_COMM_ENABLERXTX: u8 = 0x00;
+pub const UDS_COMM_ENABLERXDISABLETX: u8 = 0x01;
+pub const UDS_COMM_DISABLERXENABLETX: u8 = 0x02;
+pub const UDS_COMM_DISABLERXTX: u8 = 0x03;
+pub const UDS_COMM_ENABLERXDISABLETXENHANCED: u8 = 0x04;
+pub const UDS_COMM_ENABLERXTXENHANCED: u8 = 0x05;
+
+// CommunicationControl communication types
+pub const UDS_COMM_NORMAL: u8 = 0x01;
+pub const UDS_COMM_NETWORKMANAGEMENT: u8 = 0x02;
+pub const UDS_COMM_ALL: u8 = 0x03;
+
+// ControlDTCSetting types
+pub const UDS_DTCSETTING_ON: u8 = 0x01;
+pub const UDS_DTCSETTING_OFF: u8 = 0x02;
+
+// suppressPosRspMsgIndicationBit of sub-function parameters
+pub const UDS_SUPPRESS_RESPONSE: u8 = 0x80;
+
+// Negative response SID
+pub const UDS_RES_NEGATIVE: u8 = 0x7F;
 
 // Negative response codes
+// serviceNotSupported
+pub const UDS_NRES_SNS: u8 = 0x11;
+// subFunctionNotSupported
+pub const UDS_NRES_SFNS: u8 = 0x12;
+// incorrectMessageLengthOrInvalidFormat
+pub const UDS_NRES_IMLOIF: u8 = 0x13;
+// conditionsNotCorrect
+pub const UDS_NRES_CNC: u8 = 0x22;
+// requestSequenceError
+pub const UDS_NRES_RSE: u8 = 0x24;
+// requestOutOfRange
+pub const UDS_NRES_ROOR: u8 = 0x31;
+// securityAccessDenied
+pub const UDS_NRES_SAD: u8 = 0x33;
+// invalidKey
+pub const UDS_NRES_IK: u8 = 0x35;
 // requestCorrectlyReceivedResponsePending
 pub const UDS_NRES_RCRRP: u8 = 0x78;
 
+macro_rules! negative_response_codes {
+    ($($variant:ident = $code:expr => $name:expr,)*) => {
+        /// Negative response codes from ISO 14229-1
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum NegativeResponseCode {
+            $($variant,)*
+            /// A reserved or manufacturer specific code
+            Other(u8),
+        }
+
+        impl NegativeResponseCode {
+            /// Returns the raw response code
+            pub fn code(self) -> u8 {
+                match self {
+                    $(NegativeResponseCode::$variant => $code,)*
+                    NegativeResponseCode::Other(code) => code,
+                }
+            }
+
+            /// Returns the name of the code as used in ISO 14229-1
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(NegativeResponseCode::$variant => $name,)*
+                    NegativeResponseCode::Other(_) => "unknown",
+                }
+            }
+        }
+
+        impl From<u8> for NegativeResponseCode {
+            fn from(code: u8) -> NegativeResponseCode {
+                match code {
+                    $($code => NegativeResponseCode::$variant,)*
+                    _ => NegativeResponseCode::Other(code),
+                }
+            }
+        }
+    };
+}
+
+negative_response_codes! {
+    GeneralReject = 0x10 => "generalReject",
+    ServiceNotSupported = 0x11 => "serviceNotSupported",
+    SubFunctionNotSupported = 0x12 => "subFunctionNotSupported",
+    IncorrectMessageLengthOrInvalidFormat = 0x13 => "incorrectMessageLengthOrInvalidFormat",
+    ResponseTooLong = 0x14 => "responseTooLong",
+    BusyRepeatRequest = 0x21 => "busyRepeatRequest",
+    ConditionsNotCorrect = 0x22 => "conditionsNotCorrect",
+    RequestSequenceError = 0x24 => "requestSequenceError",
+    NoResponseFromSubnetComponent = 0x25 => "noResponseFromSubnetComponent",
+    FailurePreventsExecutionOfRequestedAction = 0x26 => "failurePreventsExecutionOfRequestedAction",
+    RequestOutOfRange = 0x31 => "requestOutOfRange",
+    SecurityAccessDenied = 0x33 => "securityAccessDenied",
+    InvalidKey = 0x35 => "invalidKey",
+    ExceededNumberOfAttempts = 0x36 => "exceededNumberOfAttempts",
+    RequiredTimeDelayNotExpired = 0x37 => "requiredTimeDelayNotExpired",
+    UploadDownloadNotAccepted = 0x70 => "uploadDownloadNotAccepted",
+    TransferDataSuspended = 0x71 => "transferDataSuspended",
+    GeneralProgrammingFailure = 0x72 => "generalProgrammingFailure",
+    WrongBlockSequenceCounter = 0x73 => "wrongBlockSequenceCounter",
+    ResponsePending = 0x78 => "requestCorrectlyReceivedResponsePending",
+    SubFunctionNotSupportedInActiveSession = 0x7E => "subFunctionNotSupportedInActiveSession",
+    ServiceNotSupportedInActiveSession = 0x7F => "serviceNotSupportedInActiveSession",
+    RpmTooHigh = 0x81 => "rpmTooHigh",
+    RpmTooLow = 0x82 => "rpmTooLow",
+    EngineIsRunning = 0x83 => "engineIsRunning",
+    EngineIsNotRunning = 0x84 => "engineIsNotRunning",
+    EngineRunTimeTooLow = 0x85 => "engineRunTimeTooLow",
+    TemperatureTooHigh = 0x86 => "temperatureTooHigh",
+    TemperatureTooLow = 0x87 => "temperatureTooLow",
+    VehicleSpeedTooHigh = 0x88 => "vehicleSpeedTooHigh",
+    VehicleSpeedTooLow = 0x89 => "vehicleSpeedTooLow",
+    ThrottlePedalTooHigh = 0x8A => "throttle/PedalTooHigh",
+    ThrottlePedalTooLow = 0x8B => "throttle/PedalTooLow",
+    TransmissionRangeNotInNeutral = 0x8C => "transmissionRangeNotInNeutral",
+    TransmissionRangeNotInGear = 0x8D => "transmissionRangeNotInGear",
+    BrakeSwitchesNotClosed = 0x8F => "brakeSwitch(es)NotClosed",
+    ShifterLeverNotInPark = 0x90 => "shifterLeverNotInPark",
+    TorqueConverterClutchLocked = 0x91 => "torqueConverterClutchLocked",
+    VoltageTooHigh = 0x92 => "voltageTooHigh",
+    VoltageTooLow = 0x93 => "voltageTooLow",
+}
+
+impl fmt::Display for NegativeResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (0x{:02X})", self.name(), self.code())
+    }
+}
+
+/// A diagnostic trouble code and its status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dtc {
+    /// 3-byte DTC number
+    pub code: u32,
+    pub status: u8,
+}
+
+/// Checks that `response` begins with `echo` and returns the data after it
+fn strip_echo(mut response: Vec<u8>, echo: &[u8]) -> Result<Vec<u8>> {
+    if !response.starts_with(echo) {
+        return Err(Error::InvalidPacket);
+    }
+    response.drain(..echo.len());
+    Ok(response)
+}
+
+/// Encodes a memoryAddress and memorySize with the addressAndLengthFormatIdentifier
+/// `format`. The high nibble is the length of the size and the low nibble the length
+/// of the address in bytes.
+pub fn encode_memory(format: u8, address: u32, size: u32) -> Result<Vec<u8>> {
+    let address_len = (format & 0x0F) as usize;
+    let size_len = (format >> 4) as usize;
+    if address_len == 0 || size_len == 0 || address_len > 4 || size_len > 4 {
+        return Err(Error::Unsupported);
+    }
+    if (address_len < 4 && address >> (address_len * 8) != 0) || (size_len < 4 && size >> (size_len * 8) != 0) {
+        // The value does not fit in the format
+        return Err(Error::TooMuchData);
+    }
+
+    let mut request = Vec::with_capacity(address_len + size_len + 1);
+    request.push(format);
+    request.extend_from_slice(&address.to_be_bytes()[4 - address_len..]);
+    request.extend_from_slice(&size.to_be_bytes()[4 - size_len..]);
+    Ok(request)
+}
+
+/// Parses the maxNumberOfBlockLength from a RequestDownload or RequestUpload response
+fn parse_max_block_length(response: &[u8]) -> Result<usize> {
+    if response.is_empty() {
+        return Err(Error::InvalidPacket);
+    }
+    // The high nibble of the lengthFormatIdentifier is the length of the parameter
+    let len = (response[0] >> 4) as usize;
+    if len == 0 || len > 8 || response.len() < len + 1 {
+        return Err(Error::InvalidPacket);
+    }
+    Ok(response[1..=len].iter().fold(0, |acc, b| (acc << 8) | *b as usize))
+}
+
 pub trait UdsInterface {
     fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>>;
 
+    /// Sends a request without waiting for a response. Used for requests with the
+    /// suppressPosRspMsgIndicationBit set. Negative responses the server still sends
+    /// must not be taken as the response to a later request.
+    ///
+    /// The default implementation waits for a response with `request` and treats a
+    /// timeout as success. Interfaces that can send without waiting should override it.
+    fn send_request(&self, request_sid: u8, data: &[u8]) -> Result<()> {
+        match self.request(request_sid, data) {
+            Ok(_) | Err(Error::Timeout) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Discards responses that were received but not requested, such as the late
+    /// response to a request that timed out. Call it before retrying a request.
+    ///
+    /// The default implementation does nothing.
+    fn discard_responses(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sends a request whose first byte is a sub-function parameter and returns the
+    /// response after the first `echo_len` bytes of the request, which the server
+    /// echoes without the suppressPosRspMsgIndicationBit. If the bit is set, the
+    /// request is sent without waiting and an empty response is returned.
+    fn request_sub_function(&self, request_sid: u8, request: &[u8], echo_len: usize) -> Result<Vec<u8>> {
+        if request.is_empty() {
+            return Err(Error::EmptyPacket);
+        }
+        if request[0] & UDS_SUPPRESS_RESPONSE != 0 {
+            self.send_request(request_sid, request)?;
+            return Ok(Vec::new());
+        }
+        let mut echo = request[..cmp::min(echo_len, request.len())].to_vec();
+        echo[0] &= !UDS_SUPPRESS_RESPONSE;
+        strip_echo(self.request(request_sid, request)?, &echo)
+    }
+
+    /// Sends a TesterPresent request to keep the current session active.
+    /// If `suppress_response` is set, the server does not respond.
+    fn tester_present(&self, suppress_response: bool) -> Result<()> {
+        if suppress_response {
+            return self.send_request(UDS_REQ_TESTERPRESENT, &[UDS_SUPPRESS_RESPONSE]);
+        }
+        let response = self.request(UDS_REQ_TESTERPRESENT, &[0])?;
+        if response.first() != Some(&0) {
+            return Err(Error::InvalidPacket);
+        }
+        Ok(())
+    }
+
     /// Sends a DiagnosticSessionControl request. Returns parameter record.
     fn request_session(&self, session_type: u8) -> Result<Vec<u8>> {
     	let mut response = self.request(UDS_REQ_SESSION, &[session_type])?;
@@ -75,6 +351,12 @@ pub trait UdsInterface {
 		self.request(UDS_REQ_READMEM, &request)
     }
 
+    /// Sends a ReadMemoryByAddress request with the addressAndLengthFormatIdentifier
+    /// `address_format`. `request_read_memory_address` uses Mazda's format without it.
+    fn read_memory_by_address(&self, address_format: u8, address: u32, length: u32) -> Result<Vec<u8>> {
+        self.request(UDS_REQ_READMEM, &encode_memory(address_format, address, length)?)
+    }
+
     fn read_data_by_identifier(&self, id: u16) -> Result<Vec<u8>> {
         let request = &[(id >> 8) as u8, (id & 0xFF) as u8];
         let mut res = self.request(UDS_REQ_READDATABYID, request)?;
@@ -91,4 +373,211 @@ pub trait UdsInterface {
         // Return dataRecord
         Ok(res)
     }
-}
\ No newline at end of file
+
+    /// Sends an ECUReset request. Returns the powerDownTime for
+    /// enableRapidPowerShutDown requests.
+    fn ecu_reset(&self, reset_type: u8) -> Result<Option<u8>> {
+        let response = self.request_sub_function(UDS_REQ_ECURESET, &[reset_type], 1)?;
+        Ok(response.first().cloned())
+    }
+
+    /// Sends a ClearDiagnosticInformation request for the DTC group `group`
+    fn clear_diagnostic_information(&self, group: u32) -> Result<()> {
+        self.request(UDS_REQ_CLEARDTC, &group.to_be_bytes()[1..])?;
+        Ok(())
+    }
+
+    /// Sends a ReadDTCInformation request. Returns the response data after the report type.
+    fn read_dtc_information(&self, report_type: u8, parameters: &[u8]) -> Result<Vec<u8>> {
+        let mut request = Vec::with_capacity(parameters.len() + 1);
+        request.push(report_type);
+        request.extend_from_slice(parameters);
+        self.request_sub_function(UDS_REQ_READDTC, &request, 1)
+    }
+
+    /// Reads the DTCs matching `status_mask`. Returns the DTCStatusAvailabilityMask and the DTCs.
+    fn read_dtc_by_status_mask(&self, status_mask: u8) -> Result<(u8, Vec<Dtc>)> {
+        let response = self.read_dtc_information(UDS_DTC_REPORTBYSTATUSMASK, &[status_mask])?;
+        if response.is_empty() || (response.len() - 1) % 4 != 0 {
+            return Err(Error::InvalidPacket);
+        }
+        let dtcs = response[1..].chunks(4).map(|record| Dtc {
+            code: (record[0] as u32) << 16 | (record[1] as u32) << 8 | record[2] as u32,
+            status: record[3],
+        }).collect();
+        Ok((response[0], dtcs))
+    }
+
+    /// Sends a WriteDataByIdentifier request
+    fn write_data_by_identifier(&self, id: u16, data: &[u8]) -> Result<()> {
+        let mut request = Vec::with_capacity(data.len() + 2);
+        request.write_u16::<BigEndian>(id).unwrap();
+        request.extend_from_slice(data);
+        let response = self.request(UDS_REQ_WRITEDATABYID, &request)?;
+        strip_echo(response, &request[..2])?;
+        Ok(())
+    }
+
+    /// Sends an InputOutputControlByIdentifier request. Returns the controlState
+    /// after the echoed control parameter.
+    fn io_control_by_identifier(&self, id: u16, control_parameter: u8, control_state: &[u8]) -> Result<Vec<u8>> {
+        let mut request = Vec::with_capacity(control_state.len() + 3);
+        request.write_u16::<BigEndian>(id).unwrap();
+        request.push(control_parameter);
+        request.extend_from_slice(control_state);
+        let response = self.request(UDS_REQ_IOCONTROLBYID, &request)?;
+        strip_echo(response, &request[..3])
+    }
+
+    /// Sends a RoutineControl request. Returns the routineStatusRecord.
+    fn routine_control(&self, control_type: u8, routine_id: u16, options: &[u8]) -> Result<Vec<u8>> {
+        let mut request = Vec::with_capacity(options.len() + 3);
+        request.push(control_type);
+        request.write_u16::<BigEndian>(routine_id).unwrap();
+        request.extend_from_slice(options);
+        self.request_sub_function(UDS_REQ_ROUTINECONTROL, &request, 3)
+    }
+
+    /// Sends a RequestDownload request for `size` bytes at `address`. `data_format`
+    /// selects the compression and encryption methods and `address_format` is the
+    /// addressAndLengthFormatIdentifier. Returns the maxNumberOfBlockLength, which
+    /// includes the TransferData SID and block sequence counter.
+    fn request_download(&self, data_format: u8, address_format: u8, address: u32, size: u32) -> Result<usize> {
+        let mut request = vec![data_format];
+        request.extend_from_slice(&encode_memory(address_format, address, size)?);
+        parse_max_block_length(&self.request(UDS_REQ_REQUESTDOWNLOAD, &request)?)
+    }
+
+    /// Sends a RequestUpload request for `size` bytes at `address`. See `request_download`.
+    fn request_upload(&self, data_format: u8, address_format: u8, address: u32, size: u32) -> Result<usize> {
+        let mut request = vec![data_format];
+        request.extend_from_slice(&encode_memory(address_format, address, size)?);
+        parse_max_block_length(&self.request(UDS_REQ_REQUESTUPLOAD, &request)?)
+    }
+
+    /// Sends a TransferData request with the block sequence counter `sequence`.
+    /// Returns the transferResponseParameterRecord.
+    fn transfer_data(&self, sequence: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut request = Vec::with_capacity(data.len() + 1);
+        request.push(sequence);
+        request.extend_from_slice(data);
+        strip_echo(self.request(UDS_REQ_TRANSFERDATA, &request)?, &[sequence])
+    }
+
+    /// Sends a RequestTransferExit request. Returns the transferResponseParameterRecord.
+    fn request_transfer_exit(&self, parameters: &[u8]) -> Result<Vec<u8>> {
+        self.request(UDS_REQ_TRANSFEREXIT, parameters)
+    }
+
+    /// Sends a CommunicationControl request. `node_id` is the nodeIdentificationNumber
+    /// sent with the control types with enhanced address information (0x04 and 0x05);
+    /// it is ignored for other control types.
+    fn communication_control(&self, control_type: u8, communication_type: u8, node_id: Option<u16>) -> Result<()> {
+        let mut request = vec![control_type, communication_type];
+        match control_type & !UDS_SUPPRESS_RESPONSE {
+            UDS_COMM_ENABLERXDISABLETXENHANCED | UDS_COMM_ENABLERXTXENHANCED => {
+                if let Some(node_id) = node_id {
+                    request.write_u16::<BigEndian>(node_id).unwrap();
+                }
+            },
+            _ => (),
+        }
+        self.request_sub_function(UDS_REQ_COMMCONTROL, &request, 1)?;
+        Ok(())
+    }
+
+    /// Sends a ControlDTCSetting request
+    fn control_dtc_setting(&self, setting_type: u8) -> Result<()> {
+        self.request_sub_function(UDS_REQ_CONTROLDTCSETTING, &[setting_type], 1)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Records requests and answers each with a positive response echoing the request
+    #[derive(Default)]
+    struct Echo {
+        requests: RefCell<Vec<Vec<u8>>>,
+        sent: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl UdsInterface for Echo {
+        fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>> {
+            let mut request = vec![request_sid];
+            request.extend_from_slice(data);
+            self.requests.borrow_mut().push(request);
+            // Servers echo the sub-function without the suppress bit
+            let mut response = data.to_vec();
+            response[0] &= !UDS_SUPPRESS_RESPONSE;
+            Ok(response)
+        }
+
+        fn send_request(&self, request_sid: u8, data: &[u8]) -> Result<()> {
+            let mut request = vec![request_sid];
+            request.extend_from_slice(data);
+            self.sent.borrow_mut().push(request);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn suppressed_sub_functions_are_sent_without_waiting() {
+        let echo = Echo::default();
+        echo.tester_present(true).unwrap();
+        echo.control_dtc_setting(UDS_DTCSETTING_OFF | UDS_SUPPRESS_RESPONSE).unwrap();
+        assert_eq!(echo.routine_control(UDS_ROUTINE_START | UDS_SUPPRESS_RESPONSE, 0xFF00, &[1]).unwrap(), Vec::<u8>::new());
+        assert!(echo.requests.borrow().is_empty());
+        assert_eq!(*echo.sent.borrow(), vec![
+            vec![UDS_REQ_TESTERPRESENT, 0x80],
+            vec![UDS_REQ_CONTROLDTCSETTING, 0x82],
+            vec![UDS_REQ_ROUTINECONTROL, 0x81, 0xFF, 0x00, 0x01],
+        ]);
+    }
+
+    #[test]
+    fn sub_function_echo_is_checked() {
+        let echo = Echo::default();
+        assert_eq!(echo.routine_control(UDS_ROUTINE_START, 0xFF00, &[1]).unwrap(), vec![1]);
+        assert_eq!(echo.request_sub_function(UDS_REQ_ECURESET, &[0x01, 0x0F], 1).unwrap(), vec![0x0F]);
+        match strip_echo(vec![0x02, 0x00], &[0x01]) {
+            Err(Error::InvalidPacket) => (),
+            res => panic!("expected InvalidPacket, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn communication_control_sends_the_node_id_for_enhanced_types() {
+        let echo = Echo::default();
+        echo.communication_control(UDS_COMM_ENABLERXTXENHANCED, UDS_COMM_NORMAL, Some(0x1234)).unwrap();
+        echo.communication_control(UDS_COMM_DISABLERXTX, UDS_COMM_ALL, Some(0x1234)).unwrap();
+        assert_eq!(*echo.requests.borrow(), vec![
+            vec![UDS_REQ_COMMCONTROL, 0x05, 0x01, 0x12, 0x34],
+            vec![UDS_REQ_COMMCONTROL, 0x03, 0x03],
+        ]);
+    }
+
+    /// Interface implementing only `request`, like implementations predating `send_request`
+    struct RequestOnly {
+        result: fn() -> Result<Vec<u8>>,
+    }
+
+    impl UdsInterface for RequestOnly {
+        fn request(&self, _request_sid: u8, _data: &[u8]) -> Result<Vec<u8>> {
+            (self.result)()
+        }
+    }
+
+    #[test]
+    fn send_request_defaults_to_request_ignoring_timeouts() {
+        assert!(RequestOnly { result: || Err(Error::Timeout) }.tester_present(true).is_ok());
+        assert!(RequestOnly { result: || Ok(Vec::new()) }.tester_present(true).is_ok());
+        match (RequestOnly { result: || Err(Error::InvalidPacket) }).tester_present(true) {
+            Err(Error::InvalidPacket) => (),
+            res => panic!("expected InvalidPacket, got {:?}", res),
+        }
+    }
+}
@@ -28,6 +28,41 @@ impl Rom {
 		Ok(())
 	}
 
+	/// Returns the ROM data
+	pub fn data(&self) -> &[u8] {
+		&self.data
+	}
+
+	/// Returns the known contents of the ECU: the last image flashed with this ROM
+	/// as a base, or the original ROM data if it was never flashed. Returns `None`
+	/// if the contents are unknown because a flash did not complete.
+	pub fn flashed_data(&self) -> Result<Option<Vec<u8>>> {
+		let path = self.flashed_path();
+		if path.is_file() {
+			let data = fs::read(&path)?;
+			// An empty record marks the contents as unknown
+			return Ok(if data.is_empty() { None } else { Some(data) });
+		}
+		Ok(Some(self.data.clone()))
+	}
+
+	/// Records `image` as flashed to the ECU
+	pub fn set_flashed(&self, image: &[u8]) -> Result<()> {
+		fs::write(self.flashed_path(), image)?;
+		Ok(())
+	}
+
+	/// Marks the contents of the ECU as unknown until the next successful flash
+	pub fn set_flashed_unknown(&self) -> Result<()> {
+		self.set_flashed(&[])
+	}
+
+	fn flashed_path(&self) -> PathBuf {
+		let mut path = self.meta.data_path.clone().into_os_string();
+		path.push(".flashed");
+		PathBuf::from(path)
+	}
+
 	/// Loads ROM from file. Internal use only; use `RomManager::load_rom`
 	fn load(meta: RomMeta) -> Result<Rom> {
 		let data = fs::read(&meta.data_path)?;
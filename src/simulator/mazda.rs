@@ -0,0 +1,343 @@
+use super::{UdsServer, ServiceResult};
+
+use crate::{
+	protocols::uds,
+	authenticator::MazdaAuthenticator,
+	definition,
+	error::{Error, Result},
+};
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use std::collections::HashMap;
+use std::result;
+
+// Mazda's erase request
+pub const MAZDA_REQ_ERASE: u8 = 0xB1;
+
+// Session types
+pub const MAZDA_SESSION_DEFAULT: u8 = 0x81;
+pub const MAZDA_SESSION_PROGRAMMING: u8 = 0x85;
+pub const MAZDA_SESSION_EXTENDED: u8 = 0x87;
+
+/// An active RequestDownload
+struct Download {
+	offset: usize,
+	remaining: usize,
+}
+
+/// Simulates an ECU using Mazda's download and flash protocol. The memory is backed
+/// by a ROM image and the flash region behaves like NOR flash: it must be erased
+/// before it can be programmed.
+pub struct Mazda1Ecu {
+	key: String,
+	memory: Vec<u8>,
+	flash_offset: usize,
+	flash_size: usize,
+	pids: HashMap<u16, Vec<u8>>,
+
+	session: u8,
+	seed: Option<[u8; 3]>,
+	unlocked: bool,
+	download: Option<Download>,
+	counter: u32,
+}
+
+impl Mazda1Ecu {
+	/// Creates a simulated ECU with the security key `key`, memory `rom` and a flash
+	/// region of `flash_size` bytes starting at `flash_offset`. Returns
+	/// `Error::OutsideFlashRegion` if the flash region does not fit in `rom`.
+	pub fn new(key: &str, rom: Vec<u8>, flash_offset: usize, flash_size: usize) -> Result<Mazda1Ecu> {
+		match flash_offset.checked_add(flash_size) {
+			Some(end) if end <= rom.len() => (),
+			_ => return Err(Error::OutsideFlashRegion),
+		}
+		Ok(Mazda1Ecu {
+			key: key.to_string(),
+			memory: rom,
+			flash_offset,
+			flash_size,
+			pids: HashMap::new(),
+			session: MAZDA_SESSION_DEFAULT,
+			seed: None,
+			unlocked: false,
+			download: None,
+			counter: 0x0012_3456,
+		})
+	}
+
+	/// Creates a simulated ECU using the security key and flash region of a platform
+	pub fn from_platform(platform: &definition::Main, rom: Vec<u8>) -> Result<Mazda1Ecu> {
+		Mazda1Ecu::new(&platform.auth.key, rom, platform.flash_region.offset, platform.flash_region.size)
+	}
+
+	/// Sets the data returned when reading the PID `code`
+	pub fn with_pid(mut self, code: u16, data: &[u8]) -> Self {
+		self.pids.insert(code, data.to_vec());
+		self
+	}
+
+	/// Updates the data returned when reading the PID `code`
+	pub fn set_pid(&mut self, code: u16, data: &[u8]) {
+		self.pids.insert(code, data.to_vec());
+	}
+
+	/// Returns the current memory contents
+	pub fn memory(&self) -> &[u8] {
+		&self.memory
+	}
+
+	/// Returns the active session type
+	pub fn session(&self) -> u8 {
+		self.session
+	}
+
+	/// Returns true if security access has been granted
+	pub fn unlocked(&self) -> bool {
+		self.unlocked
+	}
+
+	fn require_programming(&self) -> result::Result<(), u8> {
+		if !self.unlocked {
+			return Err(uds::UDS_NRES_SAD);
+		}
+		if self.session != MAZDA_SESSION_PROGRAMMING {
+			return Err(uds::UDS_NRES_CNC);
+		}
+		Ok(())
+	}
+
+	fn session_control(&mut self, data: &[u8]) -> ServiceResult {
+		if data.len() != 1 {
+			return Err(uds::UDS_NRES_IMLOIF);
+		}
+		match data[0] {
+			MAZDA_SESSION_DEFAULT | MAZDA_SESSION_PROGRAMMING | MAZDA_SESSION_EXTENDED => (),
+			_ => return Err(uds::UDS_NRES_SFNS),
+		}
+		// Changing sessions locks the ECU
+		self.session = data[0];
+		self.seed = None;
+		self.unlocked = false;
+		self.download = None;
+		Ok(vec![data[0]])
+	}
+
+	fn security_access(&mut self, data: &[u8]) -> ServiceResult {
+		if data.is_empty() {
+			return Err(uds::UDS_NRES_IMLOIF);
+		}
+		match data[0] {
+			1 => {
+				if self.session == MAZDA_SESSION_DEFAULT {
+					return Err(uds::UDS_NRES_CNC);
+				}
+				let seed = if self.unlocked {
+					// A zero seed means the ECU is already unlocked and any key is accepted
+					self.seed = Some([0; 3]);
+					[0; 3]
+				} else {
+					// The real ECU uses a timer/counter for the seed
+					self.counter = self.counter.wrapping_mul(1_103_515_245).wrapping_add(12345);
+					let seed = [(self.counter >> 16) as u8, (self.counter >> 8) as u8, self.counter as u8];
+					self.seed = Some(seed);
+					seed
+				};
+				let mut response = vec![1];
+				response.extend_from_slice(&seed);
+				Ok(response)
+			},
+			2 => {
+				let seed = self.seed.take().ok_or(uds::UDS_NRES_RSE)?;
+				if self.unlocked {
+					return Ok(vec![2]);
+				}
+				let key = MazdaAuthenticator::generate_key(&self.key, 0xC541A9, &seed);
+				if data[1..] != key {
+					return Err(uds::UDS_NRES_IK);
+				}
+				self.unlocked = true;
+				Ok(vec![2])
+			},
+			_ => Err(uds::UDS_NRES_SFNS),
+		}
+	}
+
+	fn read_memory(&mut self, data: &[u8]) -> ServiceResult {
+		if !self.unlocked {
+			return Err(uds::UDS_NRES_SAD);
+		}
+		if data.len() != 6 {
+			return Err(uds::UDS_NRES_IMLOIF);
+		}
+		let mut reader = data;
+		let address = reader.read_u32::<BigEndian>().unwrap() as usize;
+		let length = reader.read_u16::<BigEndian>().unwrap() as usize;
+		if length == 0 || address + length > self.memory.len() {
+			return Err(uds::UDS_NRES_ROOR);
+		}
+		Ok(self.memory[address..address + length].to_vec())
+	}
+
+	fn erase(&mut self, _data: &[u8]) -> ServiceResult {
+		self.require_programming()?;
+		for byte in &mut self.memory[self.flash_offset..self.flash_offset + self.flash_size] {
+			*byte = 0xFF;
+		}
+		Ok(Vec::new())
+	}
+
+	fn request_download(&mut self, data: &[u8]) -> ServiceResult {
+		self.require_programming()?;
+		if data.len() != 8 {
+			return Err(uds::UDS_NRES_IMLOIF);
+		}
+		let mut reader = data;
+		let offset = reader.read_u32::<BigEndian>().unwrap() as usize;
+		let size = reader.read_u32::<BigEndian>().unwrap() as usize;
+		if offset < self.flash_offset || offset + size > self.flash_offset + self.flash_size {
+			return Err(uds::UDS_NRES_ROOR);
+		}
+		self.download = Some(Download {
+			offset,
+			remaining: size,
+		});
+		Ok(Vec::new())
+	}
+
+	fn transfer_data(&mut self, data: &[u8]) -> ServiceResult {
+		self.require_programming()?;
+		let download = self.download.as_mut().ok_or(uds::UDS_NRES_RSE)?;
+		if data.is_empty() || data.len() > download.remaining {
+			return Err(uds::UDS_NRES_ROOR);
+		}
+		// Programming can only clear bits
+		for (byte, new) in self.memory[download.offset..download.offset + data.len()].iter_mut().zip(data) {
+			*byte &= *new;
+		}
+		download.offset += data.len();
+		download.remaining -= data.len();
+		if download.remaining == 0 {
+			self.download = None;
+		}
+		Ok(Vec::new())
+	}
+
+	fn read_data_by_identifier(&mut self, data: &[u8]) -> ServiceResult {
+		if data.len() != 2 {
+			return Err(uds::UDS_NRES_IMLOIF);
+		}
+		let code = ((data[0] as u16) << 8) | data[1] as u16;
+		let value = self.pids.get(&code).ok_or(uds::UDS_NRES_ROOR)?;
+		let mut response = data.to_vec();
+		response.extend_from_slice(value);
+		Ok(response)
+	}
+}
+
+impl Mazda1Ecu {
+	fn tester_present(&mut self, data: &[u8]) -> ServiceResult {
+		if data.len() != 1 {
+			return Err(uds::UDS_NRES_IMLOIF);
+		}
+		if data[0] & !uds::UDS_SUPPRESS_RESPONSE != 0 {
+			return Err(uds::UDS_NRES_SFNS);
+		}
+		Ok(vec![data[0]])
+	}
+}
+
+impl UdsServer for Mazda1Ecu {
+	fn handle(&mut self, request_sid: u8, data: &[u8]) -> ServiceResult {
+		match request_sid {
+			uds::UDS_REQ_SESSION => self.session_control(data),
+			uds::UDS_REQ_SECURITY => self.security_access(data),
+			uds::UDS_REQ_READMEM => self.read_memory(data),
+			MAZDA_REQ_ERASE => self.erase(data),
+			uds::UDS_REQ_REQUESTDOWNLOAD => self.request_download(data),
+			uds::UDS_REQ_TRANSFERDATA => self.transfer_data(data),
+			uds::UDS_REQ_READDATABYID => self.read_data_by_identifier(data),
+			uds::UDS_REQ_TESTERPRESENT => self.tester_present(data),
+			_ => Err(uds::UDS_NRES_SNS),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		protocols::can::VirtualCanBus,
+		simulator::testing,
+		download::{Downloader, DownloadCallback, mazda::Mazda1Downloader},
+		flash::{Flasher, FlashData, ReadMemoryVerifier, mazda::Mazda1Flasher},
+		definition::FlashRegion,
+	};
+
+	const KEY: &str = "MazdA";
+
+	fn rom() -> Vec<u8> {
+		(0..0x2000).map(|i| (i * 7) as u8).collect()
+	}
+
+	fn unlock(ecu: &mut Mazda1Ecu) {
+		ecu.handle(uds::UDS_REQ_SESSION, &[MAZDA_SESSION_PROGRAMMING]).unwrap();
+		let seed = ecu.handle(uds::UDS_REQ_SECURITY, &[1]).unwrap();
+		let mut request = vec![2];
+		request.extend_from_slice(&MazdaAuthenticator::generate_key(KEY, 0xC541A9, &seed[1..]));
+		assert_eq!(ecu.handle(uds::UDS_REQ_SECURITY, &request), Ok(vec![2]));
+	}
+
+	#[test]
+	fn flash_region_must_fit_in_memory() {
+		assert!(Mazda1Ecu::new(KEY, rom(), 0x1000, 0x1000).is_ok());
+		match Mazda1Ecu::new(KEY, rom(), 0x1000, 0x1001) {
+			Err(Error::OutsideFlashRegion) => (),
+			_ => panic!("expected OutsideFlashRegion"),
+		}
+		match Mazda1Ecu::new(KEY, rom(), usize::max_value(), 2) {
+			Err(Error::OutsideFlashRegion) => (),
+			_ => panic!("expected OutsideFlashRegion"),
+		}
+	}
+
+	#[test]
+	fn zero_seed_after_unlock_accepts_the_key() {
+		let mut ecu = Mazda1Ecu::new(KEY, rom(), 0x1000, 0x1000).unwrap();
+		assert_eq!(ecu.handle(uds::UDS_REQ_SECURITY, &[2, 0, 0, 0]), Err(uds::UDS_NRES_RSE));
+		unlock(&mut ecu);
+
+		assert_eq!(ecu.handle(uds::UDS_REQ_SECURITY, &[1]), Ok(vec![1, 0, 0, 0]));
+		assert_eq!(ecu.handle(uds::UDS_REQ_SECURITY, &[2, 0, 0, 0]), Ok(vec![2]));
+		assert!(ecu.unlocked());
+		// A key still requires a seed
+		assert_eq!(ecu.handle(uds::UDS_REQ_SECURITY, &[2, 0, 0, 0]), Err(uds::UDS_NRES_RSE));
+	}
+
+	#[test]
+	fn download_flash_and_read_back() {
+		let bus = VirtualCanBus::new();
+		let region = FlashRegion {offset: 0x1000, size: 0x1000};
+		let server = testing::spawn(&bus, Mazda1Ecu::new(KEY, rom(), region.offset, region.size).unwrap());
+		let tester = testing::tester(bus.open());
+
+		let downloaded = Mazda1Downloader::new(tester.clone(), KEY, 0x2000)
+			.with_block_size(0x400)
+			.download(&DownloadCallback::null()).unwrap();
+		assert_eq!(downloaded.data, rom());
+
+		let mut modified = downloaded.data.clone();
+		for byte in &mut modified[0x1800..0x1810] {
+			*byte = !*byte;
+		}
+		let flasher = Mazda1Flasher::new(tester.clone(), KEY, region)
+			.with_block_size(0x300)
+			.with_verifier(Box::new(ReadMemoryVerifier::new(tester.clone(), None, 0x400)));
+		flasher.flash(&FlashData::from_rom(&modified, &region).unwrap()).unwrap();
+
+		let read_back = Mazda1Downloader::new(tester.clone(), KEY, 0x2000)
+			.download(&DownloadCallback::null()).unwrap();
+		assert_eq!(read_back.data, modified);
+		assert_eq!(server.stop().memory(), &modified[..]);
+	}
+}
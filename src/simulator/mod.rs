@@ -0,0 +1,109 @@
+// Utilities for simulating ECUs
+
+pub mod mazda;
+
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+	protocols::{
+		isotp::IsotpInterface,
+		uds,
+	},
+	error::{Error, Result},
+};
+
+/// Result of a simulated service. `Err` holds the negative response code.
+pub type ServiceResult = result::Result<Vec<u8>, u8>;
+
+pub trait UdsServer {
+	/// Handles a single request. Returns the positive response data
+	/// (without the response SID) or a negative response code.
+	fn handle(&mut self, request_sid: u8, data: &[u8]) -> ServiceResult;
+
+	/// Receives and answers a single request from the interface
+	fn serve_one(&mut self, interface: &IsotpInterface) -> Result<()> {
+		let request = interface.recv()?;
+		if request.is_empty() {
+			return Err(Error::EmptyPacket);
+		}
+
+		let response = match self.handle(request[0], &request[1..]) {
+			// Mazda's session types set the suppressPosRspMsgIndicationBit, so it is only
+			// honored for TesterPresent
+			Ok(_) if request[0] == uds::UDS_REQ_TESTERPRESENT && request.len() > 1
+				&& request[1] & uds::UDS_SUPPRESS_RESPONSE != 0 => return Ok(()),
+			Ok(data) => {
+				let mut response = Vec::with_capacity(data.len() + 1);
+				response.push(request[0].wrapping_add(0x40));
+				response.extend_from_slice(&data);
+				response
+			},
+			Err(code) => vec![uds::UDS_RES_NEGATIVE, request[0], code],
+		};
+		interface.send(&response)
+	}
+
+	/// Answers requests until `running` is cleared
+	fn serve(&mut self, interface: &IsotpInterface, running: &AtomicBool) -> Result<()> {
+		while running.load(Ordering::Relaxed) {
+			match self.serve_one(interface) {
+				// Timeouts are expected while the tester is idle
+				Err(Error::Timeout) => continue,
+				res => res?,
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+	use super::*;
+	use crate::protocols::{
+		can::{VirtualCan, VirtualCanBus},
+		isotp::{IsotpCan, Options},
+		uds::{UdsInterface, UdsIsotp},
+	};
+	use std::rc::Rc;
+	use std::sync::Arc;
+	use std::thread;
+	use std::time;
+
+	/// A simulated ECU answering requests on a virtual bus from a separate thread
+	pub struct ServerThread<S> {
+		running: Arc<AtomicBool>,
+		thread: thread::JoinHandle<S>,
+	}
+
+	impl<S> ServerThread<S> {
+		/// Stops the server and returns it
+		pub fn stop(self) -> S {
+			self.running.store(false, Ordering::Relaxed);
+			self.thread.join().unwrap()
+		}
+	}
+
+	/// Serves `server` on the default diagnostic ids 0x7E0/0x7E8 of `bus`
+	pub fn spawn<S: UdsServer + Send + 'static>(bus: &VirtualCanBus, mut server: S) -> ServerThread<S> {
+		// The endpoint is opened before the tester sends its first request
+		let can = bus.open();
+		let running = Arc::new(AtomicBool::new(true));
+		let thread = {
+			let running = running.clone();
+			thread::spawn(move || {
+				let mut options = Options::listener(0x7E0, 0x7E8);
+				options.timeout = time::Duration::from_millis(20);
+				let interface = IsotpCan::new(Rc::new(can), options);
+				server.serve(&interface, &running).unwrap();
+				server
+			})
+		};
+		ServerThread {running, thread}
+	}
+
+	/// Returns a UDS tester on the default diagnostic ids of `can`
+	pub fn tester(can: VirtualCan) -> Rc<UdsInterface> {
+		Rc::new(UdsIsotp::new(Rc::new(IsotpCan::new(Rc::new(can), Options::default()))))
+	}
+}